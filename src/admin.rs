@@ -0,0 +1,294 @@
+use Options;
+use Storage;
+use Tables;
+use clock::{self, Clock, ClockSource};
+use error::ApiError;
+use export::{self, ExportInfo, ExportRoot};
+use idmap::MemoryUsage;
+use reload::{self, ReloadStatus, Reloader, StartError};
+use snapshot::{self, SnapshotInfo, SnapshotPath};
+use status::{self, ServerStatus};
+use text;
+use util;
+use wal::Wal;
+
+use rocket::{Outcome, Request, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket_contrib::Json;
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The token admin requests that change state have to carry in the
+/// `X-Admin-Token` header. With none set they're all refused.
+#[derive(Clone, PartialEq)]
+pub struct AdminToken(pub Option<String>);
+
+// Keeps the token out of the config printed at startup.
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("AdminToken(set)"),
+            None => f.write_str("AdminToken(unset)"),
+        }
+    }
+}
+
+// Compares every byte whatever the first difference, so the time taken
+// doesn't tell how much of a guess was right.
+fn same_token(expected: &str, given: &str) -> bool {
+    if expected.len() != given.len() {
+        return false;
+    }
+    expected
+        .bytes()
+        .zip(given.bytes())
+        .fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// A request that carried the admin token. Take it as
+/// `Result<Admin, ApiError>` to answer a refusal with a JSON body.
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ApiError> {
+        let expected = match request.guard::<State<AdminToken>>() {
+            Outcome::Success(token) => token.0.clone(),
+            _ => None,
+        };
+        let expected = match expected {
+            Some(token) => token,
+            None => {
+                let message = "admin requests are disabled without an admin_token".to_owned();
+                let error = ApiError::new(Status::Forbidden, "admin_disabled", message);
+                return Outcome::Failure((Status::Forbidden, error));
+            }
+        };
+        match request.headers().get_one("X-Admin-Token") {
+            Some(given) if same_token(&expected, given) => Outcome::Success(Admin),
+            _ => {
+                let message = "missing or wrong X-Admin-Token header".to_owned();
+                let error = ApiError::new(Status::Unauthorized, "unauthorized", message);
+                Outcome::Failure((Status::Unauthorized, error))
+            }
+        }
+    }
+}
+
+#[derive(FromForm)]
+struct ExportParams {
+    dir: String,
+    zip: Option<bool>,
+    #[form(field = "chunkSize")] chunk_size: Option<usize>,
+}
+
+#[post("/admin/snapshot")]
+fn admin_snapshot(
+    admin: Result<Admin, ApiError>,
+    snapshot_path: State<SnapshotPath>,
+    storage: State<Arc<Storage>>,
+    wal: State<Arc<Wal>>,
+    options: State<Options>,
+) -> Result<Json<SnapshotInfo>, ApiError> {
+    admin?;
+    let info = snapshot::take_snapshot(&snapshot_path.0, &storage, &wal, &options)?;
+    Ok(Json(info))
+}
+
+#[post("/admin/export?<params>")]
+fn admin_export(
+    admin: Result<Admin, ApiError>,
+    params: ExportParams,
+    export_root: State<ExportRoot>,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+) -> Result<Json<ExportInfo>, ApiError> {
+    admin?;
+    let dir = util::path_under(&export_root.0, &params.dir).ok_or_else(|| {
+        let message = "dir must be a relative path without \"..\"".to_owned();
+        ApiError::new(Status::BadRequest, "invalid_path", message).with_field("dir")
+    })?;
+    let chunk_size = params.chunk_size.unwrap_or(export::DEFAULT_CHUNK_SIZE);
+    let pack_zip = params.zip.unwrap_or(false);
+
+    let info = export::export(&dir, &storage, &options, chunk_size, pack_zip)?;
+    Ok(Json(info))
+}
+
+#[derive(Serialize, Debug)]
+pub struct MemoryReport {
+    pub users: MemoryUsage,
+    pub locations: MemoryUsage,
+    pub visits: MemoryUsage,
+    pub visitors: MemoryUsage,
+    pub location_visits: MemoryUsage,
+    pub user_visits: MemoryUsage,
+    pub location_marks: MemoryUsage,
+    /// Bytes in the symbol table shared by interned location fields.
+    pub interned_bytes: usize,
+    pub total: MemoryUsage,
+}
+
+pub fn memory_report(tables: &Tables) -> MemoryReport {
+    let tables_usage = [
+        tables.users.memory_usage(),
+        tables.locations.memory_usage(),
+        tables.visits.memory_usage(),
+        tables.visitors.memory_usage(),
+        tables.location_visits.memory_usage(),
+        tables.user_visits.memory_usage(),
+        tables.location_marks.memory_usage(),
+    ];
+    let interned_bytes = text::interned_bytes();
+    let interned = MemoryUsage {
+        entries: 0,
+        bytes: interned_bytes,
+        hash_map_bytes: interned_bytes,
+    };
+    let total = tables_usage.iter().fold(interned, |total, usage| {
+        MemoryUsage {
+            entries: total.entries + usage.entries,
+            bytes: total.bytes + usage.bytes,
+            hash_map_bytes: total.hash_map_bytes + usage.hash_map_bytes,
+        }
+    });
+    MemoryReport {
+        users: tables_usage[0],
+        locations: tables_usage[1],
+        visits: tables_usage[2],
+        visitors: tables_usage[3],
+        location_visits: tables_usage[4],
+        user_visits: tables_usage[5],
+        location_marks: tables_usage[6],
+        interned_bytes: interned_bytes,
+        total: total,
+    }
+}
+
+#[get("/admin/memory")]
+fn admin_memory(storage: State<Arc<Storage>>) -> Json<MemoryReport> {
+    Json(memory_report(&storage.read()))
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClockInfo {
+    source: ClockSource,
+    now: i32,
+}
+
+fn clock_info(clock: &Clock) -> ClockInfo {
+    ClockInfo {
+        source: clock.source(),
+        now: clock.now(),
+    }
+}
+
+#[derive(FromForm)]
+struct ClockParams {
+    now: i32,
+}
+
+#[get("/admin/clock")]
+fn admin_clock(options: State<Options>) -> Json<ClockInfo> {
+    Json(clock_info(&options.clock))
+}
+
+/// Sets "now" for a fixed or offset clock, for tests that need ages to
+/// move.
+#[post("/admin/clock?<params>")]
+fn admin_clock_set(
+    admin: Result<Admin, ApiError>,
+    params: ClockParams,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+) -> Result<Json<ClockInfo>, ApiError> {
+    admin?;
+    if !options.clock.set_now(params.now) {
+        return Err(ApiError::new(
+            Status::Conflict,
+            "clock_not_settable",
+            "the system clock can't be set".to_owned(),
+        ));
+    }
+    clock::refresh_visitors(&storage, params.now);
+    Ok(Json(clock_info(&options.clock)))
+}
+
+#[get("/admin/status")]
+fn admin_status(storage: State<Arc<Storage>>, options: State<Options>) -> Json<ServerStatus> {
+    Json(status::status(&storage.read(), &options))
+}
+
+#[derive(FromForm)]
+struct ReloadParams {
+    path: Option<String>,
+    /// Reload the server's own data files even if that loses changes made
+    /// since they were loaded.
+    discard: Option<bool>,
+}
+
+fn start_reload(
+    reloader: &Arc<Reloader>,
+    path: Option<PathBuf>,
+    discard: bool,
+) -> Result<Json<ReloadStatus>, ApiError> {
+    match reload::start(reloader, path, discard) {
+        Ok(status) => Ok(Json(status)),
+        Err(e @ StartError::InProgress) => Err(ApiError::new(
+            Status::Conflict,
+            "reload_in_progress",
+            e.to_string(),
+        )),
+        Err(e @ StartError::NoSource) => Err(ApiError::new(
+            Status::BadRequest,
+            "reload_path_required",
+            e.to_string(),
+        )),
+        Err(e @ StartError::WouldDiscard) => Err(ApiError::new(
+            Status::Conflict,
+            "reload_would_discard",
+            e.to_string(),
+        )),
+    }
+}
+
+/// Reloads the data the server started with, in the background, unless the
+/// server has changes the data files don't.
+#[post("/admin/reload")]
+fn admin_reload_no_params(
+    admin: Result<Admin, ApiError>,
+    reloader: State<Arc<Reloader>>,
+) -> Result<Json<ReloadStatus>, ApiError> {
+    admin?;
+    start_reload(&reloader, None, false)
+}
+
+/// Loads the data directory or archive at `path`, relative to the reload
+/// root, in the background, and serves it once it's complete. Without a
+/// path, reloads the data the server started with, as above; `discard=true`
+/// does so even if that loses changes.
+#[post("/admin/reload?<params>")]
+fn admin_reload(
+    admin: Result<Admin, ApiError>,
+    params: ReloadParams,
+    reloader: State<Arc<Reloader>>,
+) -> Result<Json<ReloadStatus>, ApiError> {
+    admin?;
+    let path = match params.path {
+        Some(ref path) => Some(reloader.source_path(path).ok_or_else(|| {
+            let message = "path must be a relative path without \"..\"".to_owned();
+            ApiError::new(Status::BadRequest, "invalid_path", message).with_field("path")
+        })?),
+        None => None,
+    };
+    start_reload(&reloader, path, params.discard.unwrap_or(false))
+}
+
+#[get("/admin/reload")]
+fn admin_reload_status(reloader: State<Arc<Reloader>>) -> Json<ReloadStatus> {
+    Json(reloader.status())
+}
@@ -0,0 +1,308 @@
+use Options;
+use Storage;
+use Tables;
+use export;
+use loader::{self, LoadPolicy};
+use wal::{self, Mutation};
+
+use serde_json;
+
+use std::env;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct BenchConfig {
+    pub readers: usize,
+    pub writers: usize,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub reads_per_sec: f64,
+    pub writes_per_sec: f64,
+}
+
+#[derive(Debug)]
+pub struct LoadResult {
+    pub name: String,
+    pub records: usize,
+    pub seconds: f64,
+    /// How much the peak resident memory grew while loading, if the system
+    /// tells.
+    pub peak_kb: Option<usize>,
+}
+
+/// A store the benchmark can read from and write to.
+trait Store: Send + Sync {
+    fn read_user_visits(&self, user: u32) -> usize;
+    fn write(&self, options: &Options, mutation: Mutation);
+}
+
+/// `Storage` as the server uses it: readers take the current version.
+impl Store for Storage {
+    fn read_user_visits(&self, user: u32) -> usize {
+        user_visits_places(&self.read(), user)
+    }
+
+    fn write(&self, options: &Options, mutation: Mutation) {
+        wal::apply(self, options, mutation);
+    }
+}
+
+/// The layout `Storage` replaced: one lock, held by readers for the whole
+/// request and by writers while they apply a mutation.
+struct LockedTables(RwLock<Tables>);
+
+impl Store for LockedTables {
+    fn read_user_visits(&self, user: u32) -> usize {
+        user_visits_places(&self.0.read().unwrap(), user)
+    }
+
+    fn write(&self, options: &Options, mutation: Mutation) {
+        wal::apply_to(&mut self.0.write().unwrap(), options, mutation);
+    }
+}
+
+// The core of `GET /users/<id>/visits`.
+fn user_visits_places(tables: &Tables, user: u32) -> usize {
+    tables.user_visits.get(&user).map_or(0, |ids| {
+        ids.iter()
+            .filter_map(|id| tables.visits.get(id))
+            .filter_map(|visit| tables.locations.get(&visit.location))
+            .map(|location| location.place.len())
+            .sum()
+    })
+}
+
+fn next_random(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+/// Runs the same read/write mix against the copy-on-write `Storage` and a
+/// single-lock copy of its tables, then the writes alone against each, since
+/// copy-on-write makes writes the slow path.
+pub fn run(storage: Storage, options: Options, config: &BenchConfig) -> Vec<BenchResult> {
+    let tables = (*storage.read()).clone();
+    let mut user_ids: Vec<u32> = tables.users.keys().collect();
+    let mut visit_ids: Vec<u32> = tables.visits.keys().collect();
+    user_ids.sort();
+    visit_ids.sort();
+    let user_ids = Arc::new(user_ids);
+    let visit_ids = Arc::new(visit_ids);
+    let options = Arc::new(options);
+
+    let locked = Arc::new(LockedTables(RwLock::new(tables)));
+    let storage = Arc::new(storage);
+    let writes_only = BenchConfig {
+        readers: 0,
+        writers: config.writers,
+        duration: config.duration,
+    };
+    vec![
+        run_one("copy-on-write", storage.clone(), &options, &user_ids, &visit_ids, config),
+        run_one("single lock", locked.clone(), &options, &user_ids, &visit_ids, config),
+        run_one(
+            "copy-on-write, writes only",
+            storage,
+            &options,
+            &user_ids,
+            &visit_ids,
+            &writes_only,
+        ),
+        run_one("single lock, writes only", locked, &options, &user_ids, &visit_ids, &writes_only),
+    ]
+}
+
+fn run_one<S>(
+    name: &'static str,
+    store: Arc<S>,
+    options: &Arc<Options>,
+    user_ids: &Arc<Vec<u32>>,
+    visit_ids: &Arc<Vec<u32>>,
+    config: &BenchConfig,
+) -> BenchResult
+where
+    S: Store + 'static,
+{
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+
+    let readers: Vec<_> = (0..config.readers)
+        .map(|reader| {
+            let store = store.clone();
+            let stop = stop.clone();
+            let user_ids = user_ids.clone();
+            thread::spawn(move || {
+                let mut state = 0x9E37_79B9 ^ (reader as u32 + 1);
+                let mut reads = 0u64;
+                while !stop.load(Ordering::Relaxed) && !user_ids.is_empty() {
+                    let user = user_ids[next_random(&mut state) as usize % user_ids.len()];
+                    store.read_user_visits(user);
+                    reads += 1;
+                }
+                reads
+            })
+        })
+        .collect();
+
+    let writers: Vec<_> = (0..config.writers)
+        .map(|writer| {
+            let store = store.clone();
+            let stop = stop.clone();
+            let options = options.clone();
+            let user_ids = user_ids.clone();
+            let visit_ids = visit_ids.clone();
+            thread::spawn(move || {
+                let mut state = 0x85EB_CA6B ^ (writer as u32 + 1);
+                let mut writes = 0u64;
+                while !stop.load(Ordering::Relaxed) && !visit_ids.is_empty() {
+                    let visit = visit_ids[next_random(&mut state) as usize % visit_ids.len()];
+                    // Every other write moves the visit to another user, which
+                    // rewrites two `user_visits` lists as well as the visit.
+                    let update = if writes % 2 == 0 {
+                        format!(r#"{{"mark":{}}}"#, next_random(&mut state) % 6)
+                    } else {
+                        let user = user_ids[next_random(&mut state) as usize % user_ids.len()];
+                        format!(r#"{{"user":{}}}"#, user)
+                    };
+                    let mutation =
+                        Mutation::VisitUpdate(visit, serde_json::from_str(&update).unwrap());
+                    store.write(&options, mutation);
+                    writes += 1;
+                }
+                writes
+            })
+        })
+        .collect();
+
+    thread::sleep(config.duration);
+    stop.store(true, Ordering::Relaxed);
+    let reads: u64 = readers.into_iter().map(|r| r.join().unwrap()).sum();
+    let writes: u64 = writers.into_iter().map(|w| w.join().unwrap()).sum();
+
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    BenchResult {
+        name: name,
+        reads_per_sec: reads as f64 / seconds,
+        writes_per_sec: writes as f64 / seconds,
+    }
+}
+
+// Makes the kernel start tracking the process's peak memory afresh.
+fn reset_peak_rss() {
+    let _ = OpenOptions::new()
+        .write(true)
+        .open("/proc/self/clear_refs")
+        .and_then(|mut file| file.write_all(b"5"));
+}
+
+fn peak_rss_kb() -> Option<usize> {
+    let mut status = String::new();
+    if File::open("/proc/self/status")
+        .and_then(|mut file| file.read_to_string(&mut status))
+        .is_err()
+    {
+        return None;
+    }
+    status
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse().ok())
+}
+
+fn measure_load(
+    name: String,
+    dir: &Path,
+    options: &Options,
+    threads: usize,
+) -> Result<(LoadResult, Storage), Box<Error>> {
+    reset_peak_rss();
+    let before = peak_rss_kb();
+    let start = Instant::now();
+    let (storage, report) = loader::load_dir(dir, options, LoadPolicy::Strict, threads)?;
+    let elapsed = start.elapsed();
+    let peak_kb = match (before, peak_rss_kb()) {
+        (Some(before), Some(after)) => Some(after.saturating_sub(before)),
+        _ => None,
+    };
+    let result = LoadResult {
+        name: name,
+        records: report.users + report.locations + report.visits,
+        seconds: elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9,
+        peak_kb: peak_kb,
+    };
+    Ok((result, storage))
+}
+
+/// Writes `factor` copies of the data in `storage` to `dir`, with each copy's
+/// ids moved past the previous one's, so loading can be measured on more data
+/// than there is.
+pub fn write_scaled(
+    storage: &Storage,
+    options: &Options,
+    factor: u32,
+    dir: &Path,
+) -> io::Result<export::ExportInfo> {
+    let tables = storage.read();
+    let user_step = tables.users.keys().max().unwrap_or(0);
+    let location_step = tables.locations.keys().max().unwrap_or(0);
+    let visit_step = tables.visits.keys().max().unwrap_or(0);
+
+    let mut scaled = Tables::default();
+    for copy in 0..factor {
+        for user in tables.users.values() {
+            let mut user = user.clone();
+            user.id += copy * user_step;
+            scaled.users.insert(user.id, user);
+        }
+        for location in tables.locations.values() {
+            let mut location = location.clone();
+            location.id += copy * location_step;
+            scaled.locations.insert(location.id, location);
+        }
+        for visit in tables.visits.values() {
+            let mut visit = visit.clone();
+            visit.id += copy * visit_step;
+            visit.user += copy * user_step;
+            visit.location += copy * location_step;
+            scaled.visits.insert(visit.id, visit);
+        }
+    }
+    let chunk_size = export::DEFAULT_CHUNK_SIZE;
+    export::export(dir, &Storage::new(scaled), options, chunk_size, false)
+}
+
+/// Measures loading the data files in `dir` on `threads` threads and, for a
+/// `scale` above 1, a data set that many times larger made from them.
+pub fn run_load(
+    dir: &Path,
+    options: &Options,
+    scale: u32,
+    threads: usize,
+) -> Result<Vec<LoadResult>, Box<Error>> {
+    let name = dir.to_string_lossy().into_owned();
+    let (result, storage) = measure_load(name, dir, options, threads)?;
+    let mut results = vec![result];
+    if scale > 1 {
+        let scaled_dir = env::temp_dir().join(format!("rustler-load-{}x", scale));
+        write_scaled(&storage, options, scale, &scaled_dir)?;
+        drop(storage);
+        let measured = measure_load(format!("{}x", scale), &scaled_dir, options, threads);
+        fs::remove_dir_all(&scaled_dir)?;
+        results.push(measured?.0);
+    }
+    Ok(results)
+}
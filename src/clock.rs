@@ -0,0 +1,124 @@
+use Storage;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i32 = 24 * 60 * 60;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClockSource {
+    /// The timestamp from `options.txt`, until it's set through the admin
+    /// endpoint.
+    Fixed,
+    /// The system clock.
+    System,
+    /// Starts at the timestamp from `options.txt` and runs with the system
+    /// clock.
+    Offset,
+}
+
+impl ClockSource {
+    pub fn parse(value: &str) -> Option<ClockSource> {
+        match value {
+            "fixed" => Some(ClockSource::Fixed),
+            "system" => Some(ClockSource::System),
+            "offset" => Some(ClockSource::Offset),
+            _ => None,
+        }
+    }
+}
+
+/// The "now" ages are calculated against.
+#[derive(Debug)]
+pub struct Clock {
+    source: ClockSource,
+    /// The time itself for a fixed clock, seconds added to the system time
+    /// for an offset one.
+    value: AtomicIsize,
+}
+
+fn system_now() -> i32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i32)
+        .unwrap_or(0)
+}
+
+impl Clock {
+    /// A clock of the given source reading `now` at first; ignored for the
+    /// system clock.
+    pub fn new(source: ClockSource, now: i32) -> Clock {
+        let clock = Clock {
+            source: source,
+            value: AtomicIsize::new(0),
+        };
+        clock.set_now(now);
+        clock
+    }
+
+    pub fn fixed(now: i32) -> Clock {
+        Clock::new(ClockSource::Fixed, now)
+    }
+
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+
+    pub fn now(&self) -> i32 {
+        let value = self.value.load(Ordering::SeqCst);
+        match self.source {
+            ClockSource::Fixed => value as i32,
+            ClockSource::System => system_now(),
+            ClockSource::Offset => (system_now() as isize + value) as i32,
+        }
+    }
+
+    /// Makes the clock read `now`; an offset clock keeps running from there.
+    /// Returns false for the system clock, which can't be set.
+    pub fn set_now(&self, now: i32) -> bool {
+        let value = match self.source {
+            ClockSource::Fixed => now as isize,
+            ClockSource::System => return false,
+            ClockSource::Offset => now as isize - system_now() as isize,
+        };
+        self.value.store(value, Ordering::SeqCst);
+        true
+    }
+}
+
+/// Days since 1970-01-01 at `now`. Ages only change when this does.
+pub fn day_of(now: i32) -> i32 {
+    if now >= 0 {
+        now / SECONDS_PER_DAY
+    } else {
+        (now + 1) / SECONDS_PER_DAY - 1
+    }
+}
+
+/// Recomputes `Tables::visitors` for `now`.
+pub fn refresh_visitors(storage: &Storage, now: i32) {
+    storage.update(|tables| tables.refresh_visitors(now));
+}
+
+/// Starts a thread that refreshes `Tables::visitors` whenever `clock` passes
+/// midnight, so the cached ages don't fall behind a running clock.
+pub fn refresh_visitors_daily(storage: Arc<Storage>, clock: Arc<Clock>) {
+    if clock.source() == ClockSource::Fixed {
+        return;
+    }
+    thread::spawn(move || {
+        let mut day = day_of(clock.now());
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let now = clock.now();
+            if day_of(now) != day {
+                day = day_of(now);
+                println!("Clock passed midnight, refreshing ages for now = {}", now);
+                refresh_visitors(&storage, now);
+            }
+        }
+    });
+}
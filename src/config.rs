@@ -0,0 +1,423 @@
+use admin::AdminToken;
+use clock::ClockSource;
+use integrity::IntegrityPolicy;
+use loader::{self, LoadPolicy};
+use wal::SyncPolicy;
+
+use toml;
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const KEYS: &[&str] = &[
+    "data_dir",
+    "input_format",
+    "options_file",
+    "zip_file",
+    "tar_file",
+    "address",
+    "port",
+    "workers",
+    "clock",
+    "snapshot_path",
+    "discard_snapshot",
+    "wal_path",
+    "wal_sync",
+    "export_root",
+    "reload_root",
+    "admin_token",
+    "integrity_check",
+    "load_policy",
+    "load_threads",
+];
+
+const ENV_PREFIX: &str = "RUSTLER_";
+const DEFAULT_CONFIG_FILE: &str = "rustler.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// `users_1.json` and so on, straight in `data_dir`.
+    Dir,
+    /// The same files packed in `zip_file`.
+    Zip,
+    /// The same files in `tar_file`, gzipped if it ends in `.gz` or `.tgz`.
+    Tar,
+    /// A tar stream on standard input.
+    Stdin,
+}
+
+impl InputFormat {
+    pub fn parse(value: &str) -> Option<InputFormat> {
+        match value {
+            "dir" => Some(InputFormat::Dir),
+            "zip" => Some(InputFormat::Zip),
+            "tar" => Some(InputFormat::Tar),
+            "stdin" => Some(InputFormat::Stdin),
+            _ => None,
+        }
+    }
+}
+
+/// Server settings. Each one is taken from, in increasing order of
+/// precedence: its default; a TOML file given by `--config <path>` or
+/// `RUSTLER_CONFIG`, else `rustler.toml` if there is one; the environment
+/// variable `RUSTLER_<KEY>`, e.g. `RUSTLER_DATA_DIR`; the flag `--<key>`
+/// with dashes, e.g. `--data-dir`. Keys are the field names.
+#[derive(Debug)]
+pub struct Config {
+    /// Holds the input and `options_file`. Default `data`.
+    pub data_dir: PathBuf,
+    /// `dir`, `zip`, `tar` or `stdin`. Default `dir`.
+    pub input_format: InputFormat,
+    /// Default `options.txt`.
+    pub options_file: String,
+    /// Read for `zip` input. Default `data.zip`.
+    pub zip_file: String,
+    /// Read for `tar` input. Default `data.tar.gz`.
+    pub tar_file: String,
+    /// Default `localhost`.
+    pub address: String,
+    /// Default 8000.
+    pub port: u16,
+    /// Request threads. Rocket picks if unset.
+    pub workers: Option<u16>,
+    /// `fixed`, `system` or `offset`. Default `fixed`.
+    pub clock: ClockSource,
+    /// Default `data_dir` with a `.snapshot` extension.
+    pub snapshot_path: PathBuf,
+    /// Start from the data files when there's a snapshot that can't be used,
+    /// dropping the changes it and the WAL hold. Default `false`, which
+    /// refuses to start instead.
+    pub discard_snapshot: bool,
+    /// Default `data_dir` with a `.wal` extension.
+    pub wal_path: PathBuf,
+    /// `always`, `never` or a number of records. Default `always`.
+    pub wal_sync: SyncPolicy,
+    /// Where `/admin/export` writes; its `dir` is taken relative to this.
+    /// Default `export`.
+    pub export_root: PathBuf,
+    /// Where `/admin/reload` reads from; its `path` is taken relative to
+    /// this. Default the directory `data_dir` is in.
+    pub reload_root: PathBuf,
+    /// Admin requests that change state, like snapshots, exports, setting
+    /// the clock and reloads, must carry it in an `X-Admin-Token` header.
+    /// They're refused while it's unset, as by default.
+    pub admin_token: AdminToken,
+    /// `off`, `report`, `reject` or `strict`. Default `report`.
+    pub integrity_check: IntegrityPolicy,
+    /// `strict` or `lenient`, also set by `--strict` and `--lenient`.
+    /// Default `strict`.
+    pub load_policy: LoadPolicy,
+    /// Threads to parse data files on. Default one per CPU.
+    pub load_threads: usize,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Io(PathBuf, io::Error),
+    /// The config file isn't a TOML table.
+    Syntax(PathBuf, String),
+    /// A key or flag that isn't a setting.
+    Unknown(String),
+    /// A flag without its value.
+    MissingValue(String),
+    /// A value the setting can't take, with where it was set.
+    Invalid {
+        source: String,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref path, ref e) => write!(f, "can't read {:?}: {}", path, e),
+            ConfigError::Syntax(ref path, ref e) => write!(f, "{:?} is not valid: {}", path, e),
+            ConfigError::Unknown(ref name) => write!(f, "unknown setting {}", name),
+            ConfigError::MissingValue(ref flag) => write!(f, "{} needs a value", flag),
+            ConfigError::Invalid {
+                ref source,
+                ref value,
+                expected,
+            } => write!(f, "invalid {} {:?}, expected {}", source, value, expected),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        "invalid configuration"
+    }
+}
+
+// A setting's value as given, and where it was given for error messages.
+struct Raw {
+    value: String,
+    source: String,
+}
+
+type RawSettings = HashMap<&'static str, Raw>;
+
+fn key_named(name: &str) -> Option<&'static str> {
+    KEYS.iter().cloned().find(|key| *key == name)
+}
+
+fn read_file(path: &Path, settings: &mut RawSettings) -> Result<(), ConfigError> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+    let table = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => table,
+        Ok(_) => return Err(ConfigError::Syntax(path.to_owned(), "not a table".to_owned())),
+        Err(e) => return Err(ConfigError::Syntax(path.to_owned(), e.to_string())),
+    };
+    for (name, value) in table {
+        let source = format!("{} in {:?}", name, path);
+        let key = key_named(&name).ok_or_else(|| ConfigError::Unknown(source.clone()))?;
+        let value = match value {
+            toml::Value::String(s) => s,
+            other => other.to_string(),
+        };
+        settings.insert(key, Raw { value: value, source: source });
+    }
+    Ok(())
+}
+
+fn read_env(vars: &HashMap<String, String>, settings: &mut RawSettings) {
+    for (name, value) in vars {
+        if !name.starts_with(ENV_PREFIX) {
+            continue;
+        }
+        // Others, like `RUSTLER_CONFIG`, aren't settings.
+        if let Some(key) = key_named(&name[ENV_PREFIX.len()..].to_lowercase()) {
+            let raw = Raw {
+                value: value.clone(),
+                source: name.clone(),
+            };
+            settings.insert(key, raw);
+        }
+    }
+}
+
+// Takes the flags that are settings out of `args`, and returns the rest for
+// the command.
+fn read_flags(args: &[String], settings: &mut RawSettings) -> Result<Vec<String>, ConfigError> {
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--strict" || arg == "--lenient" {
+            let raw = Raw {
+                value: arg[2..].to_owned(),
+                source: arg.clone(),
+            };
+            settings.insert("load_policy", raw);
+            continue;
+        }
+        let key = if arg.starts_with("--") {
+            key_named(&arg[2..].replace('-', "_"))
+        } else {
+            None
+        };
+        match key {
+            Some(key) => {
+                let value = args.next().ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                let raw = Raw {
+                    value: value.clone(),
+                    source: arg.clone(),
+                };
+                settings.insert(key, raw);
+            }
+            None => rest.push(arg.clone()),
+        }
+    }
+    Ok(rest)
+}
+
+// `--config <path>`, taken out of `args`, else `RUSTLER_CONFIG`. `None` means
+// the default file, which doesn't have to exist.
+fn config_file_path(
+    args: &mut Vec<String>,
+    vars: &HashMap<String, String>,
+) -> Result<Option<PathBuf>, ConfigError> {
+    if let Some(position) = args.iter().position(|arg| arg == "--config") {
+        if position + 1 == args.len() {
+            return Err(ConfigError::MissingValue("--config".to_owned()));
+        }
+        let path = args.remove(position + 1);
+        args.remove(position);
+        return Ok(Some(PathBuf::from(path)));
+    }
+    let name = format!("{}CONFIG", ENV_PREFIX);
+    Ok(vars.get(&name).map(PathBuf::from))
+}
+
+fn setting<T, F>(
+    settings: &RawSettings,
+    key: &str,
+    expected: &'static str,
+    parse: F,
+) -> Result<Option<T>, ConfigError>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    let raw = match settings.get(key) {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    match parse(&raw.value) {
+        Some(value) => Ok(Some(value)),
+        None => Err(ConfigError::Invalid {
+            source: raw.source.clone(),
+            value: raw.value.clone(),
+            expected: expected,
+        }),
+    }
+}
+
+fn parse_non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_path(value: &str) -> Option<PathBuf> {
+    parse_non_empty(value).map(PathBuf::from)
+}
+
+fn parse_file_name(value: &str) -> Option<String> {
+    parse_non_empty(value).and_then(|name| if name.contains('/') { None } else { Some(name) })
+}
+
+impl Config {
+    /// Reads the settings from the config file, the environment and `args`.
+    /// Returns them with the arguments that weren't settings.
+    pub fn load(args: &[String]) -> Result<(Config, Vec<String>), ConfigError> {
+        Config::load_from(args, &env::vars().collect(), Path::new(DEFAULT_CONFIG_FILE))
+    }
+
+    pub fn load_from(
+        args: &[String],
+        vars: &HashMap<String, String>,
+        default_file: &Path,
+    ) -> Result<(Config, Vec<String>), ConfigError> {
+        let mut args = args.to_vec();
+        let mut settings = RawSettings::new();
+        match config_file_path(&mut args, vars)? {
+            Some(path) => read_file(&path, &mut settings)?,
+            None => if default_file.exists() {
+                read_file(default_file, &mut settings)?;
+            },
+        }
+        read_env(vars, &mut settings);
+        let rest = read_flags(&args, &mut settings)?;
+        Ok((Config::from_settings(&settings)?, rest))
+    }
+
+    fn from_settings(settings: &RawSettings) -> Result<Config, ConfigError> {
+        let data_dir = setting(settings, "data_dir", "a path", parse_path)?
+            .unwrap_or_else(|| PathBuf::from("data"));
+        let snapshot_path = setting(settings, "snapshot_path", "a path", parse_path)?
+            .unwrap_or_else(|| data_dir.with_extension("snapshot"));
+        let wal_path = setting(settings, "wal_path", "a path", parse_path)?
+            .unwrap_or_else(|| data_dir.with_extension("wal"));
+        let reload_root = setting(settings, "reload_root", "a path", parse_path)?
+            .unwrap_or_else(|| match data_dir.parent() {
+                Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            });
+        let workers = setting(settings, "workers", "a positive number", |value| {
+            value.parse::<u16>().ok().and_then(|n| if n == 0 { None } else { Some(n) })
+        })?;
+        Ok(Config {
+            input_format: setting(
+                settings,
+                "input_format",
+                "dir, zip, tar or stdin",
+                InputFormat::parse,
+            )?
+                .unwrap_or(InputFormat::Dir),
+            options_file: setting(settings, "options_file", "a file name", parse_file_name)?
+                .unwrap_or_else(|| "options.txt".to_owned()),
+            zip_file: setting(settings, "zip_file", "a file name", parse_file_name)?
+                .unwrap_or_else(|| "data.zip".to_owned()),
+            tar_file: setting(settings, "tar_file", "a file name", parse_file_name)?
+                .unwrap_or_else(|| "data.tar.gz".to_owned()),
+            address: setting(settings, "address", "a host name or IP", parse_non_empty)?
+                .unwrap_or_else(|| "localhost".to_owned()),
+            port: setting(settings, "port", "a port number", |value| value.parse().ok())?
+                .unwrap_or(8000),
+            workers: workers,
+            clock: setting(settings, "clock", "fixed, system or offset", ClockSource::parse)?
+                .unwrap_or(ClockSource::Fixed),
+            discard_snapshot: setting(settings, "discard_snapshot", "true or false", parse_bool)?
+                .unwrap_or(false),
+            wal_sync: setting(settings, "wal_sync", "always, never or a count", SyncPolicy::parse)?
+                .unwrap_or(SyncPolicy::Always),
+            export_root: setting(settings, "export_root", "a path", parse_path)?
+                .unwrap_or_else(|| PathBuf::from("export")),
+            admin_token: AdminToken(setting(
+                settings,
+                "admin_token",
+                "a non-empty token",
+                parse_non_empty,
+            )?),
+            integrity_check: setting(
+                settings,
+                "integrity_check",
+                "off, report, reject or strict",
+                IntegrityPolicy::parse,
+            )?
+                .unwrap_or(IntegrityPolicy::Report),
+            load_policy: setting(settings, "load_policy", "strict or lenient", LoadPolicy::parse)?
+                .unwrap_or(LoadPolicy::Strict),
+            load_threads: setting(settings, "load_threads", "a positive number", |value| {
+                value.parse::<usize>().ok().and_then(|n| if n == 0 { None } else { Some(n) })
+            })?
+                .unwrap_or_else(loader::default_threads),
+            data_dir: data_dir,
+            snapshot_path: snapshot_path,
+            wal_path: wal_path,
+            reload_root: reload_root,
+        })
+    }
+
+    pub fn options_path(&self) -> PathBuf {
+        self.data_dir.join(&self.options_file)
+    }
+
+    pub fn zip_path(&self) -> PathBuf {
+        self.data_dir.join(&self.zip_file)
+    }
+
+    pub fn tar_path(&self) -> PathBuf {
+        self.data_dir.join(&self.tar_file)
+    }
+
+    /// Where the data was loaded from, unless it can't be read again.
+    pub fn input_path(&self) -> Option<PathBuf> {
+        match self.input_format {
+            InputFormat::Dir => Some(self.data_dir.clone()),
+            InputFormat::Zip => Some(self.zip_path()),
+            InputFormat::Tar => Some(self.tar_path()),
+            InputFormat::Stdin => None,
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::Json;
+use validation::Violation;
+use wal::Outcome;
+
+use std::io;
+
+#[derive(Serialize, Debug)]
+pub struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")] field: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")] violations: Vec<Violation>,
+    /// Where the entity that caused a conflict can be found.
+    #[serde(skip_serializing_if = "Option::is_none")] existing: Option<String>,
+}
+
+/// An error response with a JSON body, for clients that need to tell apart
+/// why a request was refused.
+#[derive(Debug)]
+pub struct ApiError {
+    status: Status,
+    body: ErrorBody,
+}
+
+impl ApiError {
+    pub fn new(status: Status, code: &'static str, message: String) -> ApiError {
+        ApiError {
+            status: status,
+            body: ErrorBody {
+                code: code,
+                message: message,
+                field: None,
+                violations: Vec::new(),
+                existing: None,
+            },
+        }
+    }
+
+    pub fn with_field(mut self, field: &str) -> ApiError {
+        self.body.field = Some(field.to_owned());
+        self
+    }
+
+    pub fn with_existing(mut self, uri: String) -> ApiError {
+        self.body.existing = Some(uri);
+        self
+    }
+
+    pub fn not_found(entity: &str, id: u32) -> ApiError {
+        ApiError::new(
+            Status::NotFound,
+            "not_found",
+            format!("{} {} does not exist", entity, id),
+        )
+    }
+
+    pub fn already_exists(entity: &str, id: u32) -> ApiError {
+        ApiError::new(
+            Status::Conflict,
+            "already_exists",
+            format!("{} {} already exists", entity, id),
+        ).with_field("id")
+    }
+
+    pub fn invalid_query(message: &str) -> ApiError {
+        ApiError::new(Status::BadRequest, "invalid_query", message.to_owned())
+    }
+
+    /// The error for the outcome of a mutation on `entity` `id`, or `None` if
+    /// it was applied.
+    pub fn from_outcome(outcome: Outcome, entity: &str, id: u32) -> Option<ApiError> {
+        let error = match outcome {
+            Outcome::Applied => return None,
+            Outcome::NotFound => ApiError::not_found(entity, id),
+            Outcome::AlreadyExists => ApiError::already_exists(entity, id),
+            Outcome::HasDependents => ApiError::new(
+                Status::Conflict,
+                "has_dependents",
+                format!("{} {} still has visits", entity, id),
+            ),
+            Outcome::HasOrphans => ApiError::new(
+                Status::Conflict,
+                "has_orphans",
+                format!(
+                    "visits of the deleted {} {} still reference the id; \
+                     delete it with dependents=cascade to remove them",
+                    entity, id
+                ),
+            ).with_field("id"),
+            Outcome::IdsExhausted => ApiError::new(
+                Status::Conflict,
+                "ids_exhausted",
+                format!("no {} ids are left to pick from; give one explicitly", entity),
+            ).with_field("id"),
+            Outcome::MissingReference(field, missing_id) => {
+                ApiError::missing_reference(field, missing_id)
+            }
+        };
+        Some(error)
+    }
+
+    pub fn missing_reference(field: &str, id: u32) -> ApiError {
+        ApiError::new(
+            Status::BadRequest,
+            "missing_reference",
+            format!("{} {} does not exist", field, id),
+        ).with_field(field)
+    }
+
+    pub fn violations(violations: Vec<Violation>) -> ApiError {
+        let mut error = ApiError::new(
+            Status::BadRequest,
+            "invalid_fields",
+            format!("{} field(s) failed validation", violations.len()),
+        );
+        error.body.violations = violations;
+        error
+    }
+}
+
+impl Responder<'static> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'static> {
+        Response::build_from(Json(self.body).respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(error: io::Error) -> ApiError {
+        ApiError::new(Status::InternalServerError, "internal", error.to_string())
+    }
+}
+
+#[error(400)]
+fn bad_request(request: &Request) -> ApiError {
+    ApiError::new(
+        Status::BadRequest,
+        "bad_request",
+        format!("malformed request to {}", request.uri()),
+    )
+}
+
+#[error(404)]
+fn not_found(request: &Request) -> ApiError {
+    ApiError::new(
+        Status::NotFound,
+        "not_found",
+        format!("nothing at {}", request.uri()),
+    )
+}
+
+#[error(409)]
+fn conflict(request: &Request) -> ApiError {
+    ApiError::new(
+        Status::Conflict,
+        "conflict",
+        format!("request to {} conflicts with existing data", request.uri()),
+    )
+}
+
+#[error(422)]
+fn unprocessable_entity(request: &Request) -> ApiError {
+    ApiError::new(
+        Status::UnprocessableEntity,
+        "unprocessable_entity",
+        format!("couldn't process the body of request to {}", request.uri()),
+    )
+}
+
+#[error(500)]
+fn internal_error(request: &Request) -> ApiError {
+    ApiError::new(
+        Status::InternalServerError,
+        "internal",
+        format!("request to {} failed", request.uri()),
+    )
+}
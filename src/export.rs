@@ -0,0 +1,110 @@
+use Mode;
+use Options;
+use Storage;
+
+use serde::Serialize;
+use serde_json;
+use zip;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 10000;
+
+/// Where `/admin/export` may write.
+pub struct ExportRoot(pub PathBuf);
+
+#[derive(Serialize, Debug)]
+pub struct ExportInfo {
+    dir: String,
+    files: Vec<String>,
+}
+
+/// Writes the current state of `storage` to `dir` in the layout the loaders
+/// expect: `users_N.json`, `locations_N.json` and `visits_N.json` with at most
+/// `chunk_size` entities each, plus `options.txt`. With `pack_zip` the JSON
+/// files go into `data.zip` instead, as in production.
+pub fn export(
+    dir: &Path,
+    storage: &Storage,
+    options: &Options,
+    chunk_size: usize,
+    pack_zip: bool,
+) -> io::Result<ExportInfo> {
+    if chunk_size == 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "chunk size must be positive",
+        ));
+    }
+    fs::create_dir_all(dir)?;
+
+    let tables = storage.read();
+    let mut chunks = Vec::new();
+    {
+        let mut users: Vec<_> = tables.users.values().collect();
+        users.sort_by_key(|u| u.id);
+        serialize_chunks(&mut chunks, "users", &users, chunk_size)?;
+    }
+    {
+        let mut locations: Vec<_> = tables.locations.values().collect();
+        locations.sort_by_key(|l| l.id);
+        serialize_chunks(&mut chunks, "locations", &locations, chunk_size)?;
+    }
+    {
+        let mut visits: Vec<_> = tables.visits.values().collect();
+        visits.sort_by_key(|v| v.id);
+        serialize_chunks(&mut chunks, "visits", &visits, chunk_size)?;
+    }
+
+    let mut files = Vec::new();
+    if pack_zip {
+        let mut zip = zip::ZipWriter::new(File::create(dir.join("data.zip"))?);
+        for &(ref name, ref content) in &chunks {
+            zip.start_file(name.as_str(), zip::CompressionMethod::Deflated)?;
+            zip.write_all(content)?;
+        }
+        zip.finish()?;
+        files.push("data.zip".to_owned());
+    } else {
+        for (name, content) in chunks {
+            let mut file = BufWriter::new(File::create(dir.join(&name))?);
+            file.write_all(&content)?;
+            file.flush()?;
+            files.push(name);
+        }
+    }
+
+    let mode = match options.mode {
+        Mode::Test => 0,
+        Mode::Rating => 1,
+    };
+    let mut options_file = File::create(dir.join("options.txt"))?;
+    write!(options_file, "{}\n{}", options.now(), mode)?;
+    files.push("options.txt".to_owned());
+
+    Ok(ExportInfo {
+        dir: dir.to_string_lossy().into_owned(),
+        files: files,
+    })
+}
+
+fn serialize_chunks<T>(
+    chunks: &mut Vec<(String, Vec<u8>)>,
+    entity_name: &str,
+    entities: &[T],
+    chunk_size: usize,
+) -> io::Result<()>
+where
+    T: Serialize,
+{
+    for (index, chunk) in entities.chunks(chunk_size).enumerate() {
+        let mut content = BTreeMap::new();
+        content.insert(entity_name, chunk);
+        let name = format!("{}_{}.json", entity_name, index + 1);
+        chunks.push((name, serde_json::to_vec(&content)?));
+    }
+    Ok(())
+}
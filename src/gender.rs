@@ -1,7 +1,7 @@
 use rocket::http::RawStr;
 use rocket::request::FromFormValue;
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Gender {
     Unknown,
     #[serde(rename = "m")] Male,
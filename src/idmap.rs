@@ -0,0 +1,413 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::fmt;
+use std::iter::{FlatMap, Map};
+use std::mem;
+use std::ops::Index;
+use std::slice;
+use std::sync::Arc;
+
+// Ids below this live in dense chunks; anything above goes to the sparse
+// shards so one outlying id can't make us allocate millions of slots. A write
+// to a shared chunk or shard copies all of its values, `Vec`s included, so
+// both are kept small.
+const DENSE_LIMIT: u32 = 1 << 22;
+const CHUNK_BITS: u32 = 6;
+const CHUNK_LEN: usize = 1 << CHUNK_BITS;
+const SPARSE_SHARDS: usize = 256;
+
+#[derive(Clone)]
+struct Chunk<V> {
+    slots: Vec<Option<V>>,
+}
+
+type Shard<V> = Arc<HashMap<u32, V>>;
+
+/// A map from ids, stored as a vector indexed by id. The vector is split into
+/// chunks behind `Arc`s, so cloning the map only bumps reference counts and a
+/// write copies just the chunk it lands in, and only if that chunk is still
+/// shared with another clone. Ids too large to index go to hashed shards.
+#[derive(Clone)]
+pub struct IdMap<V> {
+    chunks: Vec<Option<Arc<Chunk<V>>>>,
+    sparse: Vec<Shard<V>>,
+    /// The largest id ever inserted; removals don't lower it.
+    max_id: Option<u32>,
+    len: usize,
+}
+
+/// Like `hash_map::Entry`.
+pub enum Entry<'a, V: 'a> {
+    Occupied(OccupiedEntry<'a, V>),
+    Vacant(VacantEntry<'a, V>),
+}
+
+pub struct OccupiedEntry<'a, V: 'a> {
+    value: &'a mut V,
+}
+
+pub struct VacantEntry<'a, V: 'a> {
+    slot: VacantSlot<'a, V>,
+    id: u32,
+    max_id: &'a mut Option<u32>,
+    len: &'a mut usize,
+}
+
+enum VacantSlot<'a, V: 'a> {
+    Dense(&'a mut Option<V>),
+    Sparse(hash_map::VacantEntry<'a, u32, V>),
+}
+
+impl<'a, V> OccupiedEntry<'a, V> {
+    pub fn get(&self) -> &V {
+        &*self.value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut *self.value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        self.value
+    }
+}
+
+impl<'a, V> VacantEntry<'a, V> {
+    pub fn insert(self, value: V) -> &'a mut V {
+        raise(self.max_id, self.id);
+        *self.len += 1;
+        match self.slot {
+            VacantSlot::Dense(slot) => {
+                *slot = Some(value);
+                slot.as_mut().unwrap()
+            }
+            VacantSlot::Sparse(entry) => entry.insert(value),
+        }
+    }
+}
+
+impl<'a, V> Entry<'a, V> {
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default()),
+        }
+    }
+}
+
+fn dense_position(id: u32) -> Option<(usize, usize)> {
+    if id < DENSE_LIMIT {
+        Some(((id >> CHUNK_BITS) as usize, id as usize & (CHUNK_LEN - 1)))
+    } else {
+        None
+    }
+}
+
+fn shard_of(id: u32) -> usize {
+    id as usize % SPARSE_SHARDS
+}
+
+fn raise(max_id: &mut Option<u32>, id: u32) {
+    if max_id.map_or(true, |max| max < id) {
+        *max_id = Some(id);
+    }
+}
+
+// Allocates the chunk if needed and copies it if it's shared.
+fn chunk_mut<V: Clone>(chunks: &mut Vec<Option<Arc<Chunk<V>>>>, chunk: usize) -> &mut Chunk<V> {
+    if chunks.len() <= chunk {
+        chunks.resize(chunk + 1, None);
+    }
+    let slot = &mut chunks[chunk];
+    if slot.is_none() {
+        *slot = Some(Arc::new(Chunk {
+            slots: (0..CHUNK_LEN).map(|_| None).collect(),
+        }));
+    }
+    Arc::make_mut(slot.as_mut().unwrap())
+}
+
+pub struct Iter<'a, V: 'a> {
+    map: &'a IdMap<V>,
+    chunk: usize,
+    offset: usize,
+    sparse: FlatMap<
+        slice::Iter<'a, Shard<V>>,
+        hash_map::Iter<'a, u32, V>,
+        fn(&'a Shard<V>) -> hash_map::Iter<'a, u32, V>,
+    >,
+}
+
+fn shard_iter<V>(shard: &Shard<V>) -> hash_map::Iter<u32, V> {
+    shard.iter()
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (u32, &'a V);
+
+    fn next(&mut self) -> Option<(u32, &'a V)> {
+        let map = self.map;
+        while self.chunk < map.chunks.len() {
+            if let Some(ref chunk) = map.chunks[self.chunk] {
+                while self.offset < CHUNK_LEN {
+                    let offset = self.offset;
+                    self.offset += 1;
+                    if let Some(ref value) = chunk.slots[offset] {
+                        return Some((((self.chunk << CHUNK_BITS) + offset) as u32, value));
+                    }
+                }
+            }
+            self.chunk += 1;
+            self.offset = 0;
+        }
+        self.sparse.next().map(|(id, value)| (*id, value))
+    }
+}
+
+pub type Keys<'a, V> = Map<Iter<'a, V>, fn((u32, &'a V)) -> u32>;
+pub type Values<'a, V> = Map<Iter<'a, V>, fn((u32, &'a V)) -> &'a V>;
+
+fn entry_key<V>((id, _): (u32, &V)) -> u32 {
+    id
+}
+
+fn entry_value<V>((_, value): (u32, &V)) -> &V {
+    value
+}
+
+impl<V: Clone> IdMap<V> {
+    pub fn new() -> IdMap<V> {
+        IdMap {
+            chunks: Vec::new(),
+            sparse: (0..SPARSE_SHARDS).map(|_| Arc::new(HashMap::new())).collect(),
+            max_id: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, id: &u32) -> Option<&V> {
+        match dense_position(*id) {
+            Some((chunk, offset)) => match self.chunks.get(chunk) {
+                Some(&Some(ref chunk)) => chunk.slots[offset].as_ref(),
+                _ => None,
+            },
+            None => self.sparse[shard_of(*id)].get(id),
+        }
+    }
+
+    pub fn contains_key(&self, id: &u32) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// The largest id the map has held, even if it was removed since.
+    pub fn max_id(&self) -> Option<u32> {
+        self.max_id
+    }
+
+    /// Makes `max_id` at least `id`, for a map rebuilt without the ids that
+    /// were removed from it.
+    pub fn raise_max_id(&mut self, id: u32) {
+        raise(&mut self.max_id, id);
+    }
+
+    fn chunk_mut(&mut self, chunk: usize) -> &mut Chunk<V> {
+        chunk_mut(&mut self.chunks, chunk)
+    }
+
+    pub fn get_mut(&mut self, id: &u32) -> Option<&mut V> {
+        if !self.contains_key(id) {
+            return None;
+        }
+        match dense_position(*id) {
+            Some((chunk, offset)) => self.chunk_mut(chunk).slots[offset].as_mut(),
+            None => Arc::make_mut(&mut self.sparse[shard_of(*id)]).get_mut(id),
+        }
+    }
+
+    pub fn insert(&mut self, id: u32, value: V) -> Option<V> {
+        raise(&mut self.max_id, id);
+        let old = match dense_position(id) {
+            Some((chunk, offset)) => {
+                mem::replace(&mut self.chunk_mut(chunk).slots[offset], Some(value))
+            }
+            None => Arc::make_mut(&mut self.sparse[shard_of(id)]).insert(id, value),
+        };
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    pub fn remove(&mut self, id: &u32) -> Option<V> {
+        if !self.contains_key(id) {
+            return None;
+        }
+        self.len -= 1;
+        match dense_position(*id) {
+            Some((chunk, offset)) => self.chunk_mut(chunk).slots[offset].take(),
+            None => Arc::make_mut(&mut self.sparse[shard_of(*id)]).remove(id),
+        }
+    }
+
+    /// Like `HashMap::entry`. Copies the chunk if it's shared, whether or not
+    /// the entry is then written to.
+    pub fn entry(&mut self, id: u32) -> Entry<V> {
+        let IdMap {
+            ref mut chunks,
+            ref mut sparse,
+            ref mut max_id,
+            ref mut len,
+        } = *self;
+        match dense_position(id) {
+            Some((chunk, offset)) => {
+                let slot = &mut chunk_mut(chunks, chunk).slots[offset];
+                if slot.is_some() {
+                    Entry::Occupied(OccupiedEntry {
+                        value: slot.as_mut().unwrap(),
+                    })
+                } else {
+                    Entry::Vacant(VacantEntry {
+                        slot: VacantSlot::Dense(slot),
+                        id: id,
+                        max_id: max_id,
+                        len: len,
+                    })
+                }
+            }
+            None => match Arc::make_mut(&mut sparse[shard_of(id)]).entry(id) {
+                hash_map::Entry::Occupied(e) => Entry::Occupied(OccupiedEntry {
+                    value: e.into_mut(),
+                }),
+                hash_map::Entry::Vacant(e) => Entry::Vacant(VacantEntry {
+                    slot: VacantSlot::Sparse(e),
+                    id: id,
+                    max_id: max_id,
+                    len: len,
+                }),
+            },
+        }
+    }
+
+    /// Entries in id order, except that sparse ids come last in no order.
+    pub fn iter<'a>(&'a self) -> Iter<'a, V> {
+        Iter {
+            map: self,
+            chunk: 0,
+            offset: 0,
+            sparse: self.sparse
+                .iter()
+                .flat_map(shard_iter as fn(&'a Shard<V>) -> hash_map::Iter<'a, u32, V>),
+        }
+    }
+
+    pub fn keys<'a>(&'a self) -> Keys<'a, V> {
+        self.iter().map(entry_key as fn((u32, &'a V)) -> u32)
+    }
+
+    pub fn values<'a>(&'a self) -> Values<'a, V> {
+        self.iter().map(entry_value as fn((u32, &'a V)) -> &'a V)
+    }
+}
+
+/// Heap memory a value owns beyond its own size.
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+impl<T> HeapSize for Vec<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>()
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct MemoryUsage {
+    pub entries: usize,
+    /// Bytes this map holds, counting what its values own.
+    pub bytes: usize,
+    /// Estimate for the same entries in a `HashMap<u32, V>`, for comparison.
+    pub hash_map_bytes: usize,
+}
+
+impl<V: Clone + HeapSize> IdMap<V> {
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let owned: usize = self.values().map(|value| value.heap_size()).sum();
+        let chunk_size = mem::size_of::<Chunk<V>>() + CHUNK_LEN * mem::size_of::<Option<V>>();
+        let chunks = self.chunks.iter().filter(|c| c.is_some()).count();
+        let dense = self.chunks.capacity() * mem::size_of::<Option<Arc<Chunk<V>>>>()
+            + chunks * chunk_size;
+        let sparse: usize = self.sparse
+            .iter()
+            .map(|shard| hash_map_bytes::<V>(shard.capacity()))
+            .sum();
+
+        let entries = self.len();
+        MemoryUsage {
+            entries: entries,
+            bytes: dense + sparse + owned,
+            hash_map_bytes: hash_map_bytes::<V>(entries) + owned,
+        }
+    }
+}
+
+/// The size of `HashMap<u32, V>` with room for `capacity` entries, as the
+/// pinned nightly's std builds it: a Robin Hood table of a power of two
+/// buckets, at least 32, kept at most 10/11 full, with a `usize` hash per
+/// bucket stored beside the pairs.
+fn hash_map_bytes<V>(capacity: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = cmp::max(32, (capacity * 11 / 10).next_power_of_two());
+    buckets * (mem::size_of::<usize>() + mem::size_of::<(u32, V)>())
+}
+
+impl<V: Clone> Default for IdMap<V> {
+    fn default() -> Self {
+        IdMap::new()
+    }
+}
+
+impl<V: Clone> From<HashMap<u32, V>> for IdMap<V> {
+    fn from(map: HashMap<u32, V>) -> Self {
+        let mut ids = IdMap::new();
+        for (id, value) in map {
+            ids.insert(id, value);
+        }
+        ids
+    }
+}
+
+impl<'a, V: Clone> Index<&'a u32> for IdMap<V> {
+    type Output = V;
+
+    fn index(&self, id: &u32) -> &V {
+        self.get(id).expect("no entry found for id")
+    }
+}
+
+impl<'a, V: Clone> IntoIterator for &'a IdMap<V> {
+    type Item = (u32, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<V: Clone + PartialEq> PartialEq for IdMap<V> {
+    fn eq(&self, other: &IdMap<V>) -> bool {
+        self.len() == other.len() && self.iter().all(|(id, value)| other.get(&id) == Some(value))
+    }
+}
+
+impl<V: Clone + fmt::Debug> fmt::Debug for IdMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
@@ -0,0 +1,296 @@
+use Tables;
+use marks::{self, LocationMarks};
+use visits::{self, Visit};
+use wal::Mutation;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntegrityPolicy {
+    /// Don't scan at all.
+    Off,
+    /// Print what was found and carry on.
+    Report,
+    /// Refuse to start if the indexes don't agree with the visits. Dangling
+    /// references are only reported: deleting with `dependents=orphan`
+    /// leaves them on purpose.
+    Reject,
+    /// Refuse to start on dangling references as well.
+    Strict,
+}
+
+impl IntegrityPolicy {
+    pub fn parse(value: &str) -> Option<IntegrityPolicy> {
+        match value {
+            "off" => Some(IntegrityPolicy::Off),
+            "report" => Some(IntegrityPolicy::Report),
+            "reject" => Some(IntegrityPolicy::Reject),
+            "strict" => Some(IntegrityPolicy::Strict),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DanglingReference {
+    pub visit: u32,
+    pub field: &'static str,
+    pub id: u32,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct IntegrityReport {
+    /// Visits pointing at users or locations that don't exist.
+    pub dangling: Vec<DanglingReference>,
+    /// Visits missing from `location_visits`/`user_visits`, index entries
+    /// pointing at visits that don't exist, timelines out of order, or mark
+    /// sums that don't add up to the visits.
+    pub index_errors: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.dangling.is_empty() && self.index_errors.is_empty()
+    }
+
+    /// The indexes agree with the visits, whatever they point at.
+    pub fn is_consistent(&self) -> bool {
+        self.index_errors.is_empty()
+    }
+}
+
+/// The users, locations and visits a mutation may change the indexes of.
+#[derive(Debug, Default)]
+pub struct Scope {
+    users: Vec<u32>,
+    locations: Vec<u32>,
+    visits: Vec<u32>,
+}
+
+impl Scope {
+    /// What `mutation` names, with the visits of its users and locations and
+    /// the users and locations of its visits as `tables` has them before it
+    /// is applied.
+    pub fn of(mutation: &Mutation, tables: &Tables) -> Scope {
+        let mut scope = Scope::default();
+        match *mutation {
+            Mutation::UserNew(ref user) => scope.users.push(user.id),
+            Mutation::UserUpdate(id, _) | Mutation::UserDelete(id, _) => scope.users.push(id),
+            Mutation::LocationNew(ref location) => scope.locations.push(location.id),
+            Mutation::LocationUpdate(id, _) | Mutation::LocationDelete(id, _) => {
+                scope.locations.push(id)
+            }
+            Mutation::VisitNew(ref visit) => scope.visits.push(visit.id),
+            Mutation::VisitUpdate(id, _) | Mutation::VisitDelete(id) => scope.visits.push(id),
+        }
+        scope.widen(tables);
+        scope
+    }
+
+    fn widen(&mut self, tables: &Tables) {
+        for user in &self.users {
+            if let Some(ids) = tables.user_visits.get(user) {
+                self.visits.extend(ids);
+            }
+        }
+        for location in &self.locations {
+            if let Some(ids) = tables.location_visits.get(location) {
+                self.visits.extend(ids);
+            }
+        }
+        for visit in self.visits.iter().filter_map(|id| tables.visits.get(id)) {
+            self.users.push(visit.user);
+            self.locations.push(visit.location);
+        }
+        self.users.sort();
+        self.users.dedup();
+        self.locations.sort();
+        self.locations.dedup();
+        self.visits.sort();
+        self.visits.dedup();
+    }
+}
+
+/// Scans the whole dataset for references that don't resolve.
+pub fn check(tables: &Tables) -> IntegrityReport {
+    let mut report = IntegrityReport::default();
+    for visit in tables.visits.values() {
+        check_visit(tables, visit, &mut report);
+    }
+    for (location, ids) in &tables.location_visits {
+        check_index_entry("location_visits", location, ids, location_of, tables, &mut report);
+    }
+    for (user, ids) in &tables.user_visits {
+        check_index_entry("user_visits", user, ids, user_of, tables, &mut report);
+        check_timeline(user, ids, tables, &mut report);
+    }
+    let mut locations: Vec<u32> = tables.location_visits.keys().collect();
+    locations.extend(tables.location_marks.keys());
+    locations.sort();
+    locations.dedup();
+    for location in locations {
+        check_marks(location, tables, &mut report);
+    }
+    report
+}
+
+/// Like `check`, but only looks at what's in `scope`, after the mutation it
+/// was taken for is applied. Its user and location may have moved since, so
+/// they're looked up again.
+pub fn check_scope(tables: &Tables, mut scope: Scope) -> IntegrityReport {
+    scope.widen(tables);
+    let mut report = IntegrityReport::default();
+    for visit in scope.visits.iter().filter_map(|id| tables.visits.get(id)) {
+        check_visit(tables, visit, &mut report);
+    }
+    for &location in &scope.locations {
+        if let Some(ids) = tables.location_visits.get(&location) {
+            check_index_entry("location_visits", location, ids, location_of, tables, &mut report);
+        }
+        check_marks(location, tables, &mut report);
+    }
+    for &user in &scope.users {
+        if let Some(ids) = tables.user_visits.get(&user) {
+            check_index_entry("user_visits", user, ids, user_of, tables, &mut report);
+            check_timeline(user, ids, tables, &mut report);
+        }
+    }
+    report
+}
+
+fn check_visit(tables: &Tables, visit: &Visit, report: &mut IntegrityReport) {
+    if !tables.users.contains_key(&visit.user) {
+        report.dangling.push(DanglingReference {
+            visit: visit.id,
+            field: "user",
+            id: visit.user,
+        });
+    }
+    if !tables.locations.contains_key(&visit.location) {
+        report.dangling.push(DanglingReference {
+            visit: visit.id,
+            field: "location",
+            id: visit.location,
+        });
+    }
+
+    let in_location_index = tables
+        .location_visits
+        .get(&visit.location)
+        .map_or(false, |ids| ids.contains(&visit.id));
+    if !in_location_index {
+        report.index_errors.push(format!(
+            "visit {} is missing from location_visits[{}]",
+            visit.id,
+            visit.location
+        ));
+    }
+    let in_user_index = tables
+        .user_visits
+        .get(&visit.user)
+        .map_or(false, |ids| ids.contains(&visit.id));
+    if !in_user_index {
+        report.index_errors.push(format!(
+            "visit {} is missing from user_visits[{}]",
+            visit.id,
+            visit.user
+        ));
+    }
+}
+
+fn user_of(visit: &Visit) -> u32 {
+    visit.user
+}
+
+fn location_of(visit: &Visit) -> u32 {
+    visit.location
+}
+
+// Checks that the visits `index[key]` lists are there and that `key_of` them
+// is `key`.
+fn check_index_entry(
+    index: &str,
+    key: u32,
+    ids: &[u32],
+    key_of: fn(&Visit) -> u32,
+    tables: &Tables,
+    report: &mut IntegrityReport,
+) {
+    for id in ids {
+        if tables.visits.get(id).map_or(true, |visit| key_of(visit) != key) {
+            report.index_errors.push(format!(
+                "{}[{}] lists visit {} which isn't there",
+                index,
+                key,
+                id
+            ));
+        }
+    }
+}
+
+// Checks that `user_visits[user]` is in timeline order.
+fn check_timeline(user: u32, ids: &[u32], tables: &Tables, report: &mut IntegrityReport) {
+    let key_of = |id| visits::timeline_key(&tables.visits, id);
+    for pair in ids.windows(2) {
+        let (earlier, later) = (pair[0], pair[1]);
+        if key_of(earlier) > key_of(later) {
+            report.index_errors.push(format!(
+                "user_visits[{}] lists visit {} before visit {}",
+                user,
+                earlier,
+                later
+            ));
+        }
+    }
+}
+
+// Checks that `location_marks[location]` matches a recount of the visits
+// `location_visits[location]` lists. A missing entry stands for no marks.
+fn check_marks(location: u32, tables: &Tables, report: &mut IntegrityReport) {
+    let expected = marks::recount(tables, location);
+    let matches = match tables.location_marks.get(&location) {
+        Some(found) => *found == expected,
+        None => expected == LocationMarks::default(),
+    };
+    if !matches {
+        report.index_errors.push(format!(
+            "location_marks[{}] doesn't add up to the visits in location_visits[{}]",
+            location,
+            location
+        ));
+    }
+}
+
+/// Scans `tables` as `policy` asks, printing what was found. An error means
+/// the policy refuses the dataset.
+pub fn enforce(policy: IntegrityPolicy, tables: &Tables) -> Result<(), String> {
+    if policy == IntegrityPolicy::Off {
+        return Ok(());
+    }
+    let report = check(tables);
+    print_report(&report);
+    if policy != IntegrityPolicy::Report && !report.is_consistent() {
+        Err("the indexes don't agree with the visits".to_owned())
+    } else if policy == IntegrityPolicy::Strict && !report.is_clean() {
+        Err("visits reference missing users or locations".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn print_report(report: &IntegrityReport) {
+    println!(
+        "Integrity check: {} dangling references, {} index errors",
+        report.dangling.len(),
+        report.index_errors.len()
+    );
+    for dangling in report.dangling.iter().take(10) {
+        println!(
+            "  visit {} references missing {} {}",
+            dangling.visit,
+            dangling.field,
+            dangling.id
+        );
+    }
+    for error in report.index_errors.iter().take(10) {
+        println!("  {}", error);
+    }
+}
@@ -0,0 +1,968 @@
+use Options;
+use Storage;
+use Tables;
+use locations::Location;
+use users::User;
+use validation::{self, Validate};
+use visits::Visit;
+
+use flate2::read::GzDecoder;
+use libc;
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess};
+use serde_json::{self, Value};
+use tar;
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadPolicy {
+    /// Stop at the first bad file or record. A record is bad if it breaks the
+    /// rules a request body is held to, or is a visit of a missing user or
+    /// location.
+    Strict,
+    /// Skip bad files and records, and report them once loading is done.
+    /// Records read before a syntax error in a file are kept.
+    Lenient,
+}
+
+impl LoadPolicy {
+    pub fn parse(value: &str) -> Option<LoadPolicy> {
+        match value {
+            "strict" => Some(LoadPolicy::Strict),
+            "lenient" => Some(LoadPolicy::Lenient),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntityKind {
+    Users,
+    Locations,
+    Visits,
+}
+
+const KINDS: [EntityKind; 3] = [EntityKind::Users, EntityKind::Locations, EntityKind::Visits];
+
+impl EntityKind {
+    /// The key of the records' array in a data file, and its name prefix.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EntityKind::Users => "users",
+            EntityKind::Locations => "locations",
+            EntityKind::Visits => "visits",
+        }
+    }
+
+    fn file_name(&self, index: usize) -> String {
+        format!("{}_{}.json", self.name(), index)
+    }
+}
+
+#[derive(Debug)]
+pub enum Cause {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Zip(ZipError),
+    /// Readable, but not what the file should hold.
+    Format(String),
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cause::Io(ref e) => fmt::Display::fmt(e, f),
+            Cause::Json(ref e) => fmt::Display::fmt(e, f),
+            Cause::Zip(ref e) => fmt::Display::fmt(e, f),
+            Cause::Format(ref message) => f.write_str(message),
+        }
+    }
+}
+
+/// Why a file or a record in it couldn't be loaded.
+#[derive(Debug)]
+pub struct LoadError {
+    /// As named in the data directory or archive.
+    pub file: String,
+    pub kind: Option<EntityKind>,
+    /// Position of the record in the file's array.
+    pub index: Option<usize>,
+    /// Where in the file parsing stopped, if it got that far.
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub cause: Cause,
+}
+
+impl LoadError {
+    pub fn file(file: &str, cause: Cause) -> LoadError {
+        LoadError {
+            file: file.to_owned(),
+            kind: None,
+            index: None,
+            line: None,
+            column: None,
+            cause: cause,
+        }
+    }
+
+    pub fn at_line(file: &str, line: usize, message: String) -> LoadError {
+        LoadError {
+            line: Some(line),
+            ..LoadError::file(file, Cause::Format(message))
+        }
+    }
+
+    fn record(file: &str, kind: EntityKind, index: Option<usize>, message: String) -> LoadError {
+        LoadError {
+            kind: Some(kind),
+            index: index,
+            ..LoadError::file(file, Cause::Format(message))
+        }
+    }
+
+    fn json(file: &str, kind: EntityKind, index: Option<usize>, e: serde_json::Error) -> LoadError {
+        // Syntax errors have a position; errors in a parsed record don't.
+        let (line, column) = if e.line() == 0 {
+            (None, None)
+        } else {
+            (Some(e.line()), Some(e.column()))
+        };
+        LoadError {
+            file: file.to_owned(),
+            kind: Some(kind),
+            index: index,
+            line: line,
+            column: column,
+            cause: Cause::Json(e),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let (Some(kind), Some(index)) = (self.kind, self.index) {
+            write!(f, ", {}[{}]", kind.name(), index)?;
+        }
+        if let (Some(line), None) = (self.line, self.column) {
+            write!(f, ", line {}", line)?;
+        }
+        write!(f, ": {}", self.cause)
+    }
+}
+
+impl Error for LoadError {
+    fn description(&self) -> &str {
+        "failed to load data"
+    }
+}
+
+/// What was loaded, and what was skipped in lenient mode.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub files: usize,
+    pub users: usize,
+    pub locations: usize,
+    pub visits: usize,
+    pub skipped_files: usize,
+    pub skipped_records: usize,
+    pub errors: Vec<LoadError>,
+    /// Data files missing between ones that were there, like `visits_5.json`
+    /// next to `visits_4.json` and `visits_6.json`.
+    pub missing_files: Vec<String>,
+}
+
+impl LoadReport {
+    pub fn print(&self) {
+        println!(
+            "Loaded {} users, {} locations and {} visits from {} files",
+            self.users,
+            self.locations,
+            self.visits,
+            self.files
+        );
+        if !self.missing_files.is_empty() {
+            println!("Missing data files: {}", self.missing_files.join(", "));
+        }
+        if self.errors.is_empty() {
+            return;
+        }
+        println!(
+            "Skipped {} files and {} records:",
+            self.skipped_files,
+            self.skipped_records
+        );
+        for error in self.errors.iter().take(10) {
+            println!("  {}", error);
+        }
+        if self.errors.len() > 10 {
+            println!("  and {} more", self.errors.len() - 10);
+        }
+    }
+}
+
+/// How far a load has got, for watching it from another thread.
+#[derive(Debug, Default)]
+pub struct LoadProgress {
+    /// Data files found.
+    pub files: AtomicUsize,
+    /// Data files parsed and merged so far.
+    pub merged: AtomicUsize,
+}
+
+/// Threads to parse data files on by default: one per CPU.
+pub fn default_threads() -> usize {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus > 0 {
+        cpus as usize
+    } else {
+        1
+    }
+}
+
+fn millis(elapsed: Duration) -> u64 {
+    elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000
+}
+
+// The records of one data file, parsed on a loading thread and merged into
+// the tables in file order.
+#[derive(Default)]
+struct Batch {
+    users: Vec<User>,
+    locations: Vec<Location>,
+    visits: Vec<Visit>,
+    skipped_file: bool,
+    skipped_records: usize,
+    errors: Vec<LoadError>,
+    /// The strict mode error that stopped the file being parsed.
+    stopped: Option<LoadError>,
+}
+
+impl Batch {
+    fn add_record(
+        &mut self,
+        policy: LoadPolicy,
+        now: i32,
+        file: &str,
+        kind: EntityKind,
+        index: usize,
+        record: Value,
+    ) -> Result<(), LoadError> {
+        let result = match kind {
+            EntityKind::Users => {
+                parse_record(file, kind, index, record, now).map(|user| self.users.push(user))
+            }
+            EntityKind::Locations => parse_record(file, kind, index, record, now)
+                .map(|location| self.locations.push(location)),
+            EntityKind::Visits => {
+                parse_record(file, kind, index, record, now).map(|visit| self.visits.push(visit))
+            }
+        };
+        let error = match result {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        self.skipped_records += 1;
+        match policy {
+            LoadPolicy::Strict => Err(error),
+            LoadPolicy::Lenient => {
+                self.errors.push(error);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Holds a loaded record to the same rules as a request body, timestamps up to
+// `now` included, and deserializes it.
+fn parse_record<T: Validate>(
+    file: &str,
+    kind: EntityKind,
+    index: usize,
+    record: Value,
+    now: i32,
+) -> Result<T, LoadError> {
+    if let Some(object) = record.as_object() {
+        let violations = validation::violations::<T>(object, now);
+        if !violations.is_empty() {
+            let message: Vec<String> = violations
+                .iter()
+                .map(|violation| format!("{} {}", violation.field, violation.message))
+                .collect();
+            return Err(LoadError::record(file, kind, Some(index), message.join(", ")));
+        }
+    }
+    serde_json::from_value(record).map_err(|e| LoadError::json(file, kind, Some(index), e))
+}
+
+// One data file, `{"<kind>": [...]}`, parsed record by record.
+struct DataFile<'a> {
+    batch: &'a mut Batch,
+    policy: LoadPolicy,
+    now: i32,
+    file: &'a str,
+    kind: EntityKind,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for DataFile<'a> {
+    /// Whether the file had the array.
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a> de::Visitor<'de> for DataFile<'a> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an object with a {:?} array", self.kind.name())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<bool, A::Error> {
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key != self.kind.name() {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            map.next_value_seed(Records {
+                batch: &mut *self.batch,
+                policy: self.policy,
+                now: self.now,
+                file: self.file,
+                kind: self.kind,
+            })?;
+            found = true;
+        }
+        Ok(found)
+    }
+}
+
+// The array of a data file. Only one record is held as a `Value` at a time.
+struct Records<'a> {
+    batch: &'a mut Batch,
+    policy: LoadPolicy,
+    now: i32,
+    file: &'a str,
+    kind: EntityKind,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for Records<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, 'a> de::Visitor<'de> for Records<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of {}", self.kind.name())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut index = 0;
+        // A record is parsed as a `Value` first, so one of the wrong shape
+        // can be skipped without losing the position in the file.
+        while let Some(record) = seq.next_element::<Value>()? {
+            let added = self.batch
+                .add_record(self.policy, self.now, self.file, self.kind, index, record);
+            if let Err(error) = added {
+                self.batch.stopped = Some(error);
+                return Err(de::Error::custom("stopped at a bad record"));
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+fn parse_file<R: Read>(
+    file: &str,
+    kind: EntityKind,
+    policy: LoadPolicy,
+    now: i32,
+    reader: R,
+) -> Result<Batch, LoadError> {
+    let mut batch = Batch::default();
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let parsed = DataFile {
+        batch: &mut batch,
+        policy: policy,
+        now: now,
+        file: file,
+        kind: kind,
+    }.deserialize(&mut deserializer);
+    let parsed = parsed.and_then(|found| deserializer.end().map(|_| found));
+    let error = match parsed {
+        Ok(true) => return Ok(batch),
+        Ok(false) => LoadError {
+            kind: Some(kind),
+            ..LoadError::file(
+                file,
+                Cause::Format(format!("expected an object with a {:?} array", kind.name())),
+            )
+        },
+        Err(e) => match batch.stopped.take() {
+            Some(error) => return Err(error),
+            None => LoadError::json(file, kind, None, e),
+        },
+    };
+    match policy {
+        LoadPolicy::Strict => Err(error),
+        LoadPolicy::Lenient => {
+            batch.skipped_file = true;
+            batch.errors.push(error);
+            Ok(batch)
+        }
+    }
+}
+
+/// Where the data files are read from.
+pub trait DataSource: Send {
+    /// The names of the files in the source, in no particular order.
+    fn names(&mut self) -> Result<Vec<String>, LoadError>;
+
+    /// Opens the file `name`, one of `names`.
+    fn open<'a>(&'a mut self, name: &str) -> Result<Box<Read + 'a>, LoadError>;
+
+    /// Another handle on the same files, for another loading thread.
+    fn split(&self) -> Result<Box<DataSource>, LoadError>;
+}
+
+/// The files straight in a directory. One that doesn't exist has none.
+pub struct DirSource {
+    dir: PathBuf,
+}
+
+impl DirSource {
+    pub fn new(dir: &Path) -> DirSource {
+        DirSource { dir: dir.to_owned() }
+    }
+}
+
+impl DataSource for DirSource {
+    fn names(&mut self) -> Result<Vec<String>, LoadError> {
+        let dir = self.dir.to_string_lossy().into_owned();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(LoadError::file(&dir, Cause::Io(e))),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| LoadError::file(&dir, Cause::Io(e)))?;
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    fn open<'a>(&'a mut self, name: &str) -> Result<Box<Read + 'a>, LoadError> {
+        let file =
+            File::open(self.dir.join(name)).map_err(|e| LoadError::file(name, Cause::Io(e)))?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+
+    fn split(&self) -> Result<Box<DataSource>, LoadError> {
+        Ok(Box::new(DirSource::new(&self.dir)))
+    }
+}
+
+/// The files in a zip archive.
+pub struct ZipSource {
+    path: PathBuf,
+    zip: ZipArchive<BufReader<File>>,
+}
+
+impl ZipSource {
+    pub fn open(path: &Path) -> Result<ZipSource, LoadError> {
+        let archive = path.to_string_lossy().into_owned();
+        let file = File::open(path).map_err(|e| LoadError::file(&archive, Cause::Io(e)))?;
+        let zip = ZipArchive::new(BufReader::new(file))
+            .map_err(|e| LoadError::file(&archive, Cause::Zip(e)))?;
+        Ok(ZipSource {
+            path: path.to_owned(),
+            zip: zip,
+        })
+    }
+}
+
+impl DataSource for ZipSource {
+    fn names(&mut self) -> Result<Vec<String>, LoadError> {
+        let archive = self.path.to_string_lossy().into_owned();
+        let mut names = Vec::new();
+        for index in 0..self.zip.len() {
+            let entry = self.zip
+                .by_index(index)
+                .map_err(|e| LoadError::file(&archive, Cause::Zip(e)))?;
+            names.push(entry.name().to_owned());
+        }
+        Ok(names)
+    }
+
+    fn open<'a>(&'a mut self, name: &str) -> Result<Box<Read + 'a>, LoadError> {
+        let entry = self.zip
+            .by_name(name)
+            .map_err(|e| LoadError::file(name, Cause::Zip(e)))?;
+        Ok(Box::new(BufReader::new(entry)))
+    }
+
+    fn split(&self) -> Result<Box<DataSource>, LoadError> {
+        Ok(Box::new(ZipSource::open(&self.path)?))
+    }
+}
+
+/// The data files in a tar stream: a `.tar` file, a gzipped `.tar.gz` or
+/// `.tgz` one, or standard input. A stream can only be read in order, so the
+/// data files are read into memory up front and parsed from there.
+pub struct TarSource {
+    files: Arc<HashMap<String, Vec<u8>>>,
+}
+
+impl TarSource {
+    pub fn open(path: &Path) -> Result<TarSource, LoadError> {
+        let archive = path.to_string_lossy().into_owned();
+        let file = File::open(path).map_err(|e| LoadError::file(&archive, Cause::Io(e)))?;
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        if name.ends_with(".gz") || name.ends_with(".tgz") {
+            let gz = GzDecoder::new(BufReader::new(file))
+                .map_err(|e| LoadError::file(&archive, Cause::Io(e)))?;
+            TarSource::read(&archive, gz)
+        } else {
+            TarSource::read(&archive, BufReader::new(file))
+        }
+    }
+
+    pub fn stdin() -> Result<TarSource, LoadError> {
+        TarSource::read("standard input", io::stdin())
+    }
+
+    // Keeps the entries that look like data files, by their paths in the
+    // archive.
+    fn read<R: Read>(archive: &str, reader: R) -> Result<TarSource, LoadError> {
+        let error = |e| LoadError::file(archive, Cause::Io(e));
+        let mut files = HashMap::new();
+        let mut tar = tar::Archive::new(reader);
+        for entry in tar.entries().map_err(&error)? {
+            let mut entry = entry.map_err(&error)?;
+            let name = entry.path().map_err(&error)?.to_string_lossy().into_owned();
+            if data_file_kind(&name).is_none() {
+                continue;
+            }
+            let mut data = Vec::new();
+            entry
+                .read_to_end(&mut data)
+                .map_err(|e| LoadError::file(&name, Cause::Io(e)))?;
+            files.insert(name, data);
+        }
+        Ok(TarSource {
+            files: Arc::new(files),
+        })
+    }
+}
+
+impl DataSource for TarSource {
+    fn names(&mut self) -> Result<Vec<String>, LoadError> {
+        Ok(self.files.keys().cloned().collect())
+    }
+
+    fn open<'a>(&'a mut self, name: &str) -> Result<Box<Read + 'a>, LoadError> {
+        match self.files.get(name) {
+            Some(data) => Ok(Box::new(&data[..])),
+            None => Err(LoadError::file(
+                name,
+                Cause::Io(io::Error::new(ErrorKind::NotFound, "not in the archive")),
+            )),
+        }
+    }
+
+    fn split(&self) -> Result<Box<DataSource>, LoadError> {
+        Ok(Box::new(TarSource {
+            files: self.files.clone(),
+        }))
+    }
+}
+
+/// The source for a data directory, or a zip or tar archive, told apart by
+/// the name.
+pub fn open_source(path: &Path) -> Result<Box<DataSource>, LoadError> {
+    let name = path.to_string_lossy().into_owned();
+    if path.is_dir() {
+        Ok(Box::new(DirSource::new(path)))
+    } else if name.ends_with(".zip") {
+        Ok(Box::new(ZipSource::open(path)?))
+    } else if name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Ok(Box::new(TarSource::open(path)?))
+    } else {
+        let message = "expected a directory, or a .zip, .tar, .tar.gz or .tgz archive";
+        Err(LoadError::file(&name, Cause::Format(message.to_owned())))
+    }
+}
+
+// `users_3.json` is the third file of users; `users_03.json` isn't a data
+// file. Files in subdirectories count by their own name.
+fn data_file_kind(name: &str) -> Option<(EntityKind, usize)> {
+    let stem = match Path::new(name).file_name().and_then(|name| name.to_str()) {
+        Some(file_name) if file_name.ends_with(".json") => {
+            &file_name[..file_name.len() - ".json".len()]
+        }
+        _ => return None,
+    };
+    for &kind in KINDS.iter() {
+        let prefix = format!("{}_", kind.name());
+        if stem.starts_with(&prefix) {
+            let number = &stem[prefix.len()..];
+            if number.starts_with('0') || !number.bytes().all(|b| b >= b'0' && b <= b'9') {
+                return None;
+            }
+            return number.parse().ok().map(|index| (kind, index));
+        }
+    }
+    None
+}
+
+// The data files among `names` in the order they're merged: users, locations
+// and visits, each by number. Also returns the names missing between them.
+// Two files with the same number, e.g. in different directories of an
+// archive, are an error.
+fn find_data_files(
+    names: Vec<String>,
+) -> Result<(Vec<(EntityKind, String)>, Vec<String>), LoadError> {
+    let mut found: Vec<_> = names
+        .into_iter()
+        .filter_map(|name| data_file_kind(&name).map(|(kind, index)| (kind, index, name)))
+        .collect();
+    found.sort_by(|a, b| (a.0 as usize, a.1, &a.2).cmp(&(b.0 as usize, b.1, &b.2)));
+    for pair in found.windows(2) {
+        if (pair[0].0, pair[0].1) == (pair[1].0, pair[1].1) {
+            let message = format!("{} is there too", pair[0].2);
+            return Err(LoadError::file(&pair[1].2, Cause::Format(message)));
+        }
+    }
+
+    let mut missing = Vec::new();
+    for &kind in KINDS.iter() {
+        let mut expected = 1;
+        for &(_, index, _) in found.iter().filter(|&&(found_kind, _, _)| found_kind == kind) {
+            for gap in expected..index {
+                missing.push(kind.file_name(gap));
+            }
+            expected = index + 1;
+        }
+    }
+    let files = found.into_iter().map(|(kind, _, name)| (kind, name)).collect();
+    Ok((files, missing))
+}
+
+// How far ahead of the merge the parsing threads may get. Batches parsed out
+// of order wait in memory until the files before them are merged, so this
+// bounds how many of them there can be.
+struct MergeWindow {
+    size: usize,
+    /// Files merged so far.
+    merged: Mutex<usize>,
+    changed: Condvar,
+    /// The first file that failed; the files after it needn't be parsed.
+    failed: AtomicUsize,
+}
+
+impl MergeWindow {
+    fn new(size: usize) -> MergeWindow {
+        MergeWindow {
+            size: size,
+            merged: Mutex::new(0),
+            changed: Condvar::new(),
+            failed: AtomicUsize::new(usize::max_value()),
+        }
+    }
+
+    // Waits until file `index` is inside the window. False if a file before
+    // it failed in the meantime. The files before a failed one are still
+    // parsed, so the merge gets as far as the failure. The file right after
+    // the merged ones is always inside, so whoever took it never waits.
+    fn enter(&self, index: usize) -> bool {
+        let mut merged = self.merged.lock().unwrap();
+        while index >= *merged + self.size && index < self.failed.load(Ordering::SeqCst) {
+            merged = self.changed.wait(merged).unwrap();
+        }
+        index < self.failed.load(Ordering::SeqCst)
+    }
+
+    fn advance(&self, merged: usize) {
+        *self.merged.lock().unwrap() = merged;
+        self.changed.notify_all();
+    }
+
+    fn fail(&self, index: usize) {
+        let _merged = self.merged.lock().unwrap();
+        if index < self.failed.load(Ordering::SeqCst) {
+            self.failed.store(index, Ordering::SeqCst);
+        }
+        self.changed.notify_all();
+    }
+}
+
+// Parses the files in `jobs` that no other thread has taken, until they run
+// out or one fails in strict mode.
+fn parse_jobs(
+    mut source: Box<DataSource>,
+    jobs: Arc<Vec<(EntityKind, String)>>,
+    next: Arc<AtomicUsize>,
+    window: Arc<MergeWindow>,
+    policy: LoadPolicy,
+    now: i32,
+    results: mpsc::SyncSender<(usize, Result<Batch, LoadError>)>,
+) {
+    loop {
+        let index = next.fetch_add(1, Ordering::SeqCst);
+        if index >= jobs.len() || !window.enter(index) {
+            break;
+        }
+        let (kind, ref name) = jobs[index];
+        let result = match source.open(name) {
+            Ok(reader) => parse_file(name, kind, policy, now, reader),
+            // A file that can't be opened is skipped like one that can't be
+            // parsed.
+            Err(error) => match policy {
+                LoadPolicy::Strict => Err(error),
+                LoadPolicy::Lenient => Ok(Batch {
+                    skipped_file: true,
+                    errors: vec![LoadError { kind: Some(kind), ..error }],
+                    ..Batch::default()
+                }),
+            },
+        };
+        if result.is_err() {
+            window.fail(index);
+        }
+        if results.send((index, result)).is_err() {
+            break;
+        }
+    }
+}
+
+struct Loader {
+    policy: LoadPolicy,
+    report: LoadReport,
+    tables: Tables,
+}
+
+impl Loader {
+    // Users and locations are all merged before the first visits, so a
+    // visit's references can be checked as it comes. Ids seen in an earlier
+    // record, in this file or another, are duplicates.
+    fn merge(&mut self, file: &str, batch: Batch) -> Result<(), LoadError> {
+        self.report.files += 1;
+        if batch.skipped_file {
+            self.report.skipped_files += 1;
+        }
+        self.report.skipped_records += batch.skipped_records;
+        self.report.errors.extend(batch.errors);
+        for user in batch.users {
+            if self.tables.users.contains_key(&user.id) {
+                self.skip(file, EntityKind::Users, format!("user {} is a duplicate", user.id))?;
+                continue;
+            }
+            self.tables.users.insert(user.id, user);
+        }
+        for mut location in batch.locations {
+            if self.tables.locations.contains_key(&location.id) {
+                let message = format!("location {} is a duplicate", location.id);
+                self.skip(file, EntityKind::Locations, message)?;
+                continue;
+            }
+            location.intern();
+            self.tables.locations.insert(location.id, location);
+        }
+        for visit in batch.visits {
+            let missing = if !self.tables.users.contains_key(&visit.user) {
+                Some(("user", visit.user))
+            } else if !self.tables.locations.contains_key(&visit.location) {
+                Some(("location", visit.location))
+            } else {
+                None
+            };
+            let message = match missing {
+                Some((field, id)) => format!("visit {}: {} {} does not exist", visit.id, field, id),
+                None if self.tables.visits.contains_key(&visit.id) => {
+                    format!("visit {} is a duplicate", visit.id)
+                }
+                None => {
+                    add_visit(&mut self.tables, visit);
+                    continue;
+                }
+            };
+            self.skip(file, EntityKind::Visits, message)?;
+        }
+        Ok(())
+    }
+
+    // Skips a record that can't be merged: fails a strict load, and is
+    // reported by a lenient one.
+    fn skip(&mut self, file: &str, kind: EntityKind, message: String) -> Result<(), LoadError> {
+        let error = LoadError::record(file, kind, None, message);
+        self.report.skipped_records += 1;
+        match self.policy {
+            LoadPolicy::Strict => Err(error),
+            LoadPolicy::Lenient => {
+                self.report.errors.push(error);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self, options: &Options) -> (Storage, LoadReport) {
+        let mut report = self.report;
+        let mut tables = self.tables;
+        report.users = tables.users.len();
+        report.locations = tables.locations.len();
+        report.visits = tables.visits.len();
+        tables.rebuild_indexes(options.now());
+        (Storage::new(tables), report)
+    }
+}
+
+fn add_visit(tables: &mut Tables, visit: Visit) {
+    tables
+        .location_visits
+        .entry(visit.location)
+        .or_insert_with(Vec::new)
+        .push(visit.id);
+    tables
+        .user_visits
+        .entry(visit.user)
+        .or_insert_with(Vec::new)
+        .push(visit.id);
+    tables.visits.insert(visit.id, visit);
+}
+
+/// Loads the data files in `source` on `threads` threads. They're merged in
+/// order, users, locations and visits, each by number, whichever finishes
+/// first, so the result doesn't depend on timing. Parsing runs at most two
+/// files per thread ahead of the merge. Numbers missing in between fail a
+/// strict load; a lenient one reports them and loads the files around them.
+pub fn load(
+    source: Box<DataSource>,
+    options: &Options,
+    policy: LoadPolicy,
+    threads: usize,
+) -> Result<(Storage, LoadReport), LoadError> {
+    load_with_progress(source, options, policy, threads, &LoadProgress::default())
+}
+
+/// Like `load`, counting the files in `progress` as it goes.
+pub fn load_with_progress(
+    mut source: Box<DataSource>,
+    options: &Options,
+    policy: LoadPolicy,
+    threads: usize,
+    progress: &LoadProgress,
+) -> Result<(Storage, LoadReport), LoadError> {
+    let start = Instant::now();
+    let (jobs, missing) = find_data_files(source.names()?)?;
+    if policy == LoadPolicy::Strict && !missing.is_empty() {
+        let message = "is missing, but later files of the same kind are there".to_owned();
+        return Err(LoadError::file(&missing[0], Cause::Format(message)));
+    }
+    progress.files.store(jobs.len(), Ordering::SeqCst);
+    let threads = cmp::max(1, cmp::min(threads, jobs.len()));
+    println!("Loading {} data files on {} threads", jobs.len(), threads);
+
+    let mut sources = Vec::new();
+    for _ in 1..threads {
+        sources.push(source.split()?);
+    }
+    sources.push(source);
+
+    let count = jobs.len();
+    let jobs = Arc::new(jobs);
+    let next = Arc::new(AtomicUsize::new(0));
+    let window = Arc::new(MergeWindow::new(2 * threads));
+    let (sender, receiver) = mpsc::sync_channel(threads);
+    let now = options.now();
+    for source in sources {
+        let jobs = jobs.clone();
+        let next = next.clone();
+        let window = window.clone();
+        let sender = sender.clone();
+        thread::spawn(move || parse_jobs(source, jobs, next, window, policy, now, sender));
+    }
+    drop(sender);
+
+    // Batches that came in ahead of an earlier file wait here; the window
+    // keeps all but a few slots empty.
+    let mut pending: Vec<Option<Result<Batch, LoadError>>> = (0..count).map(|_| None).collect();
+    let mut merged = 0;
+    let mut loader = Loader {
+        policy: policy,
+        report: LoadReport::default(),
+        tables: Tables::default(),
+    };
+    loader.report.missing_files = missing;
+    for (index, result) in receiver {
+        pending[index] = Some(result);
+        while merged < count {
+            let merging = match pending[merged].take() {
+                Some(Ok(batch)) => loader.merge(&jobs[merged].1, batch),
+                Some(Err(e)) => Err(e),
+                None => break,
+            };
+            if let Err(e) = merging {
+                window.fail(merged);
+                return Err(e);
+            }
+            println!("Read data file: {:?}", jobs[merged].1);
+            merged += 1;
+            progress.merged.store(merged, Ordering::SeqCst);
+            window.advance(merged);
+        }
+    }
+    if merged < count {
+        // A parsing thread gave up, or died, before it sent the file. Any
+        // error a later file ran into is the likely reason.
+        let error = pending
+            .into_iter()
+            .filter_map(|result| match result {
+                Some(Err(e)) => Some(e),
+                _ => None,
+            })
+            .next();
+        let never_parsed = Cause::Format("was never parsed".to_owned());
+        return Err(error.unwrap_or_else(|| LoadError::file(&jobs[merged].1, never_parsed)));
+    }
+    println!("Parsed {} data files in {} ms", merged, millis(start.elapsed()));
+
+    let indexing = Instant::now();
+    let loaded = loader.finish(options);
+    println!("Built indexes in {} ms", millis(indexing.elapsed()));
+    Ok(loaded)
+}
+
+/// Loads the data files in `dir` on `threads` threads.
+pub fn load_dir(
+    dir: &Path,
+    options: &Options,
+    policy: LoadPolicy,
+    threads: usize,
+) -> Result<(Storage, LoadReport), LoadError> {
+    load(Box::new(DirSource::new(dir)), options, policy, threads)
+}
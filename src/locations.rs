@@ -2,29 +2,87 @@ use gender::Gender;
 use Options;
 use QueryId;
 use Storage;
-use util::NewOrUpdateResponse;
+use Tables;
+use error::ApiError;
+use idmap::{Entry, HeapSize};
+use marks::MarkQuery;
+use text::Symbol;
+use util::{self, DeleteParams, DependentsPolicy, NewOrUpdateResponse, PageRequest, Patch,
+           SortKey};
+use validation::{Checker, Validate, Validated};
+use visits;
+use wal::{Mutation, Outcome, Wal};
 
 use rocket::State;
-use rocket::http::Status;
-use rocket::response::Failure;
+use rocket::response::status::Created;
 use rocket_contrib::Json;
 
-use std::collections::hash_map::Entry;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Location {
+    /// 0 in a create request that leaves the id to the server.
+    #[serde(default)]
     pub id: u32,
-    pub place: String,
-    pub country: String, // [char; 50]
-    pub city: String,    // [char; 50]
+    /// Rarely repeats, so it isn't interned, but listing a user's visits
+    /// clones it.
+    pub place: Symbol,
+    pub country: Symbol, // [char; 50]
+    pub city: Symbol,    // [char; 50]
     pub distance: u32,
 }
 
+impl Location {
+    /// Shares `country` and `city` through the symbol table, once the
+    /// location is stored.
+    pub fn intern(&mut self) {
+        self.country = Symbol::intern(&self.country);
+        self.city = Symbol::intern(&self.city);
+    }
+}
+
+// Country and city are counted once, in the symbol table.
+impl HeapSize for Location {
+    fn heap_size(&self) -> usize {
+        self.place.len()
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct LocationUpdate {
-    #[serde(default)] place: String,
-    #[serde(default)] country: String, // [char; 50]
-    #[serde(default)] city: String,    // [char; 50]
-    #[serde(default)] distance: u32,
+pub struct LocationUpdate {
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    place: Patch<Symbol>,
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    country: Patch<Symbol>, // [char; 50]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    city: Patch<Symbol>, // [char; 50]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    distance: Patch<u32>,
+}
+
+fn check_location_fields(checker: &mut Checker) {
+    checker.string("place", usize::max_value());
+    checker.string("country", 50);
+    checker.string("city", 50);
+    checker.integer("distance", 0, u32::max_value() as i64);
+}
+
+impl Validate for Location {
+    fn validate(checker: &mut Checker) {
+        checker.optional_id("id");
+        check_location_fields(checker);
+    }
+}
+
+impl Validate for LocationUpdate {
+    fn is_partial() -> bool {
+        true
+    }
+
+    fn validate(checker: &mut Checker) {
+        check_location_fields(checker);
+    }
 }
 
 #[derive(FromForm, Debug)]
@@ -41,18 +99,112 @@ struct LocationAvg {
     avg: f64,
 }
 
+#[derive(FromForm, Default)]
+struct LocationsListParams {
+    limit: Option<usize>,
+    offset: Option<usize>,
+    after: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    country: Option<String>,
+    city: Option<String>,
+    #[form(field = "fromDistance")] from_distance: Option<u32>,
+    #[form(field = "toDistance")] to_distance: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct LocationsList {
+    locations: Vec<Location>,
+    total: usize,
+    next: Option<String>,
+}
+
+const LOCATION_SORT_FIELDS: &[&str] = &["id", "place", "country", "city", "distance"];
+
 #[get("/locations/<id>")]
-fn locations(id: u32, storage: State<Storage>) -> Option<Json<Location>> {
-    let locations = &*storage.locations.read().unwrap();
-    locations.get(&id).map(|entity| Json(entity.clone()))
+fn locations(id: u32, storage: State<Arc<Storage>>) -> Result<Json<Location>, ApiError> {
+    storage
+        .read()
+        .locations
+        .get(&id)
+        .map(|entity| Json(entity.clone()))
+        .ok_or_else(|| ApiError::not_found("location", id))
+}
+
+#[get("/locations")]
+fn locations_list_no_params(storage: State<Arc<Storage>>) -> Result<Json<LocationsList>, ApiError> {
+    locations_list(None, storage)
+}
+
+#[get("/locations?<params>")]
+fn locations_list(
+    params: Option<LocationsListParams>,
+    storage: State<Arc<Storage>>,
+) -> Result<Json<LocationsList>, ApiError> {
+    let params = params.unwrap_or_default();
+
+    let tables = storage.read();
+    let all_locations = &tables.locations;
+    let filtered_locations: Vec<&Location> = all_locations
+        .values()
+        .filter(|l| params.country.as_ref().map_or(true, |c| *c == *l.country))
+        .filter(|l| params.city.as_ref().map_or(true, |c| *c == *l.city))
+        .filter(|l| params.from_distance.map_or(true, |d| d < l.distance))
+        .filter(|l| params.to_distance.map_or(true, |d| d > l.distance))
+        .collect();
+
+    let page_request = PageRequest {
+        limit: params.limit,
+        offset: params.offset,
+        after: params.after.as_ref().map(|s| s.as_str()),
+        sort: params.sort.as_ref().map(|s| s.as_str()),
+        order: params.order.as_ref().map(|s| s.as_str()),
+    };
+    let page = match util::paginate(
+        filtered_locations,
+        page_request,
+        LOCATION_SORT_FIELDS,
+        |field, a, b| compare_locations(field, a, b),
+        |field, l| location_sort_key(field, l),
+        |l| l.id,
+    ) {
+        Some(page) => page,
+        None => return Err(ApiError::invalid_query("invalid sort, order, limit or cursor")),
+    };
+
+    Ok(Json(LocationsList {
+        locations: page.items.into_iter().cloned().collect(),
+        total: page.total,
+        next: page.next,
+    }))
+}
+
+fn compare_locations(field: &str, a: &Location, b: &Location) -> Ordering {
+    match field {
+        "place" => a.place.cmp(&b.place),
+        "country" => a.country.cmp(&b.country),
+        "city" => a.city.cmp(&b.city),
+        "distance" => a.distance.cmp(&b.distance),
+        _ => a.id.cmp(&b.id),
+    }
+}
+
+fn location_sort_key(field: &str, location: &Location) -> SortKey {
+    match field {
+        "place" => SortKey::Str(location.place.as_str().to_owned()),
+        "country" => SortKey::Str(location.country.as_str().to_owned()),
+        "city" => SortKey::Str(location.city.as_str().to_owned()),
+        "distance" => SortKey::Int(location.distance as i64),
+        _ => SortKey::Int(location.id as i64),
+    }
 }
 
 #[get("/locations/<id>/avg")]
 fn locations_avg_no_params(
     id: u32,
-    storage: State<Storage>,
+    storage: State<Arc<Storage>>,
     options: State<Options>,
-) -> Result<Json<LocationAvg>, Failure> {
+) -> Result<Json<LocationAvg>, ApiError> {
     locations_avg(id, None, storage, options)
 }
 
@@ -60,20 +212,22 @@ fn locations_avg_no_params(
 fn locations_avg(
     id: u32,
     params: Option<LocationAvgParams>,
-    storage: State<Storage>,
+    storage: State<Arc<Storage>>,
     options: State<Options>,
-) -> Result<Json<LocationAvg>, Failure> {
-    let all_locations = &storage.locations.read().unwrap();
+) -> Result<Json<LocationAvg>, ApiError> {
+    let tables = storage.read();
     {
-        if let None = all_locations.get(&id) {
-            return Err(Failure(Status::NotFound));
+        if let None = tables.locations.get(&id) {
+            return Err(ApiError::not_found("location", id));
         }
     }
 
     if let Some(ref params) = params {
         if let Some(ref gender) = params.gender {
             match *gender {
-                Gender::Unknown => return Err(Failure(Status::BadRequest)),
+                Gender::Unknown => {
+                    return Err(ApiError::invalid_query("unknown gender").with_field("gender"))
+                }
                 _ => {}
             };
         }
@@ -81,101 +235,29 @@ fn locations_avg(
         if params.from_age.is_none() && params.from_date.is_none() && params.gender.is_none() &&
             params.to_age.is_none() && params.to_date.is_none()
         {
-            return Err(Failure(Status::BadRequest));
+            return Err(ApiError::invalid_query("no filter parameters given"));
         }
     }
 
-    let all_visits = &storage.visits.read().unwrap();
-    let location_visits_ids = &storage.location_visits.read().unwrap();
-    let maybe_this_location_visits_ids = location_visits_ids.get(&id);
-    let location_visits = if let Some(this_location_visits_ids) = maybe_this_location_visits_ids {
-        this_location_visits_ids
-            .iter()
-            .map(|i| all_visits[&i].clone())
-    } else {
-        return Ok(Json(LocationAvg { avg: 0. }));
-    };
-
-    let result_visits: Vec<_> = if let Some(params) = params {
-        let from_date_visits =
-            location_visits.filter(|v| if let Some(from_date) = params.from_date {
-                if from_date < v.visited_at {
-                    true
-                } else {
-                    false
-                }
-            } else {
-                true
-            });
-
-        let to_date_visits = from_date_visits.filter(|v| if let Some(to_date) = params.to_date {
-            if to_date > v.visited_at {
-                true
-            } else {
-                false
-            }
-        } else {
-            true
-        });
-
-        let from_age_visits = to_date_visits.filter(|v| if let Some(from_age) = params.from_age {
-            let users = &storage.users.read().unwrap();
-            let user = users.get(&v.user).unwrap();
-
-            let birth_date_timestamp = user.birth_date;
-            let age = ::users::calculate_age_from_timestamp(birth_date_timestamp, options.now);
-
-            if from_age <= age {
-                true
-            } else {
-                false
-            }
-        } else {
-            true
-        });
-
-        let to_age_visits = from_age_visits.filter(|v| if let Some(to_age) = params.to_age {
-            let users = &storage.users.read().unwrap();
-            let user = users.get(&v.user).unwrap();
-
-            let birth_date_timestamp = user.birth_date;
-            let age = ::users::calculate_age_from_timestamp(birth_date_timestamp, options.now);
-
-            if to_age > age {
-                true
-            } else {
-                false
-            }
-        } else {
-            true
-        });
-
-        let final_visits = to_age_visits.filter(|v| if let Some(ref gender) = params.gender {
-            let users = &storage.users.read().unwrap();
-            let user = users.get(&v.user).unwrap();
-            let reference_gender = &user.gender;
-
-            if gender == reference_gender {
-                true
-            } else {
-                false
-            }
-        } else {
-            true
-        });
-
-        final_visits.collect()
-    } else {
-        location_visits.collect()
+    let query = params.map_or_else(MarkQuery::default, |params| {
+        MarkQuery {
+            from_date: params.from_date,
+            to_date: params.to_date,
+            from_age: params.from_age,
+            to_age: params.to_age,
+            gender: params.gender,
+        }
+    });
+    let now = options.now();
+    let (sum, count) = match tables.location_marks.get(&id) {
+        Some(marks) => marks
+            .total(&query, now)
+            .unwrap_or_else(|| scan_marks(&tables, id, &query, now)),
+        None => (0, 0),
     };
 
-    let marks = result_visits.iter().map(|v| v.mark);
-    let mut sum: usize = 0;
-    for v in marks {
-        sum += v as usize;
-    }
     let avg_mark: f64 = if sum > 0 {
-        sum as f64 / result_visits.len() as f64
+        sum as f64 / count as f64
     } else {
         0.0
     };
@@ -187,50 +269,158 @@ fn locations_avg(
     }))
 }
 
+// Answers the queries `LocationMarks` can't by looking at every visit.
+fn scan_marks(tables: &Tables, id: u32, query: &MarkQuery, now: i32) -> (u64, usize) {
+    let all_visits = &tables.visits;
+    let visitors = &tables.visitors;
+    let location_visits = match tables.location_visits.get(&id) {
+        Some(ids) => ids.iter().filter_map(|i| all_visits.get(i)),
+        None => return (0, 0),
+    };
+
+    let from_date_visits = location_visits.filter(|v| if let Some(from_date) = query.from_date {
+        from_date < v.visited_at
+    } else {
+        true
+    });
+
+    let to_date_visits = from_date_visits.filter(|v| if let Some(to_date) = query.to_date {
+        to_date > v.visited_at
+    } else {
+        true
+    });
+
+    let has_user_filters = query.from_age.is_some() || query.to_age.is_some() ||
+        query.gender.is_some();
+    let final_visits = to_date_visits.filter(|v| {
+        if !has_user_filters {
+            return true;
+        }
+        let visitor = match visitors.get(&v.user) {
+            Some(visitor) => visitor,
+            None => return false,
+        };
+        let age = visitor.age_at(now);
+        query.from_age.map_or(true, |from_age| from_age <= age) &&
+            query.to_age.map_or(true, |to_age| to_age > age) &&
+            query.gender.as_ref().map_or(true, |gender| *gender == visitor.gender)
+    });
+
+    final_visits.fold((0, 0), |(sum, count), v| (sum + v.mark as u64, count + 1))
+}
+
 #[post("/locations/<id>?<query_id>", data = "<location>")]
 fn locations_update(
     id: u32,
-    location: Json<LocationUpdate>,
+    location: Result<Validated<LocationUpdate>, ApiError>,
     query_id: QueryId,
-    storage: State<Storage>,
-) -> Option<Json<NewOrUpdateResponse>> {
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
     let _query_id = query_id;
-    let location_update = location.0;
+    let location = location?;
+    let mutation = Mutation::LocationUpdate(id, location.0);
+
+    let outcome = wal.commit(&storage, &options, mutation)?;
+    match ApiError::from_outcome(outcome, "location", id) {
+        None => Ok(Json(NewOrUpdateResponse)),
+        Some(error) => Err(error),
+    }
+}
+
+#[post("/locations/new", data = "<location>")]
+fn locations_new(
+    location: Result<Validated<Location>, ApiError>,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Created<Json<Location>>, ApiError> {
+    let mut location = location?.0;
+    let outcome = wal.commit_with(&storage, &options, |tables| {
+        if location.id == 0 {
+            location.id = util::next_free_id(&tables.locations)?;
+        }
+        Ok(Mutation::LocationNew(location.clone()))
+    })?;
 
-    let locations = &mut storage.locations.write().unwrap();
-    let location_entry = locations.entry(id);
+    let uri = format!("/locations/{}", location.id);
+    match ApiError::from_outcome(outcome, "location", location.id) {
+        None => Ok(Created(uri, Some(Json(location)))),
+        Some(error) if outcome == Outcome::AlreadyExists => Err(error.with_existing(uri)),
+        Some(error) => Err(error),
+    }
+}
+
+#[delete("/locations/<id>")]
+fn locations_delete_no_params(
+    id: u32,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
+    locations_delete(id, None, storage, options, wal)
+}
+
+#[delete("/locations/<id>?<params>")]
+fn locations_delete(
+    id: u32,
+    params: Option<DeleteParams>,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
+    let policy = params.and_then(|p| p.dependents).unwrap_or_default();
+    let mutation = Mutation::LocationDelete(id, policy);
+
+    let outcome = wal.commit(&storage, &options, mutation)?;
+    match ApiError::from_outcome(outcome, "location", id) {
+        None => Ok(Json(NewOrUpdateResponse)),
+        Some(error) => Err(error),
+    }
+}
+
+pub fn update_location(
+    tables: &mut Tables,
+    id: u32,
+    location_update: LocationUpdate,
+) -> Outcome {
+    let location_entry = tables.locations.entry(id);
     match location_entry {
         Entry::Occupied(mut e) => {
-            if location_update.city != "" {
-                e.get_mut().city = location_update.city;
+            let location = e.get_mut();
+            if let Some(city) = location_update.city.into_option() {
+                location.city = Symbol::intern(&city);
             }
-            if location_update.country != "" {
-                e.get_mut().country = location_update.country;
+            if let Some(country) = location_update.country.into_option() {
+                location.country = Symbol::intern(&country);
             }
-            if location_update.distance != 0 {
-                e.get_mut().distance = location_update.distance;
+            if let Some(distance) = location_update.distance.into_option() {
+                location.distance = distance;
             }
-            if location_update.place != "" {
-                e.get_mut().place = location_update.place;
+            if let Some(place) = location_update.place.into_option() {
+                location.place = place;
             }
         }
-        Entry::Vacant(_) => return None,
+        Entry::Vacant(_) => return Outcome::NotFound,
     }
-    Some(Json(NewOrUpdateResponse))
+    Outcome::Applied
 }
 
-#[post("/locations/new", data = "<location>")]
-fn locations_new(
-    location: Json<Location>,
-    storage: State<Storage>,
-) -> Option<Json<NewOrUpdateResponse>> {
-    let location = location.0;
+pub fn insert_location(tables: &mut Tables, mut location: Location) -> Outcome {
     let id = location.id;
+    if tables.locations.contains_key(&id) {
+        return Outcome::AlreadyExists;
+    }
+    // Visits orphaned by an earlier delete still point at the id.
+    if tables.location_visits.get(&id).map_or(false, |ids| !ids.is_empty()) {
+        return Outcome::HasOrphans;
+    }
 
-    let locations = &mut storage.locations.write().unwrap();
-    let location_entry = locations.entry(id);
+    location.intern();
+    let location_entry = tables.locations.entry(id);
     match location_entry {
-        Entry::Occupied(_) => return None,
+        Entry::Occupied(_) => return Outcome::AlreadyExists,
         Entry::Vacant(e) => {
             e.insert(Location {
                 id: id,
@@ -241,5 +431,38 @@ fn locations_new(
             });
         }
     }
-    Some(Json(NewOrUpdateResponse))
+    Outcome::Applied
+}
+
+/// Deletes a location, dealing with its visits according to `policy`.
+///
+/// With `DependentsPolicy::Cascade`, a location that is already gone still
+/// has the visits orphaned by its earlier delete removed, which frees the id.
+pub fn delete_location(tables: &mut Tables, id: u32, policy: DependentsPolicy) -> Outcome {
+    if !tables.locations.contains_key(&id) {
+        let orphans = tables.location_visits.get(&id).map_or(false, |ids| !ids.is_empty());
+        if !orphans || policy != DependentsPolicy::Cascade {
+            return Outcome::NotFound;
+        }
+        let visit_ids = tables.location_visits.remove(&id).unwrap_or_default();
+        visits::remove_visits(tables, &visit_ids);
+        tables.location_marks.remove(&id);
+        return Outcome::Applied;
+    }
+    let has_visits = tables
+        .location_visits
+        .get(&id)
+        .map_or(false, |ids| !ids.is_empty());
+    if has_visits && policy == DependentsPolicy::Reject {
+        return Outcome::HasDependents;
+    }
+
+    tables.locations.remove(&id);
+
+    if policy == DependentsPolicy::Cascade {
+        let visit_ids = tables.location_visits.remove(&id).unwrap_or_default();
+        visits::remove_visits(tables, &visit_ids);
+        tables.location_marks.remove(&id);
+    }
+    Outcome::Applied
 }
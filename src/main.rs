@@ -1,282 +1,333 @@
 #![feature(plugin, custom_derive)]
 #![plugin(rocket_codegen)]
 
+extern crate arrayvec;
+extern crate byteorder;
 extern crate chrono;
+extern crate flate2;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
 extern crate rocket;
 extern crate rocket_contrib;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate tar;
+extern crate toml;
 extern crate zip;
 
+mod admin;
+mod bench;
+mod clock;
+mod config;
+mod error;
+mod export;
 mod gender;
+mod idmap;
+mod integrity;
+mod loader;
 mod locations;
+mod marks;
+mod reload;
+mod snapshot;
+mod status;
+mod text;
 mod users;
 mod visits;
 mod util;
+mod validation;
+mod wal;
 
 #[cfg(test)]
 mod tests;
 
-use users::User;
+use clock::{Clock, ClockSource};
+use config::{Config, InputFormat};
+use users::{User, Visitor};
 use locations::Location;
+use marks::LocationMarks;
+use reload::Reloader;
 use visits::Visit;
+use idmap::IdMap;
+use loader::{DataSource, DirSource, LoadError, TarSource, ZipSource};
+use export::ExportRoot;
+use snapshot::SnapshotPath;
 use util::QueryId;
+use wal::Wal;
+
+use rocket::config::{Config as RocketConfig, Environment};
 
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
 use std::env;
 use std::error::Error;
-use std::io::{self, BufReader, Read};
+use std::io::{self, ErrorKind, Read};
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
-
-fn get_env() -> String {
-    match env::var("ENVIRONMENT") {
-        Ok(val) => val,
-        Err(_) => "dev".to_owned(),
-    }
-}
+use std::process;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-fn get_data_dir_path(env: &str) -> Result<PathBuf, io::Error> {
-    let data_dir = match &*env {
-        "dev" => {
-            let mut cur_dir = env::current_dir()?;
-            cur_dir.push("data");
-            cur_dir
-        }
-        "prod" => PathBuf::from("/tmp/data"),
-        _ => unreachable!(),
-    };
-    Ok(data_dir)
-}
-
-fn read_options(options_path: &Path) -> Result<Options, io::Error> {
-    let mut options_file = File::open(options_path).unwrap();
+fn read_options(options_path: &Path, clock_source: ClockSource) -> Result<Options, LoadError> {
+    let file_name = options_path.to_string_lossy().into_owned();
     let mut options_content = String::new();
-    options_file.read_to_string(&mut options_content).unwrap();
-    let options_content_lines: Vec<_> = options_content.split('\n').collect();
-    let timestamp_line = options_content_lines[0];
-    let timestamp: i32 = timestamp_line.parse().unwrap();
+    File::open(options_path)
+        .and_then(|mut options_file| options_file.read_to_string(&mut options_content))
+        .map_err(|e| LoadError::file(&file_name, loader::Cause::Io(e)))?;
+    let mut options_content_lines = options_content.lines();
+    let timestamp_line = options_content_lines.next().unwrap_or("").trim();
+    let timestamp: i32 = timestamp_line.parse().map_err(|_| {
+        let message = format!("invalid timestamp {:?}", timestamp_line);
+        LoadError::at_line(&file_name, 1, message)
+    })?;
     println!("now: {}", timestamp);
-    let mode_line = options_content_lines[1];
+    let mode_line = options_content_lines.next().unwrap_or("").trim();
     let mode = match mode_line {
         "0" => Mode::Test,
         "1" => Mode::Rating,
-        _ => unreachable!(),
+        _ => {
+            let message = format!("invalid mode {:?}, expected 0 or 1", mode_line);
+            return Err(LoadError::at_line(&file_name, 2, message));
+        }
     };
+    let clock = Clock::new(clock_source, timestamp);
+    println!("clock: {:?}", clock.source());
     Ok(Options {
-        now: timestamp,
+        clock: Arc::new(clock),
         mode: mode,
     })
 }
 
-struct Storage {
-    users: RwLock<HashMap<u32, User>>,
-    locations: RwLock<HashMap<u32, Location>>,
-    visits: RwLock<HashMap<u32, Visit>>,
-    ages: RwLock<HashMap<u32, i32>>,
-    location_visits: RwLock<HashMap<u32, Vec<u32>>>,
-    user_visits: RwLock<HashMap<u32, Vec<u32>>>,
+#[derive(Clone, Default)]
+struct Tables {
+    users: IdMap<User>,
+    locations: IdMap<Location>,
+    visits: IdMap<Visit>,
+    visitors: IdMap<Visitor>,
+    location_visits: IdMap<Vec<u32>>,
+    user_visits: IdMap<Vec<u32>>,
+    location_marks: IdMap<LocationMarks>,
 }
 
-fn read_entities_from_file<T>(
-    data_file: &mut T,
-    template: &str,
-    all_users: &mut HashMap<u32, User>,
-    all_locations: &mut HashMap<u32, Location>,
-    all_visits: &mut HashMap<u32, Visit>,
-    ages: &mut HashMap<u32, i32>,
-    location_visits: &mut HashMap<u32, Vec<u32>>,
-    user_visits: &mut HashMap<u32, Vec<u32>>,
-    options: &Options,
-) where
-    T: Read,
-{
-    let mut data = String::new();
-    data_file.read_to_string(&mut data).unwrap();
-
-    match template {
-        "users_" => {
-            let mut users: HashMap<String, Vec<User>> = serde_json::from_str(&data).unwrap();
-            for v in users.remove("users").unwrap() {
-                ages.insert(
-                    v.id,
-                    users::calculate_age_from_timestamp(v.birth_date, options.now),
-                );
-                all_users.insert(v.id, v);
-            }
-        }
-        "locations_" => {
-            let mut locations: HashMap<String, Vec<Location>> =
-                serde_json::from_str(&data).unwrap();
-            for v in locations.remove("locations").unwrap() {
-                all_locations.insert(v.id, v);
-            }
-        }
-        "visits_" => {
-            let mut visits: HashMap<String, Vec<Visit>> = serde_json::from_str(&data).unwrap();
-            for visit in visits.remove("visits").unwrap() {
-                let location_visits_entry = location_visits.entry(visit.location);
-                match location_visits_entry {
-                    Entry::Occupied(mut e) => {
-                        e.get_mut().push(visit.id);
-                    }
-                    Entry::Vacant(e) => {
-                        e.insert(vec![visit.id]);
-                    }
-                }
-
-                let user_visits_entry = user_visits.entry(visit.user);
-                match user_visits_entry {
-                    Entry::Occupied(mut e) => {
-                        e.get_mut().push(visit.id);
-                    }
-                    Entry::Vacant(e) => {
-                        e.insert(vec![visit.id]);
-                    }
-                }
-
-                all_visits.insert(visit.id, visit);
-            }
-        }
-        _ => unreachable!(),
+impl Tables {
+    /// Builds the indexes that data files and snapshots don't carry.
+    fn rebuild_indexes(&mut self, now: i32) {
+        self.refresh_visitors(now);
+        visits::sort_timelines(self);
+        marks::rebuild(self);
     }
-}
 
-fn input_data(data_path: &Path, options: &Options) -> Result<Storage, io::Error> {
-    let entity_name_templates = ["users_", "locations_", "visits_"];
-    let mut all_users = HashMap::new();
-    let mut all_locations = HashMap::new();
-    let mut all_visits = HashMap::new();
-    let mut ages = HashMap::new();
-    let mut location_visits = HashMap::new();
-    let mut user_visits = HashMap::new();
-
-    for template in &entity_name_templates {
-        let mut index = 1;
-        loop {
-            let mut data_file_name = String::from(*template);
-            data_file_name.push_str(&format!("{}", index));
-            data_file_name.push_str(".json");
-            let data_file_path = data_path.join(data_file_name);
-
-            println!("Reading data file: {:?}", data_file_path);
-            let maybe_data_file = File::open(data_file_path);
-            let mut data_file = match maybe_data_file {
-                Ok(f) => f,
-                Err(_) => break,
-            };
-            read_entities_from_file(
-                &mut data_file,
-                template,
-                &mut all_users,
-                &mut all_locations,
-                &mut all_visits,
-                &mut ages,
-                &mut location_visits,
-                &mut user_visits,
-                &options,
-            );
-            index += 1;
+    fn refresh_visitors(&mut self, now: i32) {
+        let mut visitors = IdMap::new();
+        for (id, user) in self.users.iter() {
+            visitors.insert(id, Visitor::new(user, now));
         }
+        self.visitors = visitors;
     }
+}
 
-    Ok(Storage {
-        users: RwLock::new(all_users),
-        locations: RwLock::new(all_locations),
-        visits: RwLock::new(all_visits),
-        ages: RwLock::new(ages),
-        location_visits: RwLock::new(location_visits),
-        user_visits: RwLock::new(user_visits),
-    })
+/// Immutable versions of `Tables`, replaced as a whole on every mutation.
+/// Readers take the current version and keep it as long as they like;
+/// writers copy it (sharing every shard they don't touch), apply the
+/// mutation and swap the copy in.
+struct Storage {
+    current: RwLock<Arc<Tables>>,
+    writer: Mutex<()>,
 }
 
-fn input_data_prod(data_dir_path: &Path, options: &Options) -> Result<Storage, io::Error> {
-    let entity_name_templates = ["users_", "locations_", "visits_"];
-    let mut all_users = HashMap::new();
-    let mut all_locations = HashMap::new();
-    let mut all_visits = HashMap::new();
-    let mut ages = HashMap::new();
-    let mut location_visits = HashMap::new();
-    let mut user_visits = HashMap::new();
-
-    let data_file_path = data_dir_path.join("data.zip");
-    let file = File::open(data_file_path).unwrap();
-    let reader = BufReader::new(file);
-
-    let mut zip = zip::ZipArchive::new(reader).unwrap();
-
-    for template in &entity_name_templates {
-        let mut index = 1;
-        loop {
-            let mut data_file_name = String::from(*template);
-            data_file_name.push_str(&format!("{}", index));
-            data_file_name.push_str(".json");
-
-            println!("Reading data file: {:?}", data_file_name);
-            let maybe_data_file = zip.by_name(data_file_name.as_str());
-            let mut data_file = match maybe_data_file {
-                Ok(f) => f,
-                Err(_) => break,
-            };
-            read_entities_from_file(
-                &mut data_file,
-                template,
-                &mut all_users,
-                &mut all_locations,
-                &mut all_visits,
-                &mut ages,
-                &mut location_visits,
-                &mut user_visits,
-                &options,
-            );
-            index += 1;
+impl Storage {
+    fn new(tables: Tables) -> Storage {
+        Storage {
+            current: RwLock::new(Arc::new(tables)),
+            writer: Mutex::new(()),
         }
     }
 
-    Ok(Storage {
-        users: RwLock::new(all_users),
-        locations: RwLock::new(all_locations),
-        visits: RwLock::new(all_visits),
-        ages: RwLock::new(ages),
-        location_visits: RwLock::new(location_visits),
-        user_visits: RwLock::new(user_visits),
-    })
+    /// The current version. The lock is only held to clone the `Arc`, so
+    /// this never waits for a mutation to be applied.
+    fn read(&self) -> Arc<Tables> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Applies `f` to a copy of the current version and publishes it once
+    /// `f` returns. Writers are serialized; readers see either the old
+    /// version or the new one.
+    fn update<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Tables) -> T,
+    {
+        let _writer = self.writer.lock().unwrap();
+        let mut tables = (*self.read()).clone();
+        let result = f(&mut tables);
+        *self.current.write().unwrap() = Arc::new(tables);
+        result
+    }
+
+    /// Publishes `tables` in place of the current version, once any
+    /// mutation being applied is done.
+    fn replace(&self, tables: Arc<Tables>) {
+        let _writer = self.writer.lock().unwrap();
+        *self.current.write().unwrap() = tables;
+    }
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
 enum Mode {
     Test,
     Rating,
 }
 
-#[derive(Debug)]
+impl Mode {
+    /// Rating runs warm up before taking requests.
+    fn warms_up(&self) -> bool {
+        *self == Mode::Rating
+    }
+
+    /// Test runs log every request and check the indexes after every
+    /// mutation.
+    fn is_checked(&self) -> bool {
+        *self == Mode::Test
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Options {
-    now: i32,
+    clock: Arc<Clock>,
     mode: Mode,
 }
 
-fn work() -> Result<(), Box<Error>> {
-    let env = get_env();
-    println!("env: {:?}", env);
-    let data_dir_path = get_data_dir_path(&env).unwrap();
-    println!("data_dir_path: {:?}", data_dir_path);
-
-    let mut options_path = data_dir_path.clone();
-    options_path.push("options.txt");
-    let options = read_options(&options_path).unwrap();
-    let data = match &*env {
-        "prod" => input_data_prod(&data_dir_path, &options).unwrap(),
-        "dev" => input_data(&data_dir_path, &options).unwrap(),
-        _ => unreachable!(),
+impl Options {
+    fn now(&self) -> i32 {
+        self.clock.now()
+    }
+}
+
+// The data set from the snapshot if there is one written since the data
+// files last changed, else from the data files. The WAL was emptied when the
+// snapshot was written, so it only applies on top of it: a snapshot that's
+// there but can't be used is an error, unless `discard_snapshot` is set. The
+// flag says whether the WAL applies to the data set.
+fn load_data(config: &Config, options: &Options) -> Result<(Storage, bool), Box<Error>> {
+    let input = config.input_path();
+    let input = input.as_ref().map(|path| path.as_path());
+    let snapshot = if !config.snapshot_path.exists() {
+        Err(io::Error::new(ErrorKind::NotFound, "there is none"))
+    } else if snapshot::is_newer_than_input(&config.snapshot_path, input) {
+        snapshot::read_snapshot(&config.snapshot_path, options)
+    } else {
+        Err(io::Error::new(ErrorKind::InvalidData, "older than the data files"))
     };
+    let (data, wal_applies) = match snapshot {
+        Ok(data) => {
+            println!("Loaded snapshot");
+            (data, true)
+        }
+        Err(ref e) if e.kind() != ErrorKind::NotFound && !config.discard_snapshot => {
+            let message = format!(
+                "the snapshot at {:?} can't be used: {}. The WAL only applies on top of it; \
+                 set discard_snapshot to start from the data files without either",
+                config.snapshot_path,
+                e
+            );
+            return Err(message.into());
+        }
+        Err(e) => {
+            println!("Not using snapshot: {}", e);
+            let source: Box<DataSource> = match config.input_format {
+                InputFormat::Dir => Box::new(DirSource::new(&config.data_dir)),
+                InputFormat::Zip => Box::new(ZipSource::open(&config.zip_path())?),
+                InputFormat::Tar => Box::new(TarSource::open(&config.tar_path())?),
+                InputFormat::Stdin => Box::new(TarSource::stdin()?),
+            };
+            let (policy, threads) = (config.load_policy, config.load_threads);
+            let (data, report) = loader::load(source, options, policy, threads)?;
+            report.print();
+            // Without a snapshot, the WAL holds everything since the data
+            // files were loaded; a discarded one took it along.
+            (data, e.kind() == ErrorKind::NotFound)
+        }
+    };
+    Ok((data, wal_applies))
+}
+
+/// Reads the options and the data set, and opens the WAL.
+fn load(config: &Config) -> Result<(Options, Storage, Wal), Box<Error>> {
+    let options = read_options(&config.options_path(), config.clock)?;
+    let wal = Wal::open(&config.wal_path, config.wal_sync)?;
+    let (data, wal_applies) = load_data(config, &options)?;
+    if wal_applies {
+        let replayed = wal.replay(&data, &options)?;
+        println!("Replayed {} WAL records", replayed);
+    } else {
+        println!("Discarding the WAL along with the snapshot");
+        wal.checkpoint(|| Ok(()))?;
+    }
+    Ok((options, data, wal))
+}
+
+/// Like `load`, but leaves the WAL as it is, for commands that may run next
+/// to a server.
+fn load_read_only(config: &Config) -> Result<(Options, Storage), Box<Error>> {
+    let options = read_options(&config.options_path(), config.clock)?;
+    let (data, wal_applies) = load_data(config, &options)?;
+    if wal_applies {
+        let replayed = wal::replay_read_only(&config.wal_path, &data, &options)?;
+        println!("Replayed {} WAL records", replayed);
+    }
+    Ok((options, data))
+}
+
+fn work(config: &Config) -> Result<(), Box<Error>> {
+    println!("config: {:?}", config);
+    let (options, data, wal) = load(config)?;
+
+    if let Err(error) = integrity::enforce(config.integrity_check, &data.read()) {
+        println!("Refusing to start: {}", error);
+        process::exit(1);
+    }
+
+    let memory = admin::memory_report(&data.read()).total;
+    println!(
+        "Tables take {} bytes, {} as hash maps",
+        memory.bytes,
+        memory.hash_map_bytes
+    );
+
+    if options.mode.warms_up() {
+        let read = status::warm_up(&data.read(), options.now());
+        println!("Warmed up, read {} records", read);
+    }
 
-    rocket::ignite()
+    let mode = options.mode;
+    let data = Arc::new(data);
+    let wal = Arc::new(wal);
+    snapshot::snapshot_on_shutdown(
+        config.snapshot_path.clone(),
+        data.clone(),
+        wal.clone(),
+        options.clock.clone(),
+    );
+    clock::refresh_visitors_daily(data.clone(), options.clock.clone());
+
+    let reloader = Arc::new(Reloader::new(data.clone(), wal.clone(), options.clone(), config));
+    reload::reload_on_hangup(reloader.clone());
+
+    let mut rocket_config = RocketConfig::build(Environment::active()?)
+        .address(config.address.clone())
+        .port(config.port);
+    if let Some(workers) = config.workers {
+        rocket_config = rocket_config.workers(workers);
+    }
+
+    let rocket = rocket::custom(rocket_config.finalize()?, true)
         .manage(data)
         .manage(options)
+        .manage(wal)
+        .manage(SnapshotPath(config.snapshot_path.clone()))
+        .manage(ExportRoot(config.export_root.clone()))
+        .manage(reloader)
+        .manage(config.admin_token.clone())
         .mount(
             "/",
             routes![
@@ -293,12 +344,154 @@ fn work() -> Result<(), Box<Error>> {
                 users::users_new,
                 locations::locations_new,
                 visits::visits_new,
+                users::users_list_no_params,
+                users::users_list,
+                locations::locations_list_no_params,
+                locations::locations_list,
+                visits::visits_list_no_params,
+                visits::visits_list,
+                users::users_delete_no_params,
+                users::users_delete,
+                locations::locations_delete_no_params,
+                locations::locations_delete,
+                visits::visits_delete,
+                admin::admin_snapshot,
+                admin::admin_export,
+                admin::admin_memory,
+                admin::admin_clock,
+                admin::admin_clock_set,
+                admin::admin_status,
+                admin::admin_reload_no_params,
+                admin::admin_reload,
+                admin::admin_reload_status,
             ],
         )
-        .launch();
+        .catch(errors![
+            error::bad_request,
+            error::not_found,
+            error::conflict,
+            error::unprocessable_entity,
+            error::internal_error,
+        ]);
+    if mode.is_checked() {
+        rocket.attach(status::request_logger()).launch();
+    } else {
+        rocket.launch();
+    }
+    Ok(())
+}
+
+/// `rustler export <dir> [--zip] [--chunk-size N]`: loads the data the same
+/// way the server does and writes it back out in the input layout.
+fn export_command(config: &Config, args: &[String]) -> Result<(), Box<Error>> {
+    let usage = "usage: rustler export <dir> [--zip] [--chunk-size N]";
+    let mut out_dir = None;
+    let mut pack_zip = false;
+    let mut chunk_size = export::DEFAULT_CHUNK_SIZE;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--zip" => pack_zip = true,
+            "--chunk-size" => chunk_size = args.next().ok_or(usage)?.parse()?,
+            _ => out_dir = Some(PathBuf::from(arg)),
+        }
+    }
+    let out_dir = out_dir.ok_or(usage)?;
+
+    let (options, data) = load_read_only(config)?;
+
+    let info = export::export(&out_dir, &data, &options, chunk_size, pack_zip)?;
+    println!("Exported: {:?}", info);
+    Ok(())
+}
+
+/// `rustler bench [--readers N] [--writers N] [--seconds N]`: compares read
+/// throughput under concurrent writes, and write throughput alone, for
+/// `Storage` and a single-lock store.
+fn bench_command(config: &Config, args: &[String]) -> Result<(), Box<Error>> {
+    let usage = "usage: rustler bench [--readers N] [--writers N] [--seconds N]";
+    let mut bench_config = bench::BenchConfig {
+        readers: 8,
+        writers: 1,
+        duration: Duration::from_secs(5),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(usage)?;
+        match arg.as_str() {
+            "--readers" => bench_config.readers = value.parse()?,
+            "--writers" => bench_config.writers = value.parse()?,
+            "--seconds" => bench_config.duration = Duration::from_secs(value.parse()?),
+            _ => return Err(usage.into()),
+        }
+    }
+
+    let (options, data) = load_read_only(config)?;
+
+    for result in bench::run(data, options, &bench_config) {
+        println!(
+            "{}: {:.0} reads/s, {:.0} writes/s",
+            result.name,
+            result.reads_per_sec,
+            result.writes_per_sec
+        );
+    }
+    Ok(())
+}
+
+/// `rustler bench-load [--scale N] [--threads N]`: measures the time and
+/// memory it takes to load `data_dir`, and a synthetic data set N times its
+/// size.
+fn bench_load_command(config: &Config, args: &[String]) -> Result<(), Box<Error>> {
+    let usage = "usage: rustler bench-load [--scale N] [--threads N]";
+    let mut scale = 1;
+    let mut threads = config.load_threads;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or(usage)?;
+        match arg.as_str() {
+            "--scale" => scale = value.parse()?,
+            "--threads" => threads = value.parse()?,
+            _ => return Err(usage.into()),
+        }
+    }
+
+    let options = read_options(&config.options_path(), config.clock)?;
+
+    for result in bench::run_load(&config.data_dir, &options, scale, threads)? {
+        let peak = match result.peak_kb {
+            Some(kb) => format!("{} KiB", kb),
+            None => "unknown".to_owned(),
+        };
+        println!(
+            "{}: {} records in {:.2} s, peak memory +{}",
+            result.name,
+            result.records,
+            result.seconds,
+            peak
+        );
+    }
     Ok(())
 }
 
 fn main() {
-    work().unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (config, args) = match Config::load(&args) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            process::exit(2);
+        }
+    };
+    let result = match args.first().map(|arg| arg.as_str()) {
+        Some("export") => export_command(&config, &args[1..]),
+        Some("bench") => bench_command(&config, &args[1..]),
+        Some("bench-load") => bench_load_command(&config, &args[1..]),
+        Some(arg) => Err(format!("unknown argument {:?}", arg).into()),
+        None => work(&config),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,296 @@
+use Tables;
+use gender::Gender;
+use idmap::{HeapSize, IdMap};
+use users::{self, Visitor};
+use visits::Visit;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::i32;
+
+// Visits are split by their user's gender; the last slot holds visits whose
+// user has no known gender or doesn't exist any more.
+const GENDER_SLOTS: usize = 3;
+
+fn gender_slot(gender: Option<&Gender>) -> usize {
+    match gender {
+        Some(&Gender::Male) => 0,
+        Some(&Gender::Female) => 1,
+        _ => 2,
+    }
+}
+
+/// Marks ordered by a key, with running sums so that the total over any key
+/// range takes two binary searches.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct MarkSeries {
+    /// The key, then the visit id to keep entries unique.
+    keys: Vec<(i32, u32)>,
+    /// `sums[i]` is the total of the marks up to and including `keys[i]`'s.
+    sums: Vec<u64>,
+}
+
+impl MarkSeries {
+    fn from_entries(entries: &mut Vec<((i32, u32), u8)>) -> MarkSeries {
+        entries.sort();
+        let mut sums = Vec::with_capacity(entries.len());
+        let mut sum = 0;
+        for &(_, mark) in entries.iter() {
+            sum += mark as u64;
+            sums.push(sum);
+        }
+        MarkSeries {
+            keys: entries.iter().map(|&(key, _)| key).collect(),
+            sums: sums,
+        }
+    }
+
+    // The total of the first `count` marks.
+    fn sum_before(&self, count: usize) -> u64 {
+        if count == 0 {
+            0
+        } else {
+            self.sums[count - 1]
+        }
+    }
+
+    fn insert(&mut self, key: (i32, u32), mark: u8) {
+        let position = match self.keys.binary_search(&key) {
+            Ok(_) => return,
+            Err(position) => position,
+        };
+        let sum = self.sum_before(position) + mark as u64;
+        self.keys.insert(position, key);
+        self.sums.insert(position, sum);
+        for sum in &mut self.sums[position + 1..] {
+            *sum += mark as u64;
+        }
+    }
+
+    fn remove(&mut self, key: (i32, u32)) {
+        let position = match self.keys.binary_search(&key) {
+            Ok(position) => position,
+            Err(_) => return,
+        };
+        let mark = self.sums[position] - self.sum_before(position);
+        self.keys.remove(position);
+        self.sums.remove(position);
+        for sum in &mut self.sums[position..] {
+            *sum -= mark;
+        }
+    }
+
+    // The number of leading entries whose key satisfies `before`.
+    fn count_before<F: Fn(i64) -> bool>(&self, before: F) -> usize {
+        let search = self.keys.binary_search_by(|&(key, _)| if before(key as i64) {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        });
+        search.unwrap_or_else(|position| position)
+    }
+
+    /// Sum and count of the marks with `low < key < high`.
+    fn range(&self, low: Option<i64>, high: Option<i64>) -> (u64, usize) {
+        let start = low.map_or(0, |low| self.count_before(|key| key <= low));
+        let end = high.map_or(self.keys.len(), |high| self.count_before(|key| key < high));
+        if end <= start {
+            (0, 0)
+        } else {
+            (self.sum_before(end) - self.sum_before(start), end - start)
+        }
+    }
+}
+
+/// Sums of one location's marks, kept up to date by the visit and user
+/// mutations so that `/locations/<id>/avg` doesn't have to look at every
+/// visit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocationMarks {
+    /// Per gender slot, keyed by `visited_at`.
+    by_visited_at: [MarkSeries; GENDER_SLOTS],
+    /// Per gender slot, keyed by the user's `birth_date`. Visits whose user
+    /// doesn't exist aren't here.
+    by_birth_date: [MarkSeries; GENDER_SLOTS],
+}
+
+impl HeapSize for LocationMarks {
+    fn heap_size(&self) -> usize {
+        self.by_visited_at
+            .iter()
+            .chain(self.by_birth_date.iter())
+            .map(|series| series.keys.heap_size() + series.sums.heap_size())
+            .sum()
+    }
+}
+
+/// The filters `/locations/<id>/avg` takes.
+#[derive(Debug, Default)]
+pub struct MarkQuery {
+    pub from_date: Option<i32>,
+    pub to_date: Option<i32>,
+    pub from_age: Option<i32>,
+    pub to_age: Option<i32>,
+    pub gender: Option<Gender>,
+}
+
+// The latest birth date of someone at least `age` years old at `now`. Age
+// only goes down as the birth date goes up, so this splits birth dates in two.
+fn latest_birth_date(age: i32, now: i32) -> i64 {
+    // Everything up to `low` is old enough; nothing after `high` is.
+    let mut low = i32::MIN as i64 - 1;
+    let mut high = i32::MAX as i64;
+    while low < high {
+        let middle = low + (high - low + 1) / 2;
+        if users::calculate_age_from_timestamp(middle as i32, now) >= age {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    low
+}
+
+impl LocationMarks {
+    /// Sum and count of the marks matching `query`, or `None` if it filters
+    /// on both dates and ages, which has to be answered by scanning.
+    pub fn total(&self, query: &MarkQuery, now: i32) -> Option<(u64, usize)> {
+        let has_dates = query.from_date.is_some() || query.to_date.is_some();
+        let has_ages = query.from_age.is_some() || query.to_age.is_some();
+        let (series, low, high) = if !has_ages {
+            (
+                &self.by_visited_at,
+                query.from_date.map(|date| date as i64),
+                query.to_date.map(|date| date as i64),
+            )
+        } else if !has_dates {
+            // Old enough for `fromAge`, and not old enough for `toAge`.
+            (
+                &self.by_birth_date,
+                query.to_age.map(|age| latest_birth_date(age, now)),
+                query.from_age.map(|age| latest_birth_date(age, now) + 1),
+            )
+        } else {
+            return None;
+        };
+
+        let slots = match query.gender {
+            Some(ref gender) => gender_slot(Some(gender))..gender_slot(Some(gender)) + 1,
+            None => 0..GENDER_SLOTS,
+        };
+        let mut total = (0, 0);
+        for slot in slots {
+            let (sum, count) = series[slot].range(low, high);
+            total.0 += sum;
+            total.1 += count;
+        }
+        Some(total)
+    }
+
+    fn insert(&mut self, visit: &Visit, visitor: Option<&Visitor>) {
+        let slot = gender_slot(visitor.map(|visitor| &visitor.gender));
+        self.by_visited_at[slot].insert((visit.visited_at, visit.id), visit.mark);
+        if let Some(visitor) = visitor {
+            self.by_birth_date[slot].insert((visitor.birth_date, visit.id), visit.mark);
+        }
+    }
+
+    fn remove(&mut self, visit: &Visit, visitor: Option<&Visitor>) {
+        let slot = gender_slot(visitor.map(|visitor| &visitor.gender));
+        self.by_visited_at[slot].remove((visit.visited_at, visit.id));
+        if let Some(visitor) = visitor {
+            self.by_birth_date[slot].remove((visitor.birth_date, visit.id));
+        }
+    }
+}
+
+/// Adds visits to their locations' sums, as the visits and their users'
+/// `visitors` records are now. Ids that aren't in `visits` are skipped.
+pub fn record(tables: &mut Tables, visit_ids: &[u32]) {
+    for id in visit_ids {
+        let visit = match tables.visits.get(id) {
+            Some(visit) => visit,
+            None => continue,
+        };
+        let visitor = tables.visitors.get(&visit.user);
+        tables
+            .location_marks
+            .entry(visit.location)
+            .or_insert_with(LocationMarks::default)
+            .insert(visit, visitor);
+    }
+}
+
+/// Takes visits out of their locations' sums. Has to be called before the
+/// visits or their users change, with `record` afterwards.
+pub fn forget(tables: &mut Tables, visit_ids: &[u32]) {
+    for id in visit_ids {
+        let visit = match tables.visits.get(id) {
+            Some(visit) => visit,
+            None => continue,
+        };
+        let visitor = tables.visitors.get(&visit.user);
+        if let Some(marks) = tables.location_marks.get_mut(&visit.location) {
+            marks.remove(visit, visitor);
+        }
+    }
+}
+
+type Entries = [Vec<((i32, u32), u8)>; GENDER_SLOTS];
+
+fn build_series(entries: &mut Entries) -> [MarkSeries; GENDER_SLOTS] {
+    [
+        MarkSeries::from_entries(&mut entries[0]),
+        MarkSeries::from_entries(&mut entries[1]),
+        MarkSeries::from_entries(&mut entries[2]),
+    ]
+}
+
+// Adds `visit`'s mark to the entries of its location, by `visited_at` and by
+// its user's `birth_date`.
+fn add_entries(tables: &Tables, visit: &Visit, entries: &mut (Entries, Entries)) {
+    let visitor = tables.visitors.get(&visit.user);
+    let slot = gender_slot(visitor.map(|visitor| &visitor.gender));
+    entries.0[slot].push(((visit.visited_at, visit.id), visit.mark));
+    if let Some(visitor) = visitor {
+        entries.1[slot].push(((visitor.birth_date, visit.id), visit.mark));
+    }
+}
+
+fn build_marks(entries: &mut (Entries, Entries)) -> LocationMarks {
+    LocationMarks {
+        by_visited_at: build_series(&mut entries.0),
+        by_birth_date: build_series(&mut entries.1),
+    }
+}
+
+/// Computes `location_marks` from scratch, for tables that were loaded rather
+/// than built up by mutations.
+pub fn rebuild(tables: &mut Tables) {
+    let mut entries: HashMap<u32, (Entries, Entries)> = HashMap::new();
+    for visit in tables.visits.values() {
+        let location = entries.entry(visit.location).or_insert_with(Default::default);
+        add_entries(tables, visit, location);
+    }
+
+    let mut location_marks = IdMap::new();
+    for (location, mut location_entries) in entries {
+        location_marks.insert(location, build_marks(&mut location_entries));
+    }
+    tables.location_marks = location_marks;
+}
+
+/// What `location_marks[location]` should hold, worked out from scratch from
+/// the visits `location_visits[location]` lists. Ids of visits that aren't
+/// there or are at another location are left out.
+pub fn recount(tables: &Tables, location: u32) -> LocationMarks {
+    let mut entries = Default::default();
+    if let Some(ids) = tables.location_visits.get(&location) {
+        for visit in ids.iter().filter_map(|id| tables.visits.get(id)) {
+            if visit.location == location {
+                add_entries(tables, visit, &mut entries);
+            }
+        }
+    }
+    build_marks(&mut entries)
+}
@@ -0,0 +1,322 @@
+use Options;
+use Storage;
+use clock::ClockSource;
+use config::Config;
+use integrity::{self, IntegrityPolicy};
+use loader::{self, LoadPolicy, LoadProgress};
+use read_options;
+use snapshot;
+use status;
+use util;
+use wal::{self, Outcome, Wal};
+
+use libc;
+
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReloadState {
+    /// No reload since the server started.
+    Idle,
+    Loading,
+    /// The last reload was swapped in.
+    Done,
+    /// The last reload didn't make it, and the data is as it was.
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ReloadStatus {
+    pub state: ReloadState,
+    /// The data directory or archive of the current or last reload.
+    #[serde(skip_serializing_if = "Option::is_none")] pub source: Option<String>,
+    /// Data files found so far.
+    pub files: usize,
+    /// Data files parsed and merged so far.
+    pub files_loaded: usize,
+    /// How long the last reload took, once it's over.
+    #[serde(skip_serializing_if = "Option::is_none")] pub millis: Option<u64>,
+    /// Mutations accepted while the last reload was loading, made again to
+    /// the new data once it was complete.
+    pub replayed_mutations: usize,
+    /// Mutations accepted while the last reload was loading that didn't
+    /// apply to the new data, e.g. updates of entities it doesn't have.
+    pub discarded_mutations: usize,
+    #[serde(skip_serializing_if = "Option::is_none")] pub error: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum StartError {
+    /// Another reload hasn't finished yet.
+    InProgress,
+    /// No path was given, and the server's own data can't be read again.
+    NoSource,
+    /// No path was given, and reading the data files again would lose the
+    /// changes made since they were loaded, kept in the snapshot and the WAL.
+    WouldDiscard,
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StartError::InProgress => f.write_str("a reload is already in progress"),
+            StartError::NoSource => f.write_str("no data to reload from"),
+            StartError::WouldDiscard => f.write_str(
+                "the snapshot or the WAL has changes the data files don't; \
+                 reload with discard=true to drop them",
+            ),
+        }
+    }
+}
+
+/// Loads a new dataset into a fresh `Storage` in the background and swaps it
+/// in once it's complete and passes the integrity check. Mutations accepted
+/// while it loads are made to both. Requests already running finish against
+/// the version they started with.
+pub struct Reloader {
+    storage: Arc<Storage>,
+    wal: Arc<Wal>,
+    options: Options,
+    clock_source: ClockSource,
+    options_file: String,
+    snapshot_path: PathBuf,
+    policy: LoadPolicy,
+    threads: usize,
+    integrity: IntegrityPolicy,
+    /// Reloaded on SIGHUP, or when no path is given. `None` for standard
+    /// input, which can't be read twice.
+    default_path: Option<PathBuf>,
+    /// Paths given to `/admin/reload` are taken relative to this.
+    root: PathBuf,
+    status: Mutex<ReloadStatus>,
+    progress: Arc<LoadProgress>,
+}
+
+impl Reloader {
+    pub fn new(
+        storage: Arc<Storage>,
+        wal: Arc<Wal>,
+        options: Options,
+        config: &Config,
+    ) -> Reloader {
+        Reloader {
+            storage: storage,
+            wal: wal,
+            options: options,
+            clock_source: config.clock,
+            options_file: config.options_file.clone(),
+            snapshot_path: config.snapshot_path.clone(),
+            policy: config.load_policy,
+            threads: config.load_threads,
+            integrity: config.integrity_check,
+            default_path: config.input_path(),
+            root: config.reload_root.clone(),
+            status: Mutex::new(ReloadStatus {
+                state: ReloadState::Idle,
+                source: None,
+                files: 0,
+                files_loaded: 0,
+                millis: None,
+                replayed_mutations: 0,
+                discarded_mutations: 0,
+                error: None,
+            }),
+            progress: Arc::new(LoadProgress::default()),
+        }
+    }
+
+    pub fn status(&self) -> ReloadStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        if status.state == ReloadState::Loading {
+            status.files = self.progress.files.load(Ordering::SeqCst);
+            status.files_loaded = self.progress.merged.load(Ordering::SeqCst);
+        }
+        status
+    }
+
+    /// `relative` under the directory reloads may read from, or `None` if
+    /// it would leave it.
+    pub fn source_path(&self, relative: &str) -> Option<PathBuf> {
+        util::path_under(&self.root, relative)
+    }
+
+    // Whether the data files the server started with still hold all of its
+    // data, so reading them again loses nothing.
+    fn default_is_current(&self, path: &Path) -> bool {
+        let wal_is_empty = self.wal.is_empty().unwrap_or(false);
+        wal_is_empty && !snapshot::is_newer_than_input(&self.snapshot_path, Some(path))
+    }
+
+    // Loads `path` with the options file next to it, and swaps it in. The
+    // mutations the WAL captured in the meantime are made to the new data
+    // under the WAL lock, then the snapshot is replaced and the log emptied.
+    // Returns how many of those mutations applied to the new data and how
+    // many didn't.
+    fn reload(&self, path: &Path) -> Result<(usize, usize), Box<Error>> {
+        let options_dir = if path.is_dir() {
+            path
+        } else {
+            path.parent().unwrap_or_else(|| Path::new(""))
+        };
+        let options = read_options(&options_dir.join(&self.options_file), self.clock_source)?;
+        if options.mode != self.options.mode {
+            let message = format!(
+                "the new data is for {:?} mode, but the server runs in {:?} mode",
+                options.mode,
+                self.options.mode
+            );
+            return Err(message.into());
+        }
+
+        let source = loader::open_source(path)?;
+        let (storage, report) = loader::load_with_progress(
+            source,
+            &options,
+            self.policy,
+            self.threads,
+            &self.progress,
+        )?;
+        report.print();
+        if let Err(error) = integrity::enforce(self.integrity, &storage.read()) {
+            return Err(format!("the new data fails the integrity check: {}", error).into());
+        }
+        if options.mode.warms_up() {
+            status::warm_up(&storage.read(), options.now());
+        }
+        let now = options.now();
+        let counts = self.wal.checkpoint_captured(|mutations| {
+            let total = mutations.len();
+            let mut replayed = 0;
+            for mutation in mutations {
+                let description = format!("{:?}", mutation);
+                match wal::apply(&storage, &options, mutation) {
+                    Outcome::Applied => replayed += 1,
+                    outcome => {
+                        println!("Discarded {} made while reloading: {:?}", description, outcome)
+                    }
+                }
+            }
+            snapshot::write_snapshot(&self.snapshot_path, &storage, now)?;
+            self.options.clock.set_now(now);
+            self.storage.replace(storage.read());
+            Ok((replayed, total - replayed))
+        })?;
+        Ok(counts)
+    }
+}
+
+// Stops the WAL capturing mutations when the reload it was for ends, however
+// it ends.
+struct Capture<'a>(&'a Wal);
+
+impl<'a> Drop for Capture<'a> {
+    fn drop(&mut self) {
+        self.0.stop_capture();
+    }
+}
+
+/// Starts reloading from `path`, or from where the server loaded its data if
+/// there's none. That's refused if the server's data has changed since it was
+/// loaded, unless `discard` is set.
+pub fn start(
+    reloader: &Arc<Reloader>,
+    path: Option<PathBuf>,
+    discard: bool,
+) -> Result<ReloadStatus, StartError> {
+    let path = match path {
+        Some(path) => path,
+        None => match reloader.default_path.clone() {
+            Some(ref path) if !discard && !reloader.default_is_current(path) => {
+                return Err(StartError::WouldDiscard)
+            }
+            Some(path) => path,
+            None => return Err(StartError::NoSource),
+        },
+    };
+    {
+        let mut status = reloader.status.lock().unwrap();
+        if status.state == ReloadState::Loading {
+            return Err(StartError::InProgress);
+        }
+        reloader.progress.files.store(0, Ordering::SeqCst);
+        reloader.progress.merged.store(0, Ordering::SeqCst);
+        *status = ReloadStatus {
+            state: ReloadState::Loading,
+            source: Some(path.to_string_lossy().into_owned()),
+            files: 0,
+            files_loaded: 0,
+            millis: None,
+            replayed_mutations: 0,
+            discarded_mutations: 0,
+            error: None,
+        };
+        // Before anything is loaded, so no mutation falls between what the
+        // new data holds and what's replayed on top of it.
+        reloader.wal.capture();
+    }
+    println!("Reloading data from {:?}", path);
+
+    let worker = reloader.clone();
+    thread::spawn(move || {
+        let start = Instant::now();
+        let result = {
+            let _capture = Capture(&worker.wal);
+            worker.reload(&path)
+        };
+        let elapsed = start.elapsed();
+
+        let mut status = worker.status.lock().unwrap();
+        status.files = worker.progress.files.load(Ordering::SeqCst);
+        status.files_loaded = worker.progress.merged.load(Ordering::SeqCst);
+        status.millis = Some(elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000);
+        match result {
+            Ok((replayed, discarded)) => {
+                println!(
+                    "Reloaded data from {:?}, replaying {} mutations made while loading \
+                     and discarding {} that didn't apply",
+                    path,
+                    replayed,
+                    discarded
+                );
+                status.state = ReloadState::Done;
+                status.replayed_mutations = replayed;
+                status.discarded_mutations = discarded;
+            }
+            Err(e) => {
+                println!("Failed to reload data from {:?}: {}", path, e);
+                status.state = ReloadState::Failed;
+                status.error = Some(e.to_string());
+            }
+        }
+    });
+    Ok(reloader.status())
+}
+
+static RELOAD_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn request_reload(_signal: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a SIGHUP handler that reloads the data the server started with.
+pub fn reload_on_hangup(reloader: Arc<Reloader>) {
+    unsafe {
+        libc::signal(libc::SIGHUP, request_reload as libc::sighandler_t);
+    }
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_millis(100));
+        if RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            if let Err(e) = start(&reloader, None, false) {
+                println!("Not reloading on SIGHUP: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,391 @@
+use Options;
+use Storage;
+use Tables;
+use clock::Clock;
+use gender::Gender;
+use idmap::IdMap;
+use locations::Location;
+use text::{InlineString, Symbol};
+use users::User;
+use visits::Visit;
+use wal::{self, Wal};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use libc;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Header is the magic, then `version: u32`, `now: i32`, `body length: u64`
+// and `crc32 of body: u32`, all little-endian. Bump `VERSION` on any change
+// to the body layout; snapshots with another version are rejected.
+const MAGIC: &[u8; 8] = b"RUSTLSNP";
+pub const VERSION: u32 = 3;
+
+pub struct SnapshotPath(pub PathBuf);
+
+#[derive(Serialize, Debug)]
+pub struct SnapshotInfo {
+    path: String,
+    users: usize,
+    locations: usize,
+    visits: usize,
+}
+
+/// Writes a snapshot of `storage` and empties the WAL, with mutations blocked
+/// for the duration so the two can't disagree.
+pub fn take_snapshot(
+    path: &Path,
+    storage: &Storage,
+    wal: &Wal,
+    options: &Options,
+) -> io::Result<SnapshotInfo> {
+    wal.checkpoint(|| write_snapshot(path, storage, options.now()))
+}
+
+pub fn write_snapshot(path: &Path, storage: &Storage, now: i32) -> io::Result<SnapshotInfo> {
+    let tables = storage.read();
+    let users = &tables.users;
+    let locations = &tables.locations;
+    let visits = &tables.visits;
+    let location_visits = &tables.location_visits;
+    let user_visits = &tables.user_visits;
+
+    let mut body = Vec::new();
+
+    // The largest id each table has held, so new ids don't reuse deleted ones.
+    write_max_id(&mut body, users.max_id())?;
+    write_max_id(&mut body, locations.max_id())?;
+    write_max_id(&mut body, visits.max_id())?;
+
+    body.write_u32::<LittleEndian>(users.len() as u32)?;
+    for user in users.values() {
+        body.write_u32::<LittleEndian>(user.id)?;
+        write_str(&mut body, &user.email)?;
+        write_str(&mut body, &user.first_name)?;
+        write_str(&mut body, &user.last_name)?;
+        body.write_u8(encode_gender(&user.gender))?;
+        body.write_i32::<LittleEndian>(user.birth_date)?;
+    }
+
+    body.write_u32::<LittleEndian>(locations.len() as u32)?;
+    for location in locations.values() {
+        body.write_u32::<LittleEndian>(location.id)?;
+        write_str(&mut body, &location.place)?;
+        write_str(&mut body, &location.country)?;
+        write_str(&mut body, &location.city)?;
+        body.write_u32::<LittleEndian>(location.distance)?;
+    }
+
+    body.write_u32::<LittleEndian>(visits.len() as u32)?;
+    for visit in visits.values() {
+        body.write_u32::<LittleEndian>(visit.id)?;
+        body.write_u32::<LittleEndian>(visit.location)?;
+        body.write_u32::<LittleEndian>(visit.user)?;
+        body.write_i32::<LittleEndian>(visit.visited_at)?;
+        body.write_u8(visit.mark)?;
+    }
+
+    write_index(&mut body, location_visits)?;
+    write_index(&mut body, user_visits)?;
+
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        file.write_all(MAGIC)?;
+        file.write_u32::<LittleEndian>(VERSION)?;
+        file.write_i32::<LittleEndian>(now)?;
+        file.write_u64::<LittleEndian>(body.len() as u64)?;
+        file.write_u32::<LittleEndian>(wal::crc32(&body))?;
+        file.write_all(&body)?;
+        let file = file.into_inner()?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    Ok(SnapshotInfo {
+        path: path.to_string_lossy().into_owned(),
+        users: users.len(),
+        locations: locations.len(),
+        visits: visits.len(),
+    })
+}
+
+/// Loads a snapshot written by `write_snapshot`. Fails if the file is missing,
+/// has a different format version or doesn't pass the checksum.
+pub fn read_snapshot(path: &Path, options: &Options) -> io::Result<Storage> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let mut header = Cursor::new(&data[..]);
+    let mut magic = [0; 8];
+    header.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a snapshot file".to_owned()));
+    }
+    let version = header.read_u32::<LittleEndian>()?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported snapshot version {} (expected {})",
+            version,
+            VERSION
+        )));
+    }
+    // Nothing in the body depends on the `now` the snapshot was taken at;
+    // ages are worked out against `options.now()` when the indexes are rebuilt.
+    let _now = header.read_i32::<LittleEndian>()?;
+    let body_len = header.read_u64::<LittleEndian>()?;
+    let checksum = header.read_u32::<LittleEndian>()?;
+
+    let body = &data[header.position() as usize..];
+    if body.len() as u64 != body_len {
+        return Err(invalid_data(format!(
+            "snapshot body is {} bytes, header says {}",
+            body.len(),
+            body_len
+        )));
+    }
+    if wal::crc32(body) != checksum {
+        return Err(invalid_data("snapshot checksum mismatch".to_owned()));
+    }
+
+    let mut body = Cursor::new(body);
+
+    let users_max_id = read_max_id(&mut body)?;
+    let locations_max_id = read_max_id(&mut body)?;
+    let visits_max_id = read_max_id(&mut body)?;
+
+    let users_count = body.read_u32::<LittleEndian>()? as usize;
+    let mut all_users = HashMap::with_capacity(users_count);
+    for _ in 0..users_count {
+        let user = User {
+            id: body.read_u32::<LittleEndian>()?,
+            email: read_inline(&mut body)?,
+            first_name: read_inline(&mut body)?,
+            last_name: read_inline(&mut body)?,
+            gender: decode_gender(body.read_u8()?)?,
+            birth_date: body.read_i32::<LittleEndian>()?,
+        };
+        all_users.insert(user.id, user);
+    }
+
+    let locations_count = body.read_u32::<LittleEndian>()? as usize;
+    let mut all_locations = HashMap::with_capacity(locations_count);
+    for _ in 0..locations_count {
+        let location = Location {
+            id: body.read_u32::<LittleEndian>()?,
+            place: Symbol::new(&read_str(&mut body)?),
+            country: read_symbol(&mut body)?,
+            city: read_symbol(&mut body)?,
+            distance: body.read_u32::<LittleEndian>()?,
+        };
+        all_locations.insert(location.id, location);
+    }
+
+    let visits_count = body.read_u32::<LittleEndian>()? as usize;
+    let mut all_visits = HashMap::with_capacity(visits_count);
+    for _ in 0..visits_count {
+        let visit = Visit {
+            id: body.read_u32::<LittleEndian>()?,
+            location: body.read_u32::<LittleEndian>()?,
+            user: body.read_u32::<LittleEndian>()?,
+            visited_at: body.read_i32::<LittleEndian>()?,
+            mark: body.read_u8()?,
+        };
+        all_visits.insert(visit.id, visit);
+    }
+
+    let location_visits = read_index(&mut body)?;
+    let user_visits = read_index(&mut body)?;
+
+    let mut tables = Tables {
+        users: all_users.into(),
+        locations: all_locations.into(),
+        visits: all_visits.into(),
+        visitors: IdMap::new(),
+        location_visits: location_visits.into(),
+        user_visits: user_visits.into(),
+        location_marks: IdMap::new(),
+    };
+    if let Some(id) = users_max_id {
+        tables.users.raise_max_id(id);
+    }
+    if let Some(id) = locations_max_id {
+        tables.locations.raise_max_id(id);
+    }
+    if let Some(id) = visits_max_id {
+        tables.visits.raise_max_id(id);
+    }
+    tables.rebuild_indexes(options.now());
+    Ok(Storage::new(tables))
+}
+
+/// Whether the snapshot at `path` was written after the data at `input` last
+/// changed, so it can stand in for it. Standard input, given as `None`, has
+/// nothing to compare with, and any snapshot will do.
+pub fn is_newer_than_input(path: &Path, input: Option<&Path>) -> bool {
+    let written = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(written) => written,
+        Err(_) => return false,
+    };
+    match input {
+        Some(input) => last_modified(input).map_or(true, |changed| written >= changed),
+        None => true,
+    }
+}
+
+// The latest change to `path`, or to anything straight in it if it's a
+// directory.
+fn last_modified(path: &Path) -> io::Result<SystemTime> {
+    let mut latest = fs::metadata(path)?.modified()?;
+    if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let modified = entry?.metadata()?.modified()?;
+            if modified > latest {
+                latest = modified;
+            }
+        }
+    }
+    Ok(latest)
+}
+
+static SHUTDOWN_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs SIGINT/SIGTERM handlers that write a final snapshot before the
+/// process exits.
+pub fn snapshot_on_shutdown(
+    path: PathBuf,
+    storage: Arc<Storage>,
+    wal: Arc<Wal>,
+    clock: Arc<Clock>,
+) {
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as libc::sighandler_t);
+    }
+
+    thread::spawn(move || {
+        while !SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        println!("Shutting down, writing snapshot to {:?}", path);
+        let result = wal.checkpoint(|| write_snapshot(&path, &storage, clock.now()));
+        match result {
+            Ok(info) => {
+                println!("Snapshot written: {:?}", info);
+                process::exit(0);
+            }
+            Err(e) => {
+                println!("Failed to write snapshot: {}", e);
+                process::exit(1);
+            }
+        }
+    });
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn encode_gender(gender: &Gender) -> u8 {
+    match *gender {
+        Gender::Unknown => 0,
+        Gender::Male => 1,
+        Gender::Female => 2,
+    }
+}
+
+fn decode_gender(value: u8) -> io::Result<Gender> {
+    match value {
+        0 => Ok(Gender::Unknown),
+        1 => Ok(Gender::Male),
+        2 => Ok(Gender::Female),
+        _ => Err(invalid_data(format!("invalid gender tag {}", value))),
+    }
+}
+
+fn write_str<W>(writer: &mut W, value: &str) -> io::Result<()>
+where
+    W: Write,
+{
+    writer.write_u32::<LittleEndian>(value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_str(reader: &mut Cursor<&[u8]>) -> io::Result<String> {
+    let len = reader.read_u32::<LittleEndian>()? as usize;
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn read_inline(reader: &mut Cursor<&[u8]>) -> io::Result<InlineString> {
+    Ok(InlineString::new(&read_str(reader)?))
+}
+
+fn read_symbol(reader: &mut Cursor<&[u8]>) -> io::Result<Symbol> {
+    Ok(Symbol::intern(&read_str(reader)?))
+}
+
+// A presence byte, then the id if there is one.
+fn write_max_id<W>(writer: &mut W, max_id: Option<u32>) -> io::Result<()>
+where
+    W: Write,
+{
+    match max_id {
+        Some(id) => {
+            writer.write_u8(1)?;
+            writer.write_u32::<LittleEndian>(id)
+        }
+        None => writer.write_u8(0),
+    }
+}
+
+fn read_max_id(reader: &mut Cursor<&[u8]>) -> io::Result<Option<u32>> {
+    match reader.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(reader.read_u32::<LittleEndian>()?)),
+        tag => Err(invalid_data(format!("invalid max id tag {}", tag))),
+    }
+}
+
+fn write_index<W>(writer: &mut W, index: &IdMap<Vec<u32>>) -> io::Result<()>
+where
+    W: Write,
+{
+    writer.write_u32::<LittleEndian>(index.len() as u32)?;
+    for (id, visit_ids) in index {
+        writer.write_u32::<LittleEndian>(id)?;
+        writer.write_u32::<LittleEndian>(visit_ids.len() as u32)?;
+        for visit_id in visit_ids {
+            writer.write_u32::<LittleEndian>(*visit_id)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_index(reader: &mut Cursor<&[u8]>) -> io::Result<HashMap<u32, Vec<u32>>> {
+    let count = reader.read_u32::<LittleEndian>()? as usize;
+    let mut index = HashMap::with_capacity(count);
+    for _ in 0..count {
+        let id = reader.read_u32::<LittleEndian>()?;
+        let len = reader.read_u32::<LittleEndian>()? as usize;
+        let mut visit_ids = Vec::with_capacity(len);
+        for _ in 0..len {
+            visit_ids.push(reader.read_u32::<LittleEndian>()?);
+        }
+        index.insert(id, visit_ids);
+    }
+    Ok(index)
+}
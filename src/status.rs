@@ -0,0 +1,102 @@
+use Mode;
+use Options;
+use Storage;
+use Tables;
+use integrity::{self, Scope};
+use marks::MarkQuery;
+use wal::Mutation;
+
+use rocket::fairing::AdHoc;
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::Instant;
+
+// What the mode made the server do, for `/admin/status`.
+static WARM_UP_MILLIS: AtomicUsize = ATOMIC_USIZE_INIT;
+static MUTATIONS_CHECKED: AtomicUsize = ATOMIC_USIZE_INIT;
+static INVARIANT_FAILURES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Reads every entity and index entry once, so the first rated requests
+/// don't pay for page faults. There are no caches to fill. Returns how many
+/// entities and index entries it read.
+pub fn warm_up(tables: &Tables, now: i32) -> usize {
+    let start = Instant::now();
+    let mut read = tables.users.values().count() + tables.locations.values().count() +
+        tables.visits.values().count() + tables.visitors.values().count();
+    for ids in tables.user_visits.values().chain(tables.location_visits.values()) {
+        read += ids.iter().filter(|id| tables.visits.contains_key(id)).count();
+    }
+    for marks in tables.location_marks.values() {
+        marks.total(&MarkQuery::default(), now);
+        read += 1;
+    }
+
+    let elapsed = start.elapsed();
+    let millis = elapsed.as_secs() as usize * 1000 + elapsed.subsec_nanos() as usize / 1_000_000;
+    WARM_UP_MILLIS.store(millis, Ordering::SeqCst);
+    read
+}
+
+/// In test mode, what `mutation` may change, to check once it's applied.
+pub fn scope_to_check(storage: &Storage, options: &Options, mutation: &Mutation) -> Option<Scope> {
+    if options.mode.is_checked() {
+        Some(Scope::of(mutation, &storage.read()))
+    } else {
+        None
+    }
+}
+
+/// Checks the indexes in `scope`, if there's one, and prints whatever doesn't
+/// add up.
+pub fn check_mutation(storage: &Storage, scope: Option<Scope>) {
+    let scope = match scope {
+        Some(scope) => scope,
+        None => return,
+    };
+    let report = integrity::check_scope(&storage.read(), scope);
+    MUTATIONS_CHECKED.fetch_add(1, Ordering::SeqCst);
+    if !report.index_errors.is_empty() {
+        INVARIANT_FAILURES.fetch_add(1, Ordering::SeqCst);
+        println!("Indexes are inconsistent after a mutation:");
+        for error in report.index_errors.iter().take(10) {
+            println!("  {}", error);
+        }
+    }
+}
+
+/// Prints every request and its response status, in test mode.
+pub fn request_logger() -> AdHoc {
+    AdHoc::on_response(|request, response| {
+        println!("{} {} => {}", request.method(), request.uri(), response.status());
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServerStatus {
+    mode: Mode,
+    now: i32,
+    users: usize,
+    locations: usize,
+    visits: usize,
+    /// How long warm-up took, if the mode asked for it.
+    #[serde(skip_serializing_if = "Option::is_none")] warm_up_millis: Option<usize>,
+    mutations_checked: usize,
+    invariant_failures: usize,
+}
+
+pub fn status(tables: &Tables, options: &Options) -> ServerStatus {
+    ServerStatus {
+        mode: options.mode,
+        now: options.now(),
+        users: tables.users.len(),
+        locations: tables.locations.len(),
+        visits: tables.visits.len(),
+        warm_up_millis: if options.mode.warms_up() {
+            Some(WARM_UP_MILLIS.load(Ordering::SeqCst))
+        } else {
+            None
+        },
+        mutations_checked: MUTATIONS_CHECKED.load(Ordering::SeqCst),
+        invariant_failures: INVARIANT_FAILURES.load(Ordering::SeqCst),
+    }
+}
@@ -1,28 +1,115 @@
-use rocket::local::Client;
-use rocket::http::Status;
+use rocket::local::{Client, LocalResponse};
+use rocket::http::{ContentType, Header, Status};
 use super::*;
+use admin::{self, AdminToken};
+use byteorder::{LittleEndian, WriteBytesExt};
+use bench;
+use clock::{self, ClockSource};
+use config::{Config, ConfigError, InputFormat};
+use export;
+use gender::Gender;
+use idmap::{Entry, IdMap};
+use integrity::{self, IntegrityPolicy};
+use loader::{self, DirSource, EntityKind, LoadError, LoadPolicy, TarSource, ZipSource};
+use marks::MarkQuery;
+use reload::{self, ReloadState, ReloadStatus, Reloader};
+use snapshot;
+use status;
+use text::{self, InlineString, Symbol};
+use util::{self, DependentsPolicy};
+use wal::{self, Mutation, Outcome, SyncPolicy};
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::u32;
+
+static TEMP_FILE_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn temp_path(name: &str) -> PathBuf {
+    let index = TEMP_FILE_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let path = env::temp_dir().join(format!("rustler-test-{}-{}", index, name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+fn test_options() -> Options {
+    Options {
+        clock: Arc::new(Clock::fixed(1503695452)),
+        mode: Mode::Test,
+    }
+}
+
+fn input_data(dir: &Path, options: &Options) -> Result<Storage, LoadError> {
+    loader::load_dir(dir, options, LoadPolicy::Strict, 4).map(|(storage, _)| storage)
+}
+
+fn empty_storage(options: &Options) -> Storage {
+    input_data(&PathBuf::from("nonexistent"), options).unwrap()
+}
+
+const ADMIN_TOKEN: &str = "secret";
+
+fn admin_header() -> Header<'static> {
+    Header::new("X-Admin-Token", ADMIN_TOKEN)
+}
 
 fn setup() -> rocket::Rocket {
-    let data = input_data(&PathBuf::from("data")).unwrap();
-    let data_locked = RwLock::new(data);
-    rocket::ignite().manage(data_locked).mount(
-        "/",
-        routes![
-            users,
-            locations,
-            visits,
-            users_visits_no_params,
-            users_visits,
-            locations_avg_no_params,
-            locations_avg,
-            users_update,
-            locations_update,
-            visits_update,
-            users_new,
-            locations_new,
-            visits_new,
-        ],
-    )
+    setup_with_wal(&temp_path("setup.wal"))
+}
+
+fn setup_with_wal(wal_path: &Path) -> rocket::Rocket {
+    let options = test_options();
+    let data = input_data(&PathBuf::from("data"), &options).unwrap();
+    let wal = Wal::open(wal_path, SyncPolicy::Never).unwrap();
+    rocket::ignite()
+        .manage(Arc::new(data))
+        .manage(options)
+        .manage(Arc::new(wal))
+        .manage(AdminToken(Some(ADMIN_TOKEN.to_owned())))
+        .mount(
+            "/",
+            routes![
+                users::users,
+                locations::locations,
+                visits::visits,
+                users::users_visits_no_params,
+                users::users_visits,
+                locations::locations_avg_no_params,
+                locations::locations_avg,
+                users::users_update,
+                locations::locations_update,
+                visits::visits_update,
+                users::users_new,
+                locations::locations_new,
+                visits::visits_new,
+                users::users_list_no_params,
+                users::users_list,
+                locations::locations_list_no_params,
+                locations::locations_list,
+                visits::visits_list_no_params,
+                visits::visits_list,
+                users::users_delete_no_params,
+                users::users_delete,
+                locations::locations_delete_no_params,
+                locations::locations_delete,
+                visits::visits_delete,
+                admin::admin_clock,
+                admin::admin_clock_set,
+                admin::admin_status,
+            ],
+        )
+        .catch(errors![
+            error::bad_request,
+            error::not_found,
+            error::conflict,
+            error::unprocessable_entity,
+            error::internal_error,
+        ])
 }
 
 #[test]
@@ -55,7 +142,7 @@ fn users() {
     let rocket = setup();
     let client = Client::new(rocket).expect("valid rocket instance");
     let response = client.get("/users/").dispatch();
-    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.status(), Status::Ok);
 }
 
 #[test]
@@ -65,3 +152,2284 @@ fn user() {
     let response = client.get("/user/").dispatch();
     assert_eq!(response.status(), Status::NotFound);
 }
+
+fn sample_mutations() -> Vec<Mutation> {
+    vec![
+        Mutation::UserNew(serde_json::from_str(
+            r#"{"id": 1, "email": "a@b.c", "first_name": "A", "last_name": "B",
+                "gender": "m", "birth_date": 0}"#,
+        ).unwrap()),
+        Mutation::LocationNew(serde_json::from_str(
+            r#"{"id": 2, "place": "P", "country": "C", "city": "T", "distance": 10}"#,
+        ).unwrap()),
+        Mutation::VisitNew(serde_json::from_str(
+            r#"{"id": 3, "location": 2, "user": 1, "visited_at": 100, "mark": 4}"#,
+        ).unwrap()),
+        Mutation::VisitUpdate(3, serde_json::from_str(r#"{"mark": 5}"#).unwrap()),
+    ]
+}
+
+#[test]
+fn wal_replay_restores_mutations() {
+    let options = test_options();
+    let path = temp_path("replay.wal");
+    {
+        let storage = empty_storage(&options);
+        let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+        for mutation in sample_mutations() {
+            assert_eq!(
+                wal.commit(&storage, &options, mutation).unwrap(),
+                Outcome::Applied
+            );
+        }
+    }
+
+    let storage = empty_storage(&options);
+    let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(wal.replay(&storage, &options).unwrap(), 4);
+    assert!(storage.read().users.contains_key(&1));
+    assert!(storage.read().locations.contains_key(&2));
+    assert_eq!(storage.read().visits[&3].mark, 5);
+    assert_eq!(storage.read().user_visits[&1], vec![3]);
+    assert_eq!(storage.read().location_visits[&2], vec![3]);
+}
+
+#[test]
+fn wal_replay_truncates_torn_tail() {
+    let options = test_options();
+    let path = temp_path("torn.wal");
+    {
+        let storage = empty_storage(&options);
+        let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+        for mutation in sample_mutations().into_iter().take(2) {
+            wal.commit(&storage, &options, mutation).unwrap();
+        }
+    }
+    let valid_len = fs::metadata(&path).unwrap().len();
+    {
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2, 3]).unwrap();
+    }
+
+    let storage = empty_storage(&options);
+    let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(wal.replay(&storage, &options).unwrap(), 2);
+    assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+    drop(wal);
+
+    // A whole last record with a bad checksum goes the same way.
+    {
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[3, 0, 0, 0, 9, 9, 9, 9, 1, 2, 3]).unwrap();
+    }
+    let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+    assert_eq!(wal.replay(&empty_storage(&options), &options).unwrap(), 2);
+    assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+}
+
+#[test]
+fn wal_replay_fails_on_an_intact_but_undecodable_record() {
+    let options = test_options();
+    let path = temp_path("undecodable.wal");
+    {
+        let mut file = File::create(&path).unwrap();
+        let location = serde_json::to_vec(&sample_mutations().remove(1)).unwrap();
+        let payloads: [&[u8]; 2] = [b"{\"NoSuchMutation\":1}", &location];
+        for payload in payloads.iter() {
+            file.write_u32::<LittleEndian>(payload.len() as u32).unwrap();
+            file.write_u32::<LittleEndian>(wal::crc32(payload)).unwrap();
+            file.write_all(payload).unwrap();
+        }
+    }
+    let len = fs::metadata(&path).unwrap().len();
+
+    let storage = empty_storage(&options);
+    let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+    let error = wal.replay(&storage, &options).unwrap_err();
+    assert!(error.to_string().contains("undecodable"), "{}", error);
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+}
+
+#[test]
+fn wal_replay_fails_on_a_corrupted_record_before_the_tail() {
+    let options = test_options();
+    let path = temp_path("corrupted.wal");
+    {
+        let storage = empty_storage(&options);
+        let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+        for mutation in sample_mutations() {
+            wal.commit(&storage, &options, mutation).unwrap();
+        }
+    }
+    let first = serde_json::to_vec(&sample_mutations().remove(0)).unwrap();
+    let offset = 8 + first.len() as u64;
+    {
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset + 8)).unwrap();
+        file.write_all(b"#").unwrap();
+    }
+    let len = fs::metadata(&path).unwrap().len();
+
+    let storage = empty_storage(&options);
+    let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+    let error = wal.replay(&storage, &options).unwrap_err();
+    assert!(error.to_string().contains(&format!("offset {}", offset)), "{}", error);
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    assert!(wal::replay_read_only(&path, &empty_storage(&options), &options).is_err());
+}
+
+#[test]
+fn crc32_matches_the_check_value() {
+    assert_eq!(wal::crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(wal::crc32(b""), 0);
+}
+
+#[test]
+fn wal_read_only_replay_leaves_the_log_alone() {
+    let options = test_options();
+    let path = temp_path("read-only.wal");
+    {
+        let storage = empty_storage(&options);
+        let wal = Wal::open(&path, SyncPolicy::Always).unwrap();
+        for mutation in sample_mutations().into_iter().take(2) {
+            wal.commit(&storage, &options, mutation).unwrap();
+        }
+    }
+    {
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2, 3]).unwrap();
+    }
+    let len = fs::metadata(&path).unwrap().len();
+
+    let storage = empty_storage(&options);
+    assert_eq!(wal::replay_read_only(&path, &storage, &options).unwrap(), 2);
+    assert!(storage.read().locations.contains_key(&2));
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+    let missing = temp_path("missing.wal");
+    assert_eq!(wal::replay_read_only(&missing, &storage, &options).unwrap(), 0);
+    assert!(!missing.exists());
+}
+
+#[test]
+fn client_paths_stay_under_their_root() {
+    let root = PathBuf::from("export");
+    assert_eq!(util::path_under(&root, "today/run"), Some(root.join("today/run")));
+    assert_eq!(util::path_under(&root, "./today"), Some(root.join("./today")));
+    assert_eq!(util::path_under(&root, "/etc"), None);
+    assert_eq!(util::path_under(&root, "../etc"), None);
+    assert_eq!(util::path_under(&root, "a/../../etc"), None);
+    assert_eq!(util::path_under(&root, ""), None);
+}
+
+#[test]
+fn snapshot_roundtrip() {
+    let options = test_options();
+    let path = temp_path("roundtrip.snapshot");
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    snapshot::write_snapshot(&path, &storage, options.now()).unwrap();
+
+    let restored = snapshot::read_snapshot(&path, &options).unwrap();
+    assert_eq!(
+        restored.read().users.len(),
+        storage.read().users.len()
+    );
+    assert_eq!(
+        restored.read().visits.len(),
+        storage.read().visits.len()
+    );
+    assert_eq!(restored.read().visitors, storage.read().visitors);
+    assert_eq!(
+        restored.read().location_visits,
+        storage.read().location_visits
+    );
+    assert_eq!(
+        restored.read().user_visits,
+        storage.read().user_visits
+    );
+}
+
+#[test]
+fn snapshots_keep_the_ids_deleted_from_the_top() {
+    let options = test_options();
+    let path = temp_path("max-id.snapshot");
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let (top_user, top_visit) = {
+        let tables = storage.read();
+        (tables.users.max_id().unwrap(), tables.visits.max_id().unwrap())
+    };
+    assert_eq!(
+        storage.update(|tables| visits::delete_visit(tables, top_visit)),
+        Outcome::Applied
+    );
+    assert_eq!(
+        storage.update(|tables| users::delete_user(tables, top_user, DependentsPolicy::Cascade)),
+        Outcome::Applied
+    );
+    snapshot::write_snapshot(&path, &storage, options.now()).unwrap();
+
+    let restored = snapshot::read_snapshot(&path, &options).unwrap();
+    let tables = restored.read();
+    assert!(!tables.users.contains_key(&top_user));
+    assert_eq!(util::next_free_id(&tables.users), Ok(top_user + 1));
+    assert_eq!(util::next_free_id(&tables.visits), Ok(top_visit + 1));
+}
+
+#[test]
+fn snapshots_older_than_the_data_are_not_used() {
+    let options = test_options();
+    let dir = data_dir_with(&[("options.txt", "1503695452\n0\n")]);
+    let path = temp_path("stale.snapshot");
+    assert!(!snapshot::is_newer_than_input(&path, Some(&dir)));
+    snapshot::write_snapshot(&path, &empty_storage(&options), options.now()).unwrap();
+    assert!(snapshot::is_newer_than_input(&path, Some(&dir)));
+    assert!(snapshot::is_newer_than_input(&path, None));
+
+    thread::sleep(Duration::from_millis(20));
+    File::create(dir.join("users_1.json"))
+        .unwrap()
+        .write_all(br#"{"users":[]}"#)
+        .unwrap();
+    assert!(!snapshot::is_newer_than_input(&path, Some(&dir)));
+}
+
+#[test]
+fn snapshot_rejects_other_version() {
+    let options = test_options();
+    let path = temp_path("version.snapshot");
+    snapshot::write_snapshot(&path, &empty_storage(&options), options.now()).unwrap();
+    {
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(&[snapshot::VERSION as u8 + 1, 0, 0, 0]).unwrap();
+    }
+
+    assert!(snapshot::read_snapshot(&path, &options).is_err());
+}
+
+fn restart_config(dir: &Path, snapshot_path: &Path, wal_path: &Path, flags: &[&str]) -> Config {
+    let dir = dir.to_string_lossy().into_owned();
+    let snapshot_path = snapshot_path.to_string_lossy().into_owned();
+    let wal_path = wal_path.to_string_lossy().into_owned();
+    let mut args = strings(&[
+        "--data-dir",
+        dir.as_str(),
+        "--snapshot-path",
+        snapshot_path.as_str(),
+        "--wal-path",
+        wal_path.as_str(),
+    ]);
+    args.extend(strings(flags));
+    let (config, _) = Config::load_from(&args, &HashMap::new(), Path::new("missing.toml")).unwrap();
+    config
+}
+
+#[test]
+fn a_stale_snapshot_refuses_to_start_unless_discarded() {
+    let dir = data_dir_with(&[
+        ("options.txt", "1503695452\n0\n"),
+        (
+            "users_1.json",
+            r#"{"users":[{"id":1,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+    ]);
+    let snapshot_path = temp_path("restart.snapshot");
+    let wal_path = temp_path("restart.wal");
+    let config = restart_config(&dir, &snapshot_path, &wal_path, &[]);
+    {
+        let (options, storage, wal) = load(&config).unwrap();
+        snapshot::take_snapshot(&snapshot_path, &storage, &wal, &options).unwrap();
+        let mutation = sample_mutations().remove(1);
+        assert_eq!(wal.commit(&storage, &options, mutation).unwrap(), Outcome::Applied);
+    }
+    {
+        let (_, storage, _) = load(&config).unwrap();
+        assert_eq!(storage.read().locations.len(), 1);
+    }
+
+    thread::sleep(Duration::from_millis(20));
+    File::create(dir.join("users_1.json"))
+        .unwrap()
+        .write_all(br#"{"users":[]}"#)
+        .unwrap();
+    let error = match load(&config) {
+        Err(error) => error.to_string(),
+        Ok(_) => panic!("started over a stale snapshot"),
+    };
+    assert!(error.contains("discard_snapshot"));
+    assert!(wal_path.metadata().unwrap().len() > 0);
+
+    let config = restart_config(&dir, &snapshot_path, &wal_path, &["--discard-snapshot", "true"]);
+    let (_, storage, _) = load(&config).unwrap();
+    assert_eq!(storage.read().users.len(), 0);
+    assert_eq!(storage.read().locations.len(), 0);
+    assert_eq!(wal_path.metadata().unwrap().len(), 0);
+}
+
+#[test]
+fn export_roundtrip() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let dir = temp_path("export");
+    export::export(&dir, &storage, &options, 700, false).unwrap();
+
+    let reloaded = input_data(&dir, &options).unwrap();
+    assert_eq!(
+        reloaded.read().users.len(),
+        storage.read().users.len()
+    );
+    assert_eq!(
+        reloaded.read().locations.len(),
+        storage.read().locations.len()
+    );
+    assert_eq!(
+        reloaded.read().visits.len(),
+        storage.read().visits.len()
+    );
+    let reloaded_options = read_options(&dir.join("options.txt"), ClockSource::Fixed).unwrap();
+    assert_eq!(reloaded_options.now(), options.now());
+}
+
+#[test]
+fn export_zip_roundtrip() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let dir = temp_path("export-zip");
+    export::export(&dir, &storage, &options, export::DEFAULT_CHUNK_SIZE, true).unwrap();
+
+    let source = ZipSource::open(&dir.join("data.zip")).unwrap();
+    let (reloaded, _) = loader::load(Box::new(source), &options, LoadPolicy::Strict, 4).unwrap();
+    assert_eq!(
+        reloaded.read().visits.len(),
+        storage.read().visits.len()
+    );
+}
+
+// The ids of the visits in a `/visits` response, and its cursor.
+fn listed_ids(response: &mut LocalResponse) -> (Vec<u64>, Option<String>) {
+    let page: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let ids = page["visits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|visit| visit["id"].as_u64().unwrap())
+        .collect();
+    (ids, page["next"].as_str().map(|next| next.to_owned()))
+}
+
+// Percent-encodes a cursor for a query string.
+fn encode_cursor(cursor: &str) -> String {
+    cursor
+        .bytes()
+        .map(|byte| match byte {
+            b'0'...b'9' | b'a'...b'z' | b'A'...b'Z' | b'-' | b'_' | b'.' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[test]
+fn visits_list_paginates_with_cursor() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+
+    // User 1's latest visits, newest first, are 35314, 31467, 3181 and 1691.
+    let mut response = client
+        .get("/visits?user=1&sort=visited_at&order=desc&limit=2")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let (first, next) = listed_ids(&mut response);
+    assert_eq!(first, vec![35314, 31467]);
+    let next = next.expect("more than one page");
+
+    let mut response = client
+        .get(format!(
+            "/visits?user=1&sort=visited_at&order=desc&limit=2&after={}",
+            encode_cursor(&next)
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(listed_ids(&mut response).0, vec![3181, 1691]);
+}
+
+#[test]
+fn visits_list_cursor_survives_deletion() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+
+    let mut response = client.get("/visits?limit=3").dispatch();
+    let (first, next) = listed_ids(&mut response);
+    assert_eq!(first, vec![1, 2, 3]);
+    let next = encode_cursor(&next.expect("more than one page"));
+    let response = client.delete("/visits/3").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let mut response = client.get(format!("/visits?limit=3&after={}", next)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(listed_ids(&mut response).0, vec![4, 5, 6]);
+    let mut response = client
+        .get(format!("/visits?limit=3&order=desc&after={}", next))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(listed_ids(&mut response).0, vec![2, 1]);
+}
+
+#[test]
+fn visits_list_cursor_survives_deletion_under_another_sort() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+
+    let query = "/visits?user=1&sort=visited_at&order=desc&limit=2";
+    let mut response = client.get(query).dispatch();
+    let (first, next) = listed_ids(&mut response);
+    assert_eq!(first, vec![35314, 31467]);
+    let next = encode_cursor(&next.expect("more than one page"));
+    // The cursor points at 31467, which is gone by the time it is used.
+    let response = client.delete("/visits/31467").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let mut response = client.get(format!("{}&after={}", query, next)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(listed_ids(&mut response).0, vec![3181, 1691]);
+}
+
+#[test]
+fn visits_list_rejects_a_malformed_cursor() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+
+    let response = client.get("/visits?limit=3&after=3").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn locations_list_rejects_unknown_sort_field() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let response = client.get("/locations?sort=nonsense").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn delete_user_policies() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let (user_id, visit_ids) = {
+        let tables = storage.read();
+        let (id, ids) = tables.user_visits.iter().find(|&(_, ids)| !ids.is_empty()).unwrap();
+        (id, ids.clone())
+    };
+
+    assert_eq!(
+        storage.update(|tables| users::delete_user(tables, user_id, DependentsPolicy::Reject)),
+        Outcome::HasDependents
+    );
+    assert!(storage.read().users.contains_key(&user_id));
+
+    assert_eq!(
+        storage.update(|tables| users::delete_user(tables, user_id, DependentsPolicy::Cascade)),
+        Outcome::Applied
+    );
+    assert!(!storage.read().users.contains_key(&user_id));
+    assert!(!storage.read().visitors.contains_key(&user_id));
+    let tables = storage.read();
+    for visit_id in &visit_ids {
+        assert!(!tables.visits.contains_key(visit_id));
+        assert!(tables.location_visits.values().all(|ids| !ids.contains(visit_id)));
+    }
+}
+
+#[test]
+fn deleted_ids_with_orphans_are_not_reused() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let user = {
+        let tables = storage.read();
+        let (id, _) = tables.user_visits.iter().find(|&(_, ids)| !ids.is_empty()).unwrap();
+        tables.users[&id].clone()
+    };
+
+    assert_eq!(
+        storage.update(|tables| users::delete_user(tables, user.id, DependentsPolicy::Orphan)),
+        Outcome::Applied
+    );
+    assert_eq!(
+        storage.update(|tables| users::insert_user(tables, &options, user.clone())),
+        Outcome::HasOrphans
+    );
+    assert!(!storage.read().users.contains_key(&user.id));
+}
+
+#[test]
+fn cascading_a_deleted_id_clears_its_orphans() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let (user, visit_ids) = {
+        let tables = storage.read();
+        let (id, ids) = tables.user_visits.iter().find(|&(_, ids)| !ids.is_empty()).unwrap();
+        (tables.users[&id].clone(), ids.clone())
+    };
+
+    storage.update(|tables| users::delete_user(tables, user.id, DependentsPolicy::Orphan));
+    assert_eq!(
+        storage.update(|tables| users::delete_user(tables, user.id, DependentsPolicy::Reject)),
+        Outcome::NotFound
+    );
+    assert_eq!(
+        storage.update(|tables| users::delete_user(tables, user.id, DependentsPolicy::Cascade)),
+        Outcome::Applied
+    );
+    assert!(visit_ids.iter().all(|id| !storage.read().visits.contains_key(id)));
+    assert_eq!(
+        storage.update(|tables| users::insert_user(tables, &options, user.clone())),
+        Outcome::Applied
+    );
+    assert_eq!(
+        storage.update(|tables| users::delete_user(tables, 0, DependentsPolicy::Cascade)),
+        Outcome::NotFound
+    );
+}
+
+#[test]
+fn orphaned_visits_are_skipped() {
+    let user_id = {
+        let data = input_data(&PathBuf::from("data"), &test_options()).unwrap();
+        let first_visit_id = data.read().location_visits[&1][0];
+        let user_id = data.read().visits[&first_visit_id].user;
+        user_id
+    };
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+
+    let response = client.delete("/locations/1?dependents=orphan").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get("/locations/1").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client.get(format!("/users/{}/visits", user_id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let mut response = client
+        .post("/locations/new")
+        .header(ContentType::JSON)
+        .body(r#"{"id":1,"place":"P","country":"C","city":"T","distance":10}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+    assert!(response.body_string().unwrap().contains("\"code\":\"has_orphans\""));
+}
+
+#[test]
+fn delete_visit() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let response = client.delete("/visits/1").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get("/visits/1").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client.delete("/visits/1").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn visits_new_rejects_missing_user() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let mut response = client
+        .post("/visits/new")
+        .header(ContentType::JSON)
+        .body(r#"{"id":999999,"location":1,"user":999999,"visited_at":1000000000,"mark":3}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body = response.body_string().unwrap();
+    assert!(body.contains("\"code\":\"missing_reference\""));
+    assert!(body.contains("\"field\":\"user\""));
+    let response = client.get("/visits/999999").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn refused_visit_writes_are_not_logged() {
+    let wal_path = temp_path("refused.wal");
+    let client = Client::new(setup_with_wal(&wal_path)).expect("valid rocket instance");
+    let response = client
+        .post("/visits/new")
+        .header(ContentType::JSON)
+        .body(r#"{"location":999999,"user":1,"visited_at":1000000000,"mark":3}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = client
+        .post("/visits/1?query_id=1")
+        .header(ContentType::JSON)
+        .body(r#"{"user":999999}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = client
+        .post("/visits/999999?query_id=1")
+        .header(ContentType::JSON)
+        .body(r#"{"mark":2}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+
+    let response = client
+        .post("/visits/1?query_id=1")
+        .header(ContentType::JSON)
+        .body(r#"{"mark":2}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(fs::metadata(&wal_path).unwrap().len() > 0);
+}
+
+#[test]
+fn integrity_check_reports_dangling_references() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    assert!(integrity::check(&storage.read()).is_clean());
+
+    assert_eq!(
+        storage.update(|tables| locations::delete_location(tables, 1, DependentsPolicy::Orphan)),
+        Outcome::Applied
+    );
+    let report = integrity::check(&storage.read());
+    assert!(!report.dangling.is_empty());
+    assert!(report.dangling.iter().all(|d| d.field == "location" && d.id == 1));
+    assert!(report.is_consistent());
+}
+
+#[test]
+fn strict_integrity_policy_refuses_dangling_references() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    assert!(integrity::enforce(IntegrityPolicy::Strict, &storage.read()).is_ok());
+
+    storage.update(|tables| locations::delete_location(tables, 1, DependentsPolicy::Orphan));
+    assert!(integrity::enforce(IntegrityPolicy::Reject, &storage.read()).is_ok());
+    assert_eq!(
+        integrity::enforce(IntegrityPolicy::Strict, &storage.read()),
+        Err("visits reference missing users or locations".to_owned())
+    );
+    assert_eq!(IntegrityPolicy::parse("strict"), Some(IntegrityPolicy::Strict));
+
+    // Broken indexes are named as such, whatever else is wrong.
+    let user = storage.read().visits[&1].user;
+    storage.update(|tables| tables.user_visits.get_mut(&user).unwrap().push(999_999));
+    for &policy in &[IntegrityPolicy::Reject, IntegrityPolicy::Strict] {
+        assert_eq!(
+            integrity::enforce(policy, &storage.read()),
+            Err("the indexes don't agree with the visits".to_owned())
+        );
+    }
+    assert!(integrity::enforce(IntegrityPolicy::Report, &storage.read()).is_ok());
+}
+
+#[test]
+fn scoped_integrity_check_looks_at_what_a_mutation_touched() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let visit = storage.read().visits[&1].clone();
+    let other_user = storage.read().users.keys().find(|&id| id != visit.user).unwrap();
+    // Visits that aren't there sort first, so this leaves the timeline in order.
+    storage.update(|tables| tables.user_visits.get_mut(&visit.user).unwrap().insert(0, 999_999));
+
+    let mutation = Mutation::VisitDelete(visit.id);
+    let scope = integrity::Scope::of(&mutation, &storage.read());
+    let report = integrity::check_scope(&storage.read(), scope);
+    assert_eq!(report.index_errors.len(), 1, "{:?}", report);
+    assert!(report.index_errors[0].contains("999999"));
+
+    let update = serde_json::from_str(r#"{"first_name": "Other"}"#).unwrap();
+    let mutation = Mutation::UserUpdate(other_user, update);
+    let scope = integrity::Scope::of(&mutation, &storage.read());
+    assert!(integrity::check_scope(&storage.read(), scope).is_clean());
+}
+
+#[test]
+fn integrity_check_recounts_marks_and_checks_timeline_order() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    assert!(integrity::check(&storage.read()).is_clean());
+    let user = storage.read().user_visits.iter().find(|&(_, ids)| ids.len() > 1).unwrap().0;
+    let visit = storage.read().visits[&1].clone();
+
+    storage.update(|tables| tables.user_visits.get_mut(&user).unwrap().reverse());
+    let report = integrity::check(&storage.read());
+    let unordered = report.index_errors.iter().any(|error| error.starts_with("user_visits"));
+    assert!(unordered, "{:?}", report);
+    let mutation = Mutation::UserDelete(user, DependentsPolicy::Reject);
+    let scope = integrity::Scope::of(&mutation, &storage.read());
+    assert!(!integrity::check_scope(&storage.read(), scope).is_consistent());
+    storage.update(visits::sort_timelines);
+
+    storage.update(|tables| tables.location_marks.remove(&visit.location));
+    let report = integrity::check(&storage.read());
+    assert_eq!(report.index_errors.len(), 1, "{:?}", report);
+    assert!(report.index_errors[0].starts_with("location_marks"));
+    let scope = integrity::Scope::of(&Mutation::VisitDelete(visit.id), &storage.read());
+    assert!(!integrity::check_scope(&storage.read(), scope).is_consistent());
+}
+
+#[test]
+fn cascading_a_location_delete_drops_its_marks() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let location = storage.read().visits[&1].location;
+    assert!(storage.read().location_marks.contains_key(&location));
+
+    storage.update(|tables| {
+        locations::delete_location(tables, location, DependentsPolicy::Cascade)
+    });
+    assert!(!storage.read().location_marks.contains_key(&location));
+    assert!(integrity::check(&storage.read()).is_clean());
+}
+
+#[test]
+fn timestamps_are_checked_against_the_clock() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/visits/new")
+        .header(ContentType::JSON)
+        .body(r#"{"id":999999,"location":1,"user":1,"visited_at":1450000000,"mark":3}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+
+    let mut response = client
+        .post("/visits/new")
+        .header(ContentType::JSON)
+        .body(r#"{"id":999998,"location":1,"user":1,"visited_at":1503695453,"mark":3}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(response.body_string().unwrap().contains("\"field\":\"visited_at\""));
+
+    let mut response = client
+        .post("/users/1")
+        .header(ContentType::JSON)
+        .body(r#"{"birth_date":1600000000}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(response.body_string().unwrap().contains("\"field\":\"birth_date\""));
+}
+
+#[test]
+fn users_new_reports_violations() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let mut response = client
+        .post("/users/new")
+        .header(ContentType::JSON)
+        .body(
+            r#"{"id":999999,"email":"not an email","first_name":null,"last_name":"Smith","gender":"x","birth_date":1}"#,
+        )
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["code"], "invalid_fields");
+    let mut fields: Vec<_> = body["violations"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v["field"].as_str().unwrap().to_owned())
+        .collect();
+    fields.sort();
+    assert_eq!(fields, vec!["email", "first_name", "gender"]);
+    let response = client.get("/users/999999").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn visits_update_rejects_out_of_range_mark() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/visits/1?query_id=1")
+        .header(ContentType::JSON)
+        .body(r#"{"mark":7}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = client
+        .post("/visits/1?query_id=1")
+        .header(ContentType::JSON)
+        .body(r#"{"mark":5}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn user_updates_rewriting_the_same_values_keep_the_visitor() {
+    let storage = input_data(&PathBuf::from("data"), &test_options()).unwrap();
+    let user = storage.read().users[&1].clone();
+    let visitor = storage.read().visitors[&1].clone();
+    // Ten years on, rebuilding the visitor would give it another age.
+    let later = Options {
+        clock: Arc::new(Clock::fixed(1503695452 + 10 * 365 * 86400)),
+        mode: Mode::Test,
+    };
+
+    let update = format!(
+        r#"{{"gender":{},"birth_date":{}}}"#,
+        serde_json::to_string(&user.gender).unwrap(),
+        user.birth_date
+    );
+    let update = serde_json::from_str(&update).unwrap();
+    assert_eq!(
+        storage.update(|tables| users::update_user(tables, &later, 1, update)),
+        Outcome::Applied
+    );
+    assert_eq!(storage.read().visitors[&1], visitor);
+
+    let update = format!(r#"{{"birth_date":{}}}"#, user.birth_date + 1);
+    let update = serde_json::from_str(&update).unwrap();
+    storage.update(|tables| users::update_user(tables, &later, 1, update));
+    assert!(storage.read().visitors[&1] != visitor);
+}
+
+#[test]
+fn updates_can_write_zero_values() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+
+    let update = serde_json::from_str(r#"{"birth_date":0}"#).unwrap();
+    assert_eq!(
+        storage.update(|tables| users::update_user(tables, &options, 1, update)),
+        Outcome::Applied
+    );
+    assert_eq!(storage.read().users[&1].birth_date, 0);
+    assert_eq!(
+        storage.read().visitors[&1].age_at(options.now()),
+        users::calculate_age_from_timestamp(0, options.now())
+    );
+
+    let update = serde_json::from_str(r#"{"distance":0}"#).unwrap();
+    assert_eq!(
+        storage.update(|tables| locations::update_location(tables, 1, update)),
+        Outcome::Applied
+    );
+    assert_eq!(storage.read().locations[&1].distance, 0);
+
+    let location = storage.read().visits[&1].location;
+    let update = serde_json::from_str(&format!(r#"{{"mark":0,"location":{}}}"#, location)).unwrap();
+    assert_eq!(
+        storage.update(|tables| visits::update_visit(tables, 1, update)),
+        Outcome::Applied
+    );
+    assert_eq!(storage.read().visits[&1].mark, 0);
+    let tables = storage.read();
+    assert_eq!(tables.location_visits[&location].iter().filter(|&&id| id == 1).count(), 1);
+}
+
+#[test]
+fn updates_reject_null() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let response = client
+        .post("/locations/1?query_id=1")
+        .header(ContentType::JSON)
+        .body(r#"{"distance":null}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn errors_have_json_bodies() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+
+    let mut response = client.get("/users/999999").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["code"], "not_found");
+
+    let mut response = client.get("/user/").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["code"], "not_found");
+
+    let mut response = client
+        .post("/locations/new")
+        .header(ContentType::JSON)
+        .body("{not json")
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["code"], "malformed_json");
+}
+
+#[test]
+fn create_returns_created_or_conflict() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let location = r#"{"id":999999,"place":"Pier","country":"Chile","city":"Arica","distance":0}"#;
+
+    let mut response = client
+        .post("/locations/new")
+        .header(ContentType::JSON)
+        .body(location)
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    assert_eq!(response.headers().get_one("Location"), Some("/locations/999999"));
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["distance"], 0);
+
+    let mut response = client
+        .post("/locations/new")
+        .header(ContentType::JSON)
+        .body(location)
+        .dispatch();
+    assert_eq!(response.status(), Status::Conflict);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(body["code"], "already_exists");
+    assert_eq!(body["existing"], "/locations/999999");
+}
+
+#[test]
+fn create_assigns_id_when_omitted() {
+    let rocket = setup();
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let mut response = client
+        .post("/locations/new")
+        .header(ContentType::JSON)
+        .body(r#"{"place":"Pier","country":"Chile","city":"Arica","distance":3}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Created);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let id = body["id"].as_u64().unwrap();
+    assert_eq!(
+        response.headers().get_one("Location"),
+        Some(format!("/locations/{}", id).as_str())
+    );
+
+    let data = input_data(&PathBuf::from("data"), &test_options()).unwrap();
+    let max_id = data.read().locations.keys().max().unwrap();
+    assert_eq!(id, max_id as u64 + 1);
+}
+
+#[test]
+fn next_free_id_never_reuses_or_wraps() {
+    let mut ids = IdMap::new();
+    assert_eq!(util::next_free_id(&ids), Ok(1));
+    ids.insert(5, ());
+    ids.insert(9, ());
+    ids.remove(&9);
+    assert_eq!(util::next_free_id(&ids), Ok(10));
+    if let Entry::Vacant(e) = ids.entry(20) {
+        e.insert(());
+    }
+    assert_eq!(util::next_free_id(&ids), Ok(21));
+
+    ids.insert(u32::MAX, ());
+    assert_eq!(util::next_free_id(&ids), Err(Outcome::IdsExhausted));
+}
+
+// xorshift32, enough to scatter the stress tests' writes.
+fn next_random(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+fn stress_storage(options: &Options, entities: u32, visits: u32) -> Storage {
+    let storage = empty_storage(options);
+    for id in 1..entities + 1 {
+        let user = format!(
+            r#"{{"id":{},"email":"u{}@x.y","first_name":"F","last_name":"L","gender":"f","birth_date":0}}"#,
+            id,
+            id
+        );
+        let location = format!(
+            r#"{{"id":{},"place":"P","country":"C","city":"T","distance":{}}}"#,
+            id,
+            id
+        );
+        wal::apply(&storage, options, Mutation::UserNew(serde_json::from_str(&user).unwrap()));
+        wal::apply(
+            &storage,
+            options,
+            Mutation::LocationNew(serde_json::from_str(&location).unwrap()),
+        );
+    }
+    for id in 1..visits + 1 {
+        let visit = format!(
+            r#"{{"id":{},"location":{},"user":{},"visited_at":{},"mark":3}}"#,
+            id,
+            id % entities + 1,
+            id % entities + 1,
+            id
+        );
+        wal::apply(&storage, options, Mutation::VisitNew(serde_json::from_str(&visit).unwrap()));
+    }
+    storage
+}
+
+#[test]
+fn readers_never_see_torn_writes() {
+    const ENTITIES: u32 = 50;
+    const VISITS: u32 = 500;
+    let options = Arc::new(test_options());
+    let storage = Arc::new(stress_storage(&options, ENTITIES, VISITS));
+    let wal = Arc::new(Wal::open(&temp_path("stress.wal"), SyncPolicy::Never).unwrap());
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let storage = storage.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut checks = 0;
+                while !stop.load(Ordering::SeqCst) {
+                    let report = integrity::check(&storage.read());
+                    assert!(report.is_clean(), "{:?}", report);
+                    checks += 1;
+                    thread::yield_now();
+                }
+                checks
+            })
+        })
+        .collect();
+
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let storage = storage.clone();
+            let options = options.clone();
+            let wal = wal.clone();
+            thread::spawn(move || {
+                let mut state = 0x9E37_79B9 ^ (writer + 1);
+                for _ in 0..500 {
+                    let visit = next_random(&mut state) % VISITS + 1;
+                    let user = next_random(&mut state) % ENTITIES + 1;
+                    let location = next_random(&mut state) % ENTITIES + 1;
+                    let update = format!(r#"{{"user":{},"location":{}}}"#, user, location);
+                    let mutation =
+                        Mutation::VisitUpdate(visit, serde_json::from_str(&update).unwrap());
+                    wal.commit(&storage, &options, mutation).unwrap();
+
+                    // Delete and recreate a visit, so readers also race
+                    // against entries disappearing from all three maps.
+                    let visit = next_random(&mut state) % VISITS + 1;
+                    let existing = storage.read().visits.get(&visit).cloned();
+                    if let Some(existing) = existing {
+                        wal.commit(&storage, &options, Mutation::VisitDelete(visit)).unwrap();
+                        wal.commit(&storage, &options, Mutation::VisitNew(existing)).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    stop.store(true, Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.join().unwrap() > 0);
+    }
+
+    let tables = storage.read();
+    assert!(integrity::check(&tables).is_clean());
+    assert_eq!(tables.visits.len(), VISITS as usize);
+}
+
+#[test]
+fn readers_keep_their_version() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let before = storage.read();
+    let mark = before.visits[&1].mark;
+
+    let update = serde_json::from_str(&format!(r#"{{"mark":{}}}"#, (mark + 1) % 6)).unwrap();
+    wal::apply(&storage, &options, Mutation::VisitUpdate(1, update));
+    wal::apply(&storage, &options, Mutation::VisitDelete(2));
+
+    assert_eq!(before.visits[&1].mark, mark);
+    assert!(before.visits.contains_key(&2));
+    let after = storage.read();
+    assert_eq!(after.visits[&1].mark, (mark + 1) % 6);
+    assert!(!after.visits.contains_key(&2));
+    assert_eq!(after.visits.len(), before.visits.len() - 1);
+}
+
+#[test]
+fn bench_measures_both_stores() {
+    let options = test_options();
+    let storage = stress_storage(&options, 10, 100);
+    let config = bench::BenchConfig {
+        readers: 2,
+        writers: 1,
+        duration: Duration::from_millis(200),
+    };
+    let results = bench::run(storage, options, &config);
+    assert_eq!(results.len(), 4);
+    for result in &results[..2] {
+        assert!(result.reads_per_sec > 0.0, "{:?}", result);
+    }
+    for result in results {
+        assert!(result.writes_per_sec > 0.0, "{:?}", result);
+    }
+}
+
+#[test]
+fn id_map_handles_dense_and_sparse_ids() {
+    let mut map = IdMap::new();
+    let ids = [1, 2, 1023, 1024, 70_000, 4_000_000_000, u32::max_value()];
+    for &id in ids.iter() {
+        assert_eq!(map.insert(id, id as u64 * 2), None);
+    }
+    assert_eq!(map.len(), ids.len());
+
+    let snapshot = map.clone();
+    assert_eq!(map.insert(1024, 0), Some(2048));
+    assert_eq!(map.remove(&4_000_000_000), Some(8_000_000_000));
+    assert_eq!(map.remove(&3), None);
+    match map.entry(5) {
+        Entry::Vacant(e) => {
+            e.insert(10);
+        }
+        Entry::Occupied(_) => panic!("5 was never inserted"),
+    }
+    *map.entry(u32::max_value()).or_insert_with(|| 0) += 1;
+
+    assert_eq!(map.len(), ids.len());
+    assert_eq!(map.get(&1024), Some(&0));
+    assert_eq!(map.get(&5), Some(&10));
+    assert_eq!(map[&u32::max_value()], u32::max_value() as u64 * 2 + 1);
+    assert!(!map.contains_key(&4_000_000_000));
+
+    assert_eq!(snapshot.len(), ids.len());
+    assert_eq!(snapshot.get(&1024), Some(&2048));
+    assert!(snapshot.contains_key(&4_000_000_000));
+    assert!(!snapshot.contains_key(&5));
+
+    let dense: Vec<u32> = map.keys().take(5).collect();
+    assert_eq!(dense, vec![1, 2, 5, 1023, 1024]);
+
+    map.entry(4_000_000_001).or_insert_with(|| 1);
+    assert_eq!(map.len(), ids.len() + 1);
+    assert_eq!(map.len(), map.iter().count());
+    assert_eq!(snapshot.len(), snapshot.iter().count());
+}
+
+#[test]
+fn hash_map_estimate_models_robin_hood_tables() {
+    let mut map: IdMap<Vec<u32>> = IdMap::new();
+    assert_eq!(map.memory_usage().hash_map_bytes, 0);
+    map.insert(1, Vec::new());
+    // Even one entry gets the minimum of 32 buckets, each a hash and a pair.
+    let bucket = mem::size_of::<usize>() + mem::size_of::<(u32, Vec<u32>)>();
+    assert_eq!(map.memory_usage().hash_map_bytes, 32 * bucket);
+    for id in 0..100 {
+        map.insert(id, Vec::new());
+    }
+    // 100 entries at a load factor of 10/11 need 128 buckets.
+    assert_eq!(map.memory_usage().hash_map_bytes, 128 * bucket);
+}
+
+#[test]
+fn memory_report_compares_with_hash_maps() {
+    let storage = input_data(&PathBuf::from("data"), &test_options()).unwrap();
+    let tables = storage.read();
+    let report = admin::memory_report(&tables);
+    assert_eq!(report.users.entries, tables.users.len());
+    assert_eq!(report.visits.entries, tables.visits.len());
+    assert!(report.total.bytes > 0);
+    assert!(report.visits.bytes < report.visits.hash_map_bytes, "{:?}", report.visits);
+}
+
+#[test]
+fn location_strings_are_interned() {
+    let storage = input_data(&PathBuf::from("data"), &test_options()).unwrap();
+    let tables = storage.read();
+    let mut locations = tables.locations.values();
+    let first = locations.next().unwrap();
+    let same_country = locations.find(|l| l.country == first.country).unwrap();
+    assert_eq!(first.country.as_str().as_ptr(), same_country.country.as_str().as_ptr());
+    assert_eq!(Symbol::intern(&first.country).as_str().as_ptr(), first.country.as_ptr());
+}
+
+#[test]
+fn only_stored_location_strings_are_interned() {
+    let country: Symbol = serde_json::from_str(r#""Refusedland""#).unwrap();
+    assert!(!country.is_interned());
+
+    let location: Location = serde_json::from_str(
+        r#"{"id":999999,"place":"Pier","country":"Storedland","city":"Arica","distance":0}"#,
+    ).unwrap();
+    assert!(!location.country.is_interned());
+    let storage = empty_storage(&test_options());
+    assert_eq!(
+        storage.update(|tables| locations::insert_location(tables, location)),
+        Outcome::Applied
+    );
+    let tables = storage.read();
+    let location = &tables.locations[&999999];
+    assert!(location.country.is_interned());
+    assert!(location.city.is_interned());
+    assert!(!location.place.is_interned());
+}
+
+#[test]
+fn symbols_nothing_uses_are_pruned() {
+    let kept = Symbol::intern("kept through pruning");
+    for i in 0..5000 {
+        Symbol::intern(&format!("dropped {}", i));
+    }
+    assert!(kept.is_interned());
+    // Less than the table's own overhead for all 5000 strings.
+    let entry = mem::size_of::<u64>() + mem::size_of::<Arc<str>>();
+    assert!(text::interned_bytes() < 5000 * entry);
+}
+
+#[test]
+fn long_names_fall_back_to_the_heap() {
+    let name = "Ж".repeat(text::INLINE_CAPACITY / 2);
+    assert_eq!(InlineString::new(&name).heap_size(), 0);
+    assert_eq!(mem::size_of::<InlineString>(), 24);
+    let name = "日".repeat(50);
+    assert_eq!(InlineString::new(&name).heap_size(), name.len());
+    assert_eq!(InlineString::new(&name).as_str(), name);
+
+    let client = Client::new(setup()).unwrap();
+    // 50 characters, but 150 bytes.
+    let body = format!(r#"{{"first_name":"{}"}}"#, name);
+    let response = client
+        .post("/users/1")
+        .header(ContentType::JSON)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let mut response = client.get("/users/1").dispatch();
+    let user: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!(user["first_name"], name);
+}
+
+#[test]
+fn an_over_long_email_is_reported_once() {
+    let client = Client::new(setup()).unwrap();
+    let body = format!(r#"{{"email":"{}@example.com"}}"#, "a".repeat(100));
+    let mut response = client
+        .post("/users/1")
+        .header(ContentType::JSON)
+        .body(body)
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    let violations = body["violations"].as_array().unwrap();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0]["field"], "email");
+}
+
+// Filters and sorts every visit, the way `GET /users/<id>/visits` used to.
+fn naive_user_visits(tables: &Tables, user: u32, from: Option<i32>, to: Option<i32>) -> Vec<u32> {
+    let mut visits: Vec<&Visit> = tables
+        .visits
+        .values()
+        .filter(|v| v.user == user)
+        .filter(|v| from.map_or(true, |from| from < v.visited_at))
+        .filter(|v| to.map_or(true, |to| to > v.visited_at))
+        .collect();
+    visits.sort_by_key(|v| (v.visited_at, v.id));
+    visits.iter().map(|v| v.id).collect()
+}
+
+#[test]
+fn user_visits_stay_ordered_by_time() {
+    const ENTITIES: u32 = 10;
+    const VISITS: u32 = 200;
+    let options = test_options();
+    let storage = stress_storage(&options, ENTITIES, VISITS);
+    let mut state = 0x2545_F491;
+    let mut next_id = VISITS + 1;
+
+    for round in 0..500 {
+        let visited_at = (next_random(&mut state) % 100) as i32;
+        let user = next_random(&mut state) % ENTITIES + 1;
+        let mutation = match round % 5 {
+            0 => {
+                next_id += 1;
+                let visit = format!(
+                    r#"{{"id":{},"location":1,"user":{},"visited_at":{},"mark":0}}"#,
+                    next_id,
+                    user,
+                    visited_at
+                );
+                Mutation::VisitNew(serde_json::from_str(&visit).unwrap())
+            }
+            1 => Mutation::VisitDelete(next_random(&mut state) % next_id + 1),
+            _ => {
+                let update = match round % 3 {
+                    0 => format!(r#"{{"visited_at":{}}}"#, visited_at),
+                    1 => format!(r#"{{"user":{}}}"#, user),
+                    _ => format!(r#"{{"user":{},"visited_at":{}}}"#, user, visited_at),
+                };
+                let id = next_random(&mut state) % next_id + 1;
+                Mutation::VisitUpdate(id, serde_json::from_str(&update).unwrap())
+            }
+        };
+        wal::apply(&storage, &options, mutation);
+
+        let tables = storage.read();
+        let user = next_random(&mut state) % ENTITIES + 1;
+        let ids = tables.user_visits.get(&user).cloned().unwrap_or_default();
+        assert_eq!(ids, naive_user_visits(&tables, user, None, None));
+        for _ in 0..5 {
+            let from = (next_random(&mut state) % 110) as i32 - 5;
+            let to = (next_random(&mut state) % 110) as i32 - 5;
+            let (from, to) = match next_random(&mut state) % 4 {
+                0 => (None, None),
+                1 => (Some(from), None),
+                2 => (None, Some(to)),
+                _ => (Some(from), Some(to)),
+            };
+            assert_eq!(
+                visits::timeline_range(&tables.visits, &ids, from, to).to_vec(),
+                naive_user_visits(&tables, user, from, to),
+                "user {} from {:?} to {:?}",
+                user,
+                from,
+                to
+            );
+        }
+    }
+}
+
+fn naive_marks(tables: &Tables, location: u32, query: &MarkQuery, now: i32) -> (u64, usize) {
+    let age_of = |user: &User| users::calculate_age_from_timestamp(user.birth_date, now);
+    let visits = tables
+        .visits
+        .values()
+        .filter(|v| v.location == location)
+        .filter(|v| query.from_date.map_or(true, |from| from < v.visited_at))
+        .filter(|v| query.to_date.map_or(true, |to| to > v.visited_at))
+        .filter(|v| {
+            let user = tables.users.get(&v.user);
+            query.from_age.map_or(true, |from| user.map_or(false, |u| from <= age_of(u))) &&
+                query.to_age.map_or(true, |to| user.map_or(false, |u| to > age_of(u))) &&
+                query.gender.as_ref().map_or(true, |g| user.map_or(false, |u| *g == u.gender))
+        });
+    visits.fold((0, 0), |(sum, count), v| (sum + v.mark as u64, count + 1))
+}
+
+#[test]
+fn location_marks_match_a_scan() {
+    const ENTITIES: u32 = 8;
+    const VISITS: u32 = 150;
+    let options = test_options();
+    let storage = stress_storage(&options, ENTITIES, VISITS);
+    let mut state = 0x1B87_3593;
+    let mut next_id = VISITS + 1;
+
+    for round in 0..400 {
+        let id = next_random(&mut state) % ENTITIES + 1;
+        let other = next_random(&mut state) % ENTITIES + 1;
+        let birth_date = validation::MIN_BIRTH_DATE as i32 +
+            (next_random(&mut state) % 2_000_000_000) as i32;
+        let visited_at = (next_random(&mut state) % 300) as i32;
+        let mark = next_random(&mut state) % 6;
+        let mutation = match round % 8 {
+            0 => {
+                next_id += 1;
+                let visit = format!(
+                    r#"{{"id":{},"location":{},"user":{},"visited_at":{},"mark":{}}}"#,
+                    next_id,
+                    id,
+                    other,
+                    visited_at,
+                    mark
+                );
+                Mutation::VisitNew(serde_json::from_str(&visit).unwrap())
+            }
+            1 => {
+                let update = format!(
+                    r#"{{"location":{},"user":{},"visited_at":{},"mark":{}}}"#,
+                    id,
+                    other,
+                    visited_at,
+                    mark
+                );
+                let visit = next_random(&mut state) % next_id + 1;
+                Mutation::VisitUpdate(visit, serde_json::from_str(&update).unwrap())
+            }
+            2 => Mutation::VisitDelete(next_random(&mut state) % next_id + 1),
+            3 | 4 => {
+                let gender = if mark % 2 == 0 { "m" } else { "f" };
+                let update = format!(r#"{{"gender":"{}","birth_date":{}}}"#, gender, birth_date);
+                Mutation::UserUpdate(id, serde_json::from_str(&update).unwrap())
+            }
+            5 => {
+                let policy = if mark % 2 == 0 {
+                    DependentsPolicy::Orphan
+                } else {
+                    DependentsPolicy::Cascade
+                };
+                Mutation::UserDelete(id, policy)
+            }
+            _ => {
+                let user = format!(
+                    r#"{{"id":{},"email":"u@x.y","first_name":"F","last_name":"L","gender":"m","birth_date":{}}}"#,
+                    id,
+                    birth_date
+                );
+                Mutation::UserNew(serde_json::from_str(&user).unwrap())
+            }
+        };
+        wal::apply(&storage, &options, mutation);
+
+        let tables = storage.read();
+        let mut rebuilt = (*tables).clone();
+        rebuilt.rebuild_indexes(options.now());
+        assert_eq!(tables.visitors, rebuilt.visitors, "round {}", round);
+        let location = next_random(&mut state) % ENTITIES + 1;
+        let incremental = tables.location_marks.get(&location).cloned().unwrap_or_default();
+        let from_scratch = rebuilt.location_marks.get(&location).cloned().unwrap_or_default();
+        assert_eq!(incremental, from_scratch, "round {}", round);
+
+        for _ in 0..5 {
+            let mut query = MarkQuery::default();
+            match next_random(&mut state) % 3 {
+                0 => {
+                    query.from_date = Some((next_random(&mut state) % 320) as i32 - 10);
+                    query.to_date = Some((next_random(&mut state) % 320) as i32 - 10);
+                }
+                1 => {
+                    query.from_age = Some((next_random(&mut state) % 100) as i32);
+                    query.to_age = Some((next_random(&mut state) % 100) as i32);
+                }
+                _ => {}
+            }
+            query.gender = match next_random(&mut state) % 3 {
+                0 => Some(Gender::Male),
+                1 => Some(Gender::Female),
+                _ => None,
+            };
+            assert_eq!(
+                incremental.total(&query, options.now()),
+                Some(naive_marks(&tables, location, &query, options.now())),
+                "{:?}",
+                query
+            );
+        }
+    }
+}
+
+#[test]
+fn visitor_ages_follow_now() {
+    // 1992-02-29, 1980-12-31 and 1970-01-01.
+    let birth_dates = [699_321_600, 347_068_800, 0];
+    let mut state = 0x6A09_E667;
+    for &birth_date in birth_dates.iter() {
+        let user = format!(
+            r#"{{"id":1,"email":"u@x.y","first_name":"F","last_name":"L","gender":"f","birth_date":{}}}"#,
+            birth_date
+        );
+        let user: User = serde_json::from_str(&user).unwrap();
+        let visitor = Visitor::new(&user, 1_503_695_452);
+        // Around each birthday, then anywhere up to 2038.
+        for year in 0..68 {
+            let birthday = birth_date + year * 365 * 86_400 + (year / 4) * 86_400;
+            for &now in [birthday - 86_400, birthday, birthday + 86_400].iter() {
+                assert_eq!(
+                    visitor.age_at(now),
+                    users::calculate_age_from_timestamp(birth_date, now),
+                    "born {} at {}",
+                    birth_date,
+                    now
+                );
+            }
+        }
+        for _ in 0..1000 {
+            let now = (next_random(&mut state) % i32::max_value() as u32) as i32;
+            assert_eq!(
+                visitor.age_at(now),
+                users::calculate_age_from_timestamp(birth_date, now),
+                "born {} at {}",
+                birth_date,
+                now
+            );
+        }
+    }
+}
+
+#[test]
+fn clock_sources() {
+    let fixed = Clock::fixed(100);
+    assert!(fixed.set_now(200));
+    assert_eq!(fixed.now(), 200);
+
+    let offset = Clock::new(ClockSource::Offset, 1_000);
+    assert!(offset.now() >= 1_000 && offset.now() < 1_010);
+    assert!(offset.set_now(-5_000));
+    assert!(offset.now() >= -5_000 && offset.now() < -4_990);
+
+    let system = Clock::new(ClockSource::System, 0);
+    assert!(!system.set_now(100));
+    assert!(system.now() > 1_500_000_000);
+
+    assert_eq!(clock::day_of(0), 0);
+    assert_eq!(clock::day_of(86_399), 0);
+    assert_eq!(clock::day_of(86_400), 1);
+    assert_eq!(clock::day_of(-1), -1);
+    assert_eq!(clock::day_of(-86_400), -1);
+    assert_eq!(clock::day_of(-86_401), -2);
+}
+
+#[test]
+fn admin_clock_moves_now() {
+    let client = Client::new(setup()).expect("valid rocket instance");
+    let mut response = client.get("/admin/clock").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.body_string(),
+        Some(r#"{"source":"fixed","now":1503695452}"#.into())
+    );
+
+    let response = client.post("/admin/clock?now=1600000000").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = client
+        .post("/admin/clock?now=1600000000")
+        .header(Header::new("X-Admin-Token", "secreT"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let mut response = client
+        .post("/admin/clock?now=1600000000")
+        .header(admin_header())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.body_string(),
+        Some(r#"{"source":"fixed","now":1600000000}"#.into())
+    );
+    let mut response = client.get("/admin/clock").dispatch();
+    assert_eq!(
+        response.body_string(),
+        Some(r#"{"source":"fixed","now":1600000000}"#.into())
+    );
+}
+
+#[test]
+fn admin_writes_are_refused_without_a_token() {
+    let options = test_options();
+    let rocket = rocket::ignite()
+        .manage(Arc::new(empty_storage(&options)))
+        .manage(options)
+        .manage(AdminToken(None))
+        .mount("/", routes![admin::admin_clock, admin::admin_clock_set]);
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let mut response = client
+        .post("/admin/clock?now=1600000000")
+        .header(Header::new("X-Admin-Token", ""))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(response.body_string().unwrap().contains("\"code\":\"admin_disabled\""));
+    let response = client.get("/admin/clock").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(format!("{:?}", AdminToken(Some("hidden".to_owned()))), "AdminToken(set)");
+}
+
+#[test]
+fn refreshed_visitors_match_a_rebuild() {
+    let options = test_options();
+    let original = input_data(&PathBuf::from("data"), &options).unwrap();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let later = options.now() + 400 * 86_400;
+    clock::refresh_visitors(&storage, later);
+
+    let mut rebuilt = (*storage.read()).clone();
+    rebuilt.rebuild_indexes(later);
+    assert_eq!(storage.read().visitors, rebuilt.visitors);
+    assert!(storage.read().visitors != original.read().visitors);
+}
+
+#[test]
+fn test_mode_checks_mutations() {
+    let client = Client::new(setup()).expect("valid rocket instance");
+    let read_status = |client: &Client| -> serde_json::Value {
+        let mut response = client.get("/admin/status").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    };
+    let before = read_status(&client);
+    assert_eq!(before["mode"], "test");
+    assert_eq!(before["now"], 1503695452);
+    assert!(before.get("warm_up_millis").is_none());
+
+    let response = client
+        .post("/visits/1?query_id=1")
+        .header(ContentType::JSON)
+        .body(r#"{"mark":2}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let after = read_status(&client);
+    assert!(after["mutations_checked"].as_u64() > before["mutations_checked"].as_u64());
+    assert_eq!(after["invariant_failures"], 0);
+}
+
+#[test]
+fn warm_up_reads_everything() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let tables = storage.read();
+    let read = status::warm_up(&tables, options.now());
+    let entities = tables.users.len() + tables.locations.len() + tables.visits.len();
+    // Every visit is in both indexes as well.
+    assert!(read >= entities + 2 * tables.visits.len(), "{}", read);
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+#[test]
+fn config_sources_take_precedence_in_order() {
+    let file = temp_path("config.toml");
+    let content = "data_dir = \"/srv/data\"\nport = 9000\nworkers = 3\nclock = \"system\"\n";
+    File::create(&file).unwrap().write_all(content.as_bytes()).unwrap();
+    let mut vars = HashMap::new();
+    vars.insert("RUSTLER_CONFIG".to_owned(), file.to_string_lossy().into_owned());
+    vars.insert("RUSTLER_PORT".to_owned(), "9001".to_owned());
+    vars.insert("RUSTLER_INPUT_FORMAT".to_owned(), "zip".to_owned());
+    vars.insert("OTHER_PORT".to_owned(), "1".to_owned());
+    let args = strings(&["export", "--port", "9002", "out", "--zip"]);
+
+    let (config, rest) = Config::load_from(&args, &vars, Path::new("missing.toml")).unwrap();
+    assert_eq!(rest, strings(&["export", "out", "--zip"]));
+    assert_eq!(config.port, 9002);
+    assert_eq!(config.input_format, InputFormat::Zip);
+    assert_eq!(config.workers, Some(3));
+    assert_eq!(config.clock, ClockSource::System);
+    assert_eq!(config.data_dir, PathBuf::from("/srv/data"));
+    assert_eq!(config.zip_path(), PathBuf::from("/srv/data/data.zip"));
+    assert_eq!(config.wal_path, PathBuf::from("/srv/data.wal"));
+    assert_eq!(config.reload_root, PathBuf::from("/srv"));
+    assert_eq!(config.address, "localhost");
+
+    let (config, _) = Config::load_from(&[], &HashMap::new(), Path::new("missing.toml")).unwrap();
+    assert_eq!(config.data_dir, PathBuf::from("data"));
+    assert_eq!(config.options_path(), PathBuf::from("data/options.txt"));
+    assert_eq!(config.reload_root, PathBuf::from("."));
+    assert_eq!(config.input_path(), Some(PathBuf::from("data")));
+    assert_eq!(config.snapshot_path, PathBuf::from("data.snapshot"));
+    assert_eq!(config.input_format, InputFormat::Dir);
+    assert_eq!(config.workers, None);
+}
+
+#[test]
+fn config_errors_name_the_source() {
+    let no_vars = HashMap::new();
+    let missing = Path::new("missing.toml");
+    match Config::load_from(&strings(&["--port", "eighty"]), &no_vars, missing) {
+        Err(ConfigError::Invalid { source, value, .. }) => {
+            assert_eq!(source, "--port");
+            assert_eq!(value, "eighty");
+        }
+        other => panic!("{:?}", other),
+    }
+    match Config::load_from(&strings(&["--workers"]), &no_vars, missing) {
+        Err(ConfigError::MissingValue(flag)) => assert_eq!(flag, "--workers"),
+        other => panic!("{:?}", other),
+    }
+
+    let mut vars = HashMap::new();
+    vars.insert("RUSTLER_WAL_SYNC".to_owned(), "sometimes".to_owned());
+    let error = Config::load_from(&[], &vars, missing).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "invalid RUSTLER_WAL_SYNC \"sometimes\", expected always, never or a count"
+    );
+
+    let file = temp_path("unknown.toml");
+    File::create(&file).unwrap().write_all(b"data_directory = \"data\"\n").unwrap();
+    match Config::load_from(&[], &no_vars, &file) {
+        Err(ConfigError::Unknown(name)) => assert!(name.starts_with("data_directory")),
+        other => panic!("{:?}", other),
+    }
+    match Config::load_from(&strings(&["--config", "missing.toml"]), &no_vars, missing) {
+        Err(ConfigError::Io(path, _)) => assert_eq!(path, PathBuf::from("missing.toml")),
+        other => panic!("{:?}", other),
+    }
+}
+
+fn data_dir_with(files: &[(&str, &str)]) -> PathBuf {
+    let dir = temp_path("load");
+    fs::create_dir_all(&dir).unwrap();
+    for &(name, content) in files.iter() {
+        File::create(dir.join(name))
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+    dir
+}
+
+#[test]
+fn strict_loading_stops_at_a_bad_record() {
+    let dir = data_dir_with(&[
+        (
+            "users_1.json",
+            r#"{"users":[{"id":1,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+        (
+            "locations_1.json",
+            r#"{"locations":[{"id":1,"place":"P","country":"C","city":"T","distance":1}]}"#,
+        ),
+        (
+            "visits_1.json",
+            r#"{"visits":[{"id":1,"location":1,"user":1,"visited_at":1000000000,"mark":1},{"id":2}]}"#,
+        ),
+    ]);
+    let error = match loader::load_dir(&dir, &test_options(), LoadPolicy::Strict, 2) {
+        Err(error) => error,
+        Ok(_) => panic!("loaded a visit without fields"),
+    };
+    assert_eq!(error.file, "visits_1.json");
+    assert_eq!(error.kind, Some(EntityKind::Visits));
+    assert_eq!(error.index, Some(1));
+    assert!(error.to_string().starts_with("visits_1.json, visits[1]: location is required"));
+}
+
+#[test]
+fn lenient_loading_skips_files_it_cannot_open() {
+    let dir = data_dir_with(&[
+        (
+            "users_1.json",
+            r#"{"users":[{"id":1,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+    ]);
+    unix::fs::symlink(dir.join("missing.json"), dir.join("users_2.json")).unwrap();
+
+    let (storage, report) =
+        loader::load_dir(&dir, &test_options(), LoadPolicy::Lenient, 2).unwrap();
+    assert_eq!(storage.read().users.len(), 1);
+    assert_eq!(report.files, 2);
+    assert_eq!(report.skipped_files, 1);
+    assert_eq!(report.errors.len(), 1);
+    assert_eq!(report.errors[0].file, "users_2.json");
+    assert_eq!(report.errors[0].kind, Some(EntityKind::Users));
+
+    match loader::load_dir(&dir, &test_options(), LoadPolicy::Strict, 2) {
+        Err(error) => assert_eq!(error.file, "users_2.json"),
+        Ok(_) => panic!("strict loading skipped a file it couldn't open"),
+    }
+}
+
+#[test]
+fn lenient_loading_skips_and_counts() {
+    let dir = data_dir_with(&[
+        (
+            "users_1.json",
+            r#"{"users":[{"id":1,"email":"a@b.c"},
+                {"id":2,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0},
+                {"id":3,"email":"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@b.cd","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+        ("locations_1.json", "{\"locations\": [\n{\"id\": 1,\n"),
+        ("locations_2.json", r#"{"places":[]}"#),
+        (
+            "locations_3.json",
+            r#"{"locations":[{"id":1,"place":"P","country":"C","city":"T","distance":1}]}"#,
+        ),
+        (
+            "visits_1.json",
+            r#"{"visits":[{"id":1,"location":1,"user":2,"visited_at":1000000000,"mark":1},{"id":2},
+                {"id":3,"location":1,"user":2,"visited_at":1000000000,"mark":9},
+                {"id":4,"location":1,"user":1,"visited_at":1000000000,"mark":1}]}"#,
+        ),
+    ]);
+    let (storage, report) =
+        loader::load_dir(&dir, &test_options(), LoadPolicy::Lenient, 2).unwrap();
+    assert_eq!(storage.read().visits.len(), 1);
+    assert_eq!(report.files, 5);
+    assert_eq!(report.visits, 1);
+    assert_eq!(report.users, 1);
+    assert_eq!(report.skipped_files, 2);
+    assert_eq!(report.skipped_records, 5);
+    assert_eq!(report.errors.len(), 7);
+
+    assert!(report.errors[1].to_string().contains("users[2]: email must be at most 100"));
+    let syntax = &report.errors[2];
+    assert_eq!(syntax.file, "locations_1.json");
+    assert_eq!(syntax.line, Some(3));
+    assert_eq!(syntax.index, None);
+    assert!(report.errors[3].to_string().contains("\"locations\" array"));
+    assert!(report.errors[5].to_string().contains("visits[2]: mark must be between 0 and 5"));
+    assert!(report.errors[6].to_string().ends_with("visit 4: user 1 does not exist"));
+
+    match loader::load_dir(&dir, &test_options(), LoadPolicy::Strict, 2) {
+        Err(error) => assert_eq!(error.file, "users_1.json"),
+        Ok(_) => panic!("strict loading skipped a bad user"),
+    }
+}
+
+#[test]
+fn duplicate_ids_fail_a_strict_load_and_are_skipped_by_a_lenient_one() {
+    let dir = data_dir_with(&[
+        (
+            "users_1.json",
+            r#"{"users":[{"id":1,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+        (
+            "users_2.json",
+            r#"{"users":[{"id":1,"email":"x@y.z","first_name":"X","last_name":"Y","gender":"f","birth_date":0}]}"#,
+        ),
+        (
+            "locations_1.json",
+            r#"{"locations":[{"id":1,"place":"P","country":"C","city":"T","distance":1}]}"#,
+        ),
+        (
+            "visits_1.json",
+            r#"{"visits":[{"id":1,"location":1,"user":1,"visited_at":1000000000,"mark":1},
+                {"id":1,"location":1,"user":1,"visited_at":1000000001,"mark":2}]}"#,
+        ),
+    ]);
+    let (storage, report) =
+        loader::load_dir(&dir, &test_options(), LoadPolicy::Lenient, 2).unwrap();
+    let tables = storage.read();
+    assert_eq!(tables.users[&1].first_name.as_str(), "A");
+    assert_eq!(tables.visits[&1].mark, 1);
+    assert_eq!(tables.location_visits[&1], vec![1]);
+    assert_eq!(tables.user_visits[&1], vec![1]);
+    assert_eq!(report.skipped_records, 2);
+    assert!(report.errors[0].to_string().ends_with("user 1 is a duplicate"));
+    assert!(report.errors[1].to_string().ends_with("visit 1 is a duplicate"));
+
+    match loader::load_dir(&dir, &test_options(), LoadPolicy::Strict, 2) {
+        Err(error) => assert_eq!(error.file, "users_2.json"),
+        Ok(_) => panic!("strict loading took a duplicate user"),
+    }
+}
+
+#[test]
+fn a_failed_file_fails_a_parallel_load() {
+    let mut files = vec![
+        (
+            "users_1.json".to_owned(),
+            r#"{"users":[{"id":1,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#
+                .to_owned(),
+        ),
+        (
+            "locations_1.json".to_owned(),
+            r#"{"locations":[{"id":1,"place":"P","country":"C","city":"T","distance":1}]}"#
+                .to_owned(),
+        ),
+    ];
+    for number in 1..31 {
+        // One bad mark, well after the files other threads start on.
+        let mark = if number == 25 { 9 } else { 1 };
+        let visits = format!(
+            r#"{{"visits":[{{"id":{},"location":1,"user":1,"visited_at":1000000000,"mark":{}}}]}}"#,
+            number,
+            mark
+        );
+        files.push((format!("visits_{}.json", number), visits));
+    }
+    let files: Vec<(&str, &str)> = files
+        .iter()
+        .map(|&(ref name, ref content)| (name.as_str(), content.as_str()))
+        .collect();
+    let dir = data_dir_with(&files);
+
+    // The threads race differently each time; none may end in a partial load.
+    for _ in 0..20 {
+        match loader::load_dir(&dir, &test_options(), LoadPolicy::Strict, 8) {
+            Err(error) => assert_eq!(error.file, "visits_25.json"),
+            Ok((storage, _)) => panic!("loaded {} visits", storage.read().visits.len()),
+        }
+    }
+}
+
+#[test]
+fn loading_reports_missing_and_truncated_files() {
+    let dir = data_dir_with(&[("options.txt", "1503695452\n")]);
+    match read_options(&dir.join("options.txt"), ClockSource::Fixed) {
+        Err(error) => {
+            assert_eq!(error.line, Some(2));
+            assert!(error.to_string().ends_with("line 2: invalid mode \"\", expected 0 or 1"));
+        }
+        Ok(_) => panic!("read options without a mode"),
+    }
+    assert!(read_options(&dir.join("missing.txt"), ClockSource::Fixed).is_err());
+    match ZipSource::open(&dir.join("data.zip")) {
+        Err(error) => assert!(error.file.ends_with("data.zip")),
+        Ok(_) => panic!("opened a missing archive"),
+    }
+}
+
+#[test]
+fn loading_streams_records_around_other_keys() {
+    let dir = data_dir_with(&[
+        (
+            "visits_1.json",
+            r#"{"total": 2, "visits": [
+                {"id": 1, "location": 1, "user": 1, "visited_at": 1000000000, "mark": 1},
+                {"id": 2, "location": 1, "user": 1, "visited_at": 1000000005, "mark": 4}
+            ], "more": {"visits": [{"id": 3}]}}"#,
+        ),
+        ("visits_2.json", r#"{"visits": []} trailing"#),
+        (
+            "users_1.json",
+            r#"{"users":[{"id":1,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+        (
+            "locations_1.json",
+            r#"{"locations":[{"id":1,"place":"P","country":"C","city":"T","distance":1}]}"#,
+        ),
+    ]);
+    let (storage, report) =
+        loader::load_dir(&dir, &test_options(), LoadPolicy::Lenient, 2).unwrap();
+    let tables = storage.read();
+    assert_eq!(tables.visits.len(), 2);
+    assert_eq!(tables.location_visits[&1], vec![1, 2]);
+    assert_eq!(report.skipped_records, 0);
+    assert_eq!(report.skipped_files, 1);
+    assert_eq!(report.errors[0].file, "visits_2.json");
+}
+
+#[test]
+fn scaled_data_sets_repeat_the_data_with_new_ids() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let dir = temp_path("scaled");
+    bench::write_scaled(&storage, &options, 3, &dir).unwrap();
+    let scaled = input_data(&dir, &options).unwrap();
+
+    let tables = storage.read();
+    let scaled_tables = scaled.read();
+    assert_eq!(scaled_tables.users.len(), 3 * tables.users.len());
+    assert_eq!(scaled_tables.locations.len(), 3 * tables.locations.len());
+    assert_eq!(scaled_tables.visits.len(), 3 * tables.visits.len());
+    assert!(integrity::check(&scaled_tables).is_clean());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn parallel_loading_matches_sequential() {
+    let options = test_options();
+    let data = PathBuf::from("data");
+    let (sequential, _) = loader::load_dir(&data, &options, LoadPolicy::Strict, 1).unwrap();
+    let (parallel, report) = loader::load_dir(&data, &options, LoadPolicy::Strict, 8).unwrap();
+    let (sequential, parallel) = (sequential.read(), parallel.read());
+    assert_eq!(report.visits, sequential.visits.len());
+    assert_eq!(sequential.users.len(), parallel.users.len());
+    assert_eq!(sequential.locations.len(), parallel.locations.len());
+    assert_eq!(sequential.visitors, parallel.visitors);
+    assert_eq!(sequential.location_visits, parallel.location_visits);
+    assert_eq!(sequential.user_visits, parallel.user_visits);
+
+    let dir = data_dir_with(&[
+        ("users_1.json", r#"{"users":[]}"#),
+        ("users_2.json", r#"{"users":[{"id":1}]}"#),
+        ("users_3.json", r#"{"users":[{"id":2}]}"#),
+        ("locations_1.json", r#"{"locations":[]}"#),
+    ]);
+    for _ in 0..10 {
+        match loader::load_dir(&dir, &options, LoadPolicy::Strict, 4) {
+            Err(error) => assert_eq!(error.file, "users_2.json"),
+            Ok(_) => panic!("loaded users without fields"),
+        }
+    }
+}
+
+#[test]
+fn loading_many_files_stays_within_the_merge_window() {
+    let names: Vec<_> = (1..41).map(|i| format!("users_{}.json", i)).collect();
+    let contents: Vec<_> = (1..41)
+        .map(|i| {
+            format!(
+                r#"{{"users":[{{"id":{},"email":"u@x.y","first_name":"F","last_name":"L","gender":"m","birth_date":0}}]}}"#,
+                i
+            )
+        })
+        .collect();
+    let mut files: Vec<_> = names
+        .iter()
+        .zip(contents.iter())
+        .map(|(name, content)| (name.as_str(), content.as_str()))
+        .collect();
+    let dir = data_dir_with(&files);
+    let (storage, report) = loader::load_dir(&dir, &test_options(), LoadPolicy::Strict, 3).unwrap();
+    assert_eq!((report.files, storage.read().users.len()), (40, 40));
+
+    files[0].1 = r#"{"users":[{"id":1}]}"#;
+    let dir = data_dir_with(&files);
+    match loader::load_dir(&dir, &test_options(), LoadPolicy::Strict, 3) {
+        Err(error) => assert_eq!(error.file, "users_1.json"),
+        Ok(_) => panic!("loaded users without fields"),
+    }
+}
+
+#[test]
+fn loading_finds_files_past_gaps() {
+    let dir = data_dir_with(&[
+        (
+            "users_2.json",
+            r#"{"users":[{"id":1,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+        (
+            "locations_1.json",
+            r#"{"locations":[{"id":1,"place":"P","country":"C","city":"T","distance":1}]}"#,
+        ),
+        (
+            "visits_1.json",
+            r#"{"visits":[{"id":1,"location":1,"user":1,"visited_at":1000000000,"mark":1}]}"#,
+        ),
+        (
+            "visits_3.json",
+            r#"{"visits":[{"id":3,"location":1,"user":1,"visited_at":1000000000,"mark":1}]}"#,
+        ),
+        ("visits_x.json", r#"not a data file"#),
+        ("visits_01.json", r#"not a data file either"#),
+        ("notes.txt", "neither"),
+    ]);
+    let source = DirSource::new(&dir);
+    let (storage, report) =
+        loader::load(Box::new(source), &test_options(), LoadPolicy::Lenient, 2).unwrap();
+    assert_eq!(storage.read().visits.len(), 2);
+    assert_eq!(report.files, 4);
+    assert_eq!(report.missing_files, vec!["users_1.json", "visits_2.json"]);
+
+    match loader::load(Box::new(DirSource::new(&dir)), &test_options(), LoadPolicy::Strict, 2) {
+        Err(error) => assert_eq!(error.file, "users_1.json"),
+        Ok(_) => panic!("loaded past a gap in strict mode"),
+    }
+}
+
+#[test]
+fn tar_sources_load_like_directories() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let dir = temp_path("export-tar");
+    export::export(&dir, &storage, &options, 1000, false).unwrap();
+    let tar_path = dir.join("data.tar");
+    {
+        let mut builder = tar::Builder::new(File::create(&tar_path).unwrap());
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(true, |extension| extension != "json") {
+                continue;
+            }
+            let name = format!("data/{}", path.file_name().unwrap().to_string_lossy());
+            builder.append_file(name, &mut File::open(&path).unwrap()).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    let source = TarSource::open(&tar_path).unwrap();
+    let (reloaded, report) =
+        loader::load(Box::new(source), &options, LoadPolicy::Strict, 4).unwrap();
+    assert_eq!(reloaded.read().users.len(), storage.read().users.len());
+    assert_eq!(reloaded.read().visits.len(), storage.read().visits.len());
+    assert!(report.missing_files.is_empty());
+
+    let duplicated_path = dir.join("duplicated.tar");
+    {
+        let mut builder = tar::Builder::new(File::create(&duplicated_path).unwrap());
+        for name in &["a/users_1.json", "b/users_1.json"] {
+            builder
+                .append_file(name, &mut File::open(dir.join("users_1.json")).unwrap())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+    let source = TarSource::open(&duplicated_path).unwrap();
+    match loader::load(Box::new(source), &options, LoadPolicy::Lenient, 2) {
+        Err(error) => assert_eq!(error.file, "b/users_1.json"),
+        Ok(_) => panic!("loaded two files numbered alike"),
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn wait_for_reload(reloader: &Reloader) -> ReloadStatus {
+    for _ in 0..500 {
+        let status = reloader.status();
+        if status.state != ReloadState::Loading {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("reload didn't finish");
+}
+
+fn reload_config(snapshot_path: &Path, flags: &[&str]) -> Config {
+    let snapshot_path = snapshot_path.to_string_lossy().into_owned();
+    let mut args = strings(&["--snapshot-path", snapshot_path.as_str()]);
+    args.extend(strings(flags));
+    let (config, _) = Config::load_from(&args, &HashMap::new(), Path::new("missing.toml")).unwrap();
+    config
+}
+
+#[test]
+fn reload_swaps_in_new_data() {
+    let options = test_options();
+    let storage = Arc::new(input_data(&PathBuf::from("data"), &options).unwrap());
+    let wal_path = temp_path("reload.wal");
+    let wal = Arc::new(Wal::open(&wal_path, SyncPolicy::Always).unwrap());
+    wal.commit(&storage, &options, Mutation::VisitDelete(1)).unwrap();
+    assert!(!wal.is_empty().unwrap());
+    let snapshot_path = temp_path("reload.snapshot");
+    let config = reload_config(&snapshot_path, &["--input-format", "stdin", "--load-threads", "2"]);
+    let reloader = Arc::new(Reloader::new(storage.clone(), wal.clone(), options.clone(), &config));
+    let in_flight = storage.read();
+    let dir = data_dir_with(&[
+        ("options.txt", "1600000000\n0\n"),
+        (
+            "users_1.json",
+            r#"{"users":[{"id":7,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}]}"#,
+        ),
+    ]);
+
+    match reload::start(&reloader, None, false) {
+        Err(reload::StartError::NoSource) => {}
+        other => panic!("reloaded without a source: {:?}", other),
+    }
+    let started = reload::start(&reloader, Some(dir.clone()), false).unwrap();
+    assert_eq!(started.state, ReloadState::Loading);
+    let status = wait_for_reload(&reloader);
+    assert_eq!(status.state, ReloadState::Done, "{:?}", status);
+    assert_eq!((status.files, status.files_loaded), (1, 1));
+    assert_eq!((status.replayed_mutations, status.discarded_mutations), (0, 0));
+    assert_eq!(options.now(), 1600000000);
+
+    let tables = storage.read();
+    assert_eq!(tables.users.len(), 1);
+    assert!(tables.users.get(&7).is_some());
+    assert!(tables.visits.len() == 0 && tables.visitors.len() == 1);
+    assert!(in_flight.users.len() > 1);
+    assert_eq!(fs::metadata(&wal_path).unwrap().len(), 0);
+    let persisted = snapshot::read_snapshot(&snapshot_path, &options).unwrap();
+    assert_eq!(persisted.read().users.len(), 1);
+
+    reload::start(&reloader, Some(dir.join("missing.zip")), false).unwrap();
+    let failed = wait_for_reload(&reloader);
+    assert_eq!(failed.state, ReloadState::Failed);
+    assert!(failed.error.unwrap().contains("missing.zip"));
+    assert_eq!(storage.read().users.len(), 1);
+
+    let rating = data_dir_with(&[("options.txt", "1700000000\n1\n")]);
+    reload::start(&reloader, Some(rating), false).unwrap();
+    let failed = wait_for_reload(&reloader);
+    assert_eq!(failed.state, ReloadState::Failed);
+    assert!(failed.error.unwrap().contains("Rating mode"));
+    assert_eq!(options.now(), 1600000000);
+
+    // A failed reload stops capturing along with a finished one.
+    wal.commit(&storage, &options, Mutation::UserDelete(7, DependentsPolicy::Reject)).unwrap();
+    assert_eq!(wal.checkpoint_captured(|mutations| Ok(mutations.len())).unwrap(), 0);
+}
+
+#[test]
+fn default_reload_keeps_changes_unless_told_to_discard() {
+    let options = test_options();
+    let storage = Arc::new(empty_storage(&options));
+    let wal = Arc::new(Wal::open(&temp_path("default-reload.wal"), SyncPolicy::Never).unwrap());
+    let config = reload_config(&temp_path("default-reload.snapshot"), &[]);
+    let reloader = Arc::new(Reloader::new(storage.clone(), wal.clone(), options.clone(), &config));
+    let user = r#"{"id":7,"email":"a@b.c","first_name":"A","last_name":"B","gender":"m","birth_date":0}"#;
+    let user = serde_json::from_str(user).unwrap();
+    wal.commit(&storage, &options, Mutation::UserNew(user)).unwrap();
+
+    match reload::start(&reloader, None, false) {
+        Err(reload::StartError::WouldDiscard) => {}
+        other => panic!("reloaded over a change: {:?}", other),
+    }
+    assert_eq!(storage.read().users.len(), 1);
+
+    reload::start(&reloader, None, true).unwrap();
+    let status = wait_for_reload(&reloader);
+    assert_eq!(status.state, ReloadState::Done, "{:?}", status);
+    assert!(storage.read().users.len() > 1);
+    assert!(wal.is_empty().unwrap());
+}
+
+#[test]
+fn wal_hands_over_the_mutations_captured_for_a_reload() {
+    let options = test_options();
+    let storage = input_data(&PathBuf::from("data"), &options).unwrap();
+    let wal = Wal::open(&temp_path("capture.wal"), SyncPolicy::Never).unwrap();
+    wal.commit(&storage, &options, Mutation::VisitDelete(1)).unwrap();
+    wal.capture();
+    wal.commit(&storage, &options, Mutation::VisitDelete(2)).unwrap();
+    wal.commit(&storage, &options, Mutation::VisitDelete(1)).unwrap();
+
+    // The captured mutations are made to the replacement as they were logged.
+    let replacement = input_data(&PathBuf::from("data"), &options).unwrap();
+    let outcomes = wal.checkpoint_captured(|mutations| {
+        Ok(mutations
+            .into_iter()
+            .map(|mutation| wal::apply(&replacement, &options, mutation))
+            .collect::<Vec<_>>())
+    }).unwrap();
+    assert_eq!(outcomes, vec![Outcome::Applied, Outcome::Applied]);
+    assert!(!replacement.read().visits.contains_key(&1));
+    assert!(!replacement.read().visits.contains_key(&2));
+    assert!(wal.is_empty().unwrap());
+
+    wal.commit(&storage, &options, Mutation::VisitDelete(3)).unwrap();
+    assert_eq!(wal.checkpoint_captured(|mutations| Ok(mutations.len())).unwrap(), 0);
+}
+
+#[test]
+fn reload_runs_the_integrity_check() {
+    let options = test_options();
+    let storage = Arc::new(empty_storage(&options));
+    let wal = Arc::new(Wal::open(&temp_path("integrity-reload.wal"), SyncPolicy::Never).unwrap());
+    let snapshot_path = temp_path("integrity-reload.snapshot");
+    let config = reload_config(&snapshot_path, &["--integrity-check", "strict"]);
+    let reloader = Arc::new(Reloader::new(storage.clone(), wal, options, &config));
+
+    reload::start(&reloader, Some(PathBuf::from("data")), false).unwrap();
+    let status = wait_for_reload(&reloader);
+    assert_eq!(status.state, ReloadState::Done, "{:?}", status);
+    assert!(integrity::check(&storage.read()).is_clean());
+}
+
+#[test]
+fn admin_reload_reports_progress() {
+    let options = test_options();
+    let storage = Arc::new(empty_storage(&options));
+    let wal = Arc::new(Wal::open(&temp_path("admin-reload.wal"), SyncPolicy::Never).unwrap());
+    let config = reload_config(&temp_path("admin-reload.snapshot"), &[]);
+    let reloader = Reloader::new(storage, wal, options, &config);
+    let rocket = rocket::ignite()
+        .manage(Arc::new(reloader))
+        .manage(AdminToken(Some(ADMIN_TOKEN.to_owned())))
+        .mount(
+            "/",
+            routes![
+                admin::admin_reload_no_params,
+                admin::admin_reload,
+                admin::admin_reload_status,
+            ],
+        );
+    let client = Client::new(rocket).expect("valid rocket instance");
+    let read_status = |client: &Client| -> serde_json::Value {
+        let mut response = client.get("/admin/reload").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    };
+    assert_eq!(read_status(&client)["state"], "idle");
+
+    let response = client.post("/admin/reload").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(read_status(&client)["state"], "idle");
+    let response = client.post("/admin/reload").header(admin_header()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let mut state = read_status(&client);
+    for _ in 0..500 {
+        if state["state"] != "loading" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        state = read_status(&client);
+    }
+    assert_eq!(state["state"], "done");
+    assert_eq!(state["source"], "data");
+    assert_eq!(state["files"], state["files_loaded"]);
+
+    for path in &["/etc", "../data", "data/../../etc"] {
+        let response = client
+            .post(format!("/admin/reload?path={}", path))
+            .header(admin_header())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", path);
+    }
+}
@@ -0,0 +1,266 @@
+use arrayvec::ArrayString;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, Visitor};
+
+use std::cmp::{self, Ordering};
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+
+/// A reference-counted string, so cloning one doesn't allocate. For `country`
+/// and `city`, where a handful of values repeat across the whole dataset,
+/// stored symbols are shared through the symbol table. Deserializing makes an
+/// unshared one, so strings from requests that are refused never get in.
+#[derive(Clone)]
+pub struct Symbol(Arc<str>);
+
+/// Symbol table size below which it's never pruned.
+const MIN_PRUNE_AT: usize = 1024;
+
+struct SymbolTable {
+    strings: HashSet<Arc<str>>,
+    /// Size at which strings no symbol uses any more are dropped.
+    prune_at: usize,
+}
+
+lazy_static! {
+    static ref SYMBOLS: RwLock<SymbolTable> = RwLock::new(SymbolTable {
+        strings: HashSet::new(),
+        prune_at: MIN_PRUNE_AT,
+    });
+}
+
+impl Symbol {
+    /// A symbol of its own, not shared through the table.
+    pub fn new(s: &str) -> Symbol {
+        Symbol(Arc::from(s))
+    }
+
+    /// The table's symbol for `s`, added if there's none yet. Strings only
+    /// the table still holds are dropped each time it doubles in size.
+    pub fn intern(s: &str) -> Symbol {
+        if let Some(symbol) = SYMBOLS.read().unwrap().strings.get(s) {
+            return Symbol(symbol.clone());
+        }
+        let mut symbols = SYMBOLS.write().unwrap();
+        if let Some(symbol) = symbols.strings.get(s) {
+            return Symbol(symbol.clone());
+        }
+        if symbols.strings.len() >= symbols.prune_at {
+            symbols.strings.retain(|symbol| Arc::strong_count(symbol) > 1);
+            symbols.prune_at = cmp::max(MIN_PRUNE_AT, symbols.strings.len() * 2);
+        }
+        let symbol: Arc<str> = Arc::from(s);
+        symbols.strings.insert(symbol.clone());
+        Symbol(symbol)
+    }
+
+    /// Whether this is the table's symbol for its string.
+    pub fn is_interned(&self) -> bool {
+        SYMBOLS
+            .read()
+            .unwrap()
+            .strings
+            .get(self.as_str())
+            .map_or(false, |symbol| Arc::ptr_eq(symbol, &self.0))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Bytes held by the symbol table.
+pub fn interned_bytes() -> usize {
+    let symbols = SYMBOLS.read().unwrap();
+    let strings: usize = symbols.strings.iter().map(|s| s.len()).sum();
+    strings + symbols.strings.capacity() * (mem::size_of::<u64>() + mem::size_of::<Arc<str>>())
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Symbol) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Symbol) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+struct SymbolVisitor;
+
+impl<'de> Visitor<'de> for SymbolVisitor {
+    type Value = Symbol;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Symbol, E> {
+        Ok(Symbol::new(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Symbol, D::Error> {
+        deserializer.deserialize_str(SymbolVisitor)
+    }
+}
+
+/// Bytes an `InlineString` holds in place: with its length and the variant
+/// tag, that makes it 24 bytes, as big as a `String`, and room for almost any
+/// name and the shorter half of emails.
+pub const INLINE_CAPACITY: usize = 22;
+
+/// A string stored in place, for short fields like names, so copying an
+/// entity doesn't allocate. One longer than `INLINE_CAPACITY` bytes goes on
+/// the heap, shared between the copies.
+#[derive(Clone)]
+pub struct InlineString(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline(ArrayString<[u8; INLINE_CAPACITY]>),
+    Heap(Arc<str>),
+}
+
+impl InlineString {
+    pub fn new(s: &str) -> InlineString {
+        match ArrayString::from(s) {
+            Ok(inline) => InlineString(Repr::Inline(inline)),
+            Err(_) => InlineString(Repr::Heap(Arc::from(s))),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self.0 {
+            Repr::Inline(ref inline) => inline,
+            Repr::Heap(ref heap) => heap,
+        }
+    }
+
+    /// Bytes allocated for the string, 0 when it's stored in place.
+    pub fn heap_size(&self) -> usize {
+        match self.0 {
+            Repr::Inline(_) => 0,
+            Repr::Heap(ref heap) => heap.len(),
+        }
+    }
+}
+
+impl Deref for InlineString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq for InlineString {
+    fn eq(&self, other: &InlineString) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for InlineString {}
+
+impl PartialOrd for InlineString {
+    fn partial_cmp(&self, other: &InlineString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InlineString {
+    fn cmp(&self, other: &InlineString) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Hash for InlineString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl fmt::Debug for InlineString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for InlineString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for InlineString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+struct InlineStringVisitor;
+
+impl<'de> Visitor<'de> for InlineStringVisitor {
+    type Value = InlineString;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<InlineString, E> {
+        Ok(InlineString::new(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for InlineString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<InlineString, D::Error> {
+        deserializer.deserialize_str(InlineStringVisitor)
+    }
+}
@@ -2,15 +2,24 @@ use gender::Gender;
 use Options;
 use QueryId;
 use Storage;
-use util::NewOrUpdateResponse;
-
-use chrono::{Datelike, NaiveDateTime};
+use Tables;
+use error::ApiError;
+use idmap::{Entry, HeapSize};
+use marks;
+use text::{InlineString, Symbol};
+use util::{self, DeleteParams, DependentsPolicy, NewOrUpdateResponse, PageRequest, Patch,
+           SortKey};
+use validation::{self, Checker, Validate, Validated};
+use visits;
+use wal::{Mutation, Outcome, Wal};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
 use rocket::State;
-use rocket::http::Status;
-use rocket::response::Failure;
+use rocket::response::status::Created;
 use rocket_contrib::Json;
 
-use std::collections::hash_map::Entry;
+use std::cmp::Ordering;
+use std::sync::Arc;
 
 #[derive(Serialize)]
 pub struct UserVisits {
@@ -25,40 +34,240 @@ pub struct UsersVisitsParams {
     #[form(field = "toDistance")] to_distance: Option<u32>,
 }
 
+#[derive(FromForm, Default)]
+pub struct UsersListParams {
+    limit: Option<usize>,
+    offset: Option<usize>,
+    after: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    gender: Option<Gender>,
+    #[form(field = "fromBirthDate")] from_birth_date: Option<i32>,
+    #[form(field = "toBirthDate")] to_birth_date: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct UsersList {
+    users: Vec<User>,
+    total: usize,
+    next: Option<String>,
+}
+
+const USER_SORT_FIELDS: &[&str] = &[
+    "id",
+    "email",
+    "first_name",
+    "last_name",
+    "gender",
+    "birth_date",
+];
+
 #[derive(Serialize, Deserialize)]
 pub struct VisitInfo {
     mark: u8,
     visited_at: i32,
-    place: String,
+    place: Symbol,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
+    /// 0 in a create request that leaves the id to the server.
+    #[serde(default)]
     pub id: u32,
-    email: String,      // [char; 100]
-    first_name: String, // [char; 50]
-    last_name: String,  // [char; 50]
+    pub email: InlineString,      // [char; 100]
+    pub first_name: InlineString, // [char; 50]
+    pub last_name: InlineString,  // [char; 50]
     pub gender: Gender,
     pub birth_date: i32,
 }
 
+// Its strings are stored inline unless they're unusually long.
+impl HeapSize for User {
+    fn heap_size(&self) -> usize {
+        self.email.heap_size() + self.first_name.heap_size() + self.last_name.heap_size()
+    }
+}
+
+/// What the age and gender filters need to know about a user, kept in
+/// `Tables::visitors` so they don't work the age out per visit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Visitor {
+    pub gender: Gender,
+    pub birth_date: i32,
+    age: i32,
+    // `age` holds from `age_from` up to, not including, `age_until`: from
+    // one birthday to the next.
+    age_from: i64,
+    age_until: i64,
+}
+
+impl Visitor {
+    pub fn new(user: &User, now: i32) -> Visitor {
+        let birth = NaiveDateTime::from_timestamp(user.birth_date as i64, 0).date();
+        let today = NaiveDateTime::from_timestamp(now as i64, 0).date();
+        let this_year = birthday_in(birth, today.year());
+        let (from, until) = if this_year <= today {
+            (this_year, birthday_in(birth, today.year() + 1))
+        } else {
+            (birthday_in(birth, today.year() - 1), this_year)
+        };
+        Visitor {
+            gender: user.gender.clone(),
+            birth_date: user.birth_date,
+            age: calculate_age_from_timestamp(user.birth_date, now),
+            age_from: from.and_hms(0, 0, 0).timestamp(),
+            age_until: until.and_hms(0, 0, 0).timestamp(),
+        }
+    }
+
+    /// The age at `now`. Only worked out from `birth_date` once `now` has
+    /// left the year the record was made for.
+    pub fn age_at(&self, now: i32) -> i32 {
+        let now = now as i64;
+        if now >= self.age_from && now < self.age_until {
+            self.age
+        } else {
+            calculate_age_from_timestamp(self.birth_date, now as i32)
+        }
+    }
+}
+
+impl HeapSize for Visitor {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+// The day someone born on `birth` gets a year older in `year`: their
+// birthday, or March 1 for those born on February 29.
+fn birthday_in(birth: NaiveDate, year: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, birth.month(), birth.day())
+        .unwrap_or_else(|| NaiveDate::from_ymd(year, 3, 1))
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct UserUpdate {
-    #[serde(default)] email: String,      // [char; 100]
-    #[serde(default)] first_name: String, // [char; 50]
-    #[serde(default)] last_name: String,  // [char; 50]
-    #[serde(default)] gender: Gender,
-    #[serde(default)] birth_date: i32,
+pub struct UserUpdate {
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    email: Patch<InlineString>, // [char; 100]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    first_name: Patch<InlineString>, // [char; 50]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    last_name: Patch<InlineString>, // [char; 50]
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    gender: Patch<Gender>,
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    birth_date: Patch<i32>,
+}
+
+fn check_user_fields(checker: &mut Checker) {
+    checker.email("email", 100);
+    checker.string("first_name", 50);
+    checker.string("last_name", 50);
+    checker.one_of("gender", &["m", "f"]);
+    checker.timestamp("birth_date", validation::MIN_BIRTH_DATE);
+}
+
+impl Validate for User {
+    fn validate(checker: &mut Checker) {
+        checker.optional_id("id");
+        check_user_fields(checker);
+    }
+}
+
+impl Validate for UserUpdate {
+    fn is_partial() -> bool {
+        true
+    }
+
+    fn validate(checker: &mut Checker) {
+        check_user_fields(checker);
+    }
 }
 
 #[get("/users/<id>")]
-fn users(id: u32, storage: State<Storage>) -> Option<Json<User>> {
-    let users = &*storage.users.read().unwrap();
-    users.get(&id).map(|entity| Json(entity.clone()))
+fn users(id: u32, storage: State<Arc<Storage>>) -> Result<Json<User>, ApiError> {
+    storage
+        .read()
+        .users
+        .get(&id)
+        .map(|entity| Json(entity.clone()))
+        .ok_or_else(|| ApiError::not_found("user", id))
+}
+
+#[get("/users")]
+fn users_list_no_params(storage: State<Arc<Storage>>) -> Result<Json<UsersList>, ApiError> {
+    users_list(None, storage)
+}
+
+#[get("/users?<params>")]
+fn users_list(
+    params: Option<UsersListParams>,
+    storage: State<Arc<Storage>>,
+) -> Result<Json<UsersList>, ApiError> {
+    let params = params.unwrap_or_default();
+
+    let tables = storage.read();
+    let all_users = &tables.users;
+    let filtered_users: Vec<&User> = all_users
+        .values()
+        .filter(|u| params.gender.as_ref().map_or(true, |g| *g == u.gender))
+        .filter(|u| params.from_birth_date.map_or(true, |d| d < u.birth_date))
+        .filter(|u| params.to_birth_date.map_or(true, |d| d > u.birth_date))
+        .collect();
+
+    let page_request = PageRequest {
+        limit: params.limit,
+        offset: params.offset,
+        after: params.after.as_ref().map(|s| s.as_str()),
+        sort: params.sort.as_ref().map(|s| s.as_str()),
+        order: params.order.as_ref().map(|s| s.as_str()),
+    };
+    let page = match util::paginate(
+        filtered_users,
+        page_request,
+        USER_SORT_FIELDS,
+        |field, a, b| compare_users(field, a, b),
+        |field, u| user_sort_key(field, u),
+        |u| u.id,
+    ) {
+        Some(page) => page,
+        None => return Err(ApiError::invalid_query("invalid sort, order, limit or cursor")),
+    };
+
+    Ok(Json(UsersList {
+        users: page.items.into_iter().cloned().collect(),
+        total: page.total,
+        next: page.next,
+    }))
+}
+
+fn compare_users(field: &str, a: &User, b: &User) -> Ordering {
+    match field {
+        "email" => a.email.cmp(&b.email),
+        "first_name" => a.first_name.cmp(&b.first_name),
+        "last_name" => a.last_name.cmp(&b.last_name),
+        "gender" => a.gender.cmp(&b.gender),
+        "birth_date" => a.birth_date.cmp(&b.birth_date),
+        _ => a.id.cmp(&b.id),
+    }
+}
+
+fn user_sort_key(field: &str, user: &User) -> SortKey {
+    match field {
+        "email" => SortKey::Str(user.email.as_str().to_owned()),
+        "first_name" => SortKey::Str(user.first_name.as_str().to_owned()),
+        "last_name" => SortKey::Str(user.last_name.as_str().to_owned()),
+        "gender" => SortKey::Int(user.gender.clone() as i64),
+        "birth_date" => SortKey::Int(user.birth_date as i64),
+        _ => SortKey::Int(user.id as i64),
+    }
 }
 
 #[get("/users/<id>/visits")]
-fn users_visits_no_params(id: u32, storage: State<Storage>) -> Result<Json<UserVisits>, Failure> {
+fn users_visits_no_params(
+    id: u32,
+    storage: State<Arc<Storage>>,
+) -> Result<Json<UserVisits>, ApiError> {
     users_visits(id, None, storage)
 }
 
@@ -66,56 +275,43 @@ fn users_visits_no_params(id: u32, storage: State<Storage>) -> Result<Json<UserV
 fn users_visits(
     id: u32,
     params: Option<UsersVisitsParams>,
-    storage: State<Storage>,
-) -> Result<Json<UserVisits>, Failure> {
-    let all_users = &*storage.users.read().unwrap();
+    storage: State<Arc<Storage>>,
+) -> Result<Json<UserVisits>, ApiError> {
+    let tables = storage.read();
     {
-        if let None = all_users.get(&id) {
-            return Err(Failure(Status::NotFound));
+        if let None = tables.users.get(&id) {
+            return Err(ApiError::not_found("user", id));
         }
     }
     if let Some(ref params) = params {
         if params.country.is_none() && params.from_date.is_none() && params.to_date.is_none() &&
             params.to_distance.is_none()
         {
-            return Err(Failure(Status::BadRequest));
+            return Err(ApiError::invalid_query("no filter parameters given"));
         }
     }
 
-    let all_visits = &*storage.visits.read().unwrap();
-    let user_visits_ids = &storage.user_visits.read().unwrap();
-    let maybe_this_user_visits_ids = user_visits_ids.get(&id);
-    let user_visits = if let Some(this_user_visits_ids) = maybe_this_user_visits_ids {
-        this_user_visits_ids.iter().map(|i| all_visits[&i].clone())
-    } else {
-        return Ok(Json(UserVisits { visits: vec![] }));
+    let all_visits = &tables.visits;
+    let locations = &tables.locations;
+    let this_user_visits_ids = match tables.user_visits.get(&id) {
+        Some(ids) => ids,
+        None => return Ok(Json(UserVisits { visits: vec![] })),
     };
+    // The list is ordered by `visited_at`, so the dates pick out a slice of it
+    // and the result needs no sorting.
+    let (from_date, to_date) = params
+        .as_ref()
+        .map_or((None, None), |params| (params.from_date, params.to_date));
+    let user_visits = visits::timeline_range(all_visits, this_user_visits_ids, from_date, to_date)
+        .iter()
+        .filter_map(|i| all_visits.get(i));
 
-    let mut result_visits = if let Some(params) = params {
-        let from_date_visits = user_visits.filter(|v| if let Some(from_date) = params.from_date {
-            if from_date < v.visited_at {
-                true
-            } else {
-                false
-            }
-        } else {
-            true
-        });
-
-        let to_date_visits = from_date_visits.filter(|v| if let Some(to_date) = params.to_date {
-            if to_date > v.visited_at {
-                true
-            } else {
-                false
-            }
-        } else {
-            true
-        });
-
-        let country_visits = to_date_visits.filter(|v| if let Some(ref country) = params.country {
-            // FIXME: get rid of unwrap
-            let locations = &storage.locations.read().unwrap();
-            let reference_country = &locations.get(&v.location).unwrap().country;
+    let result_visits: Vec<_> = if let Some(params) = params {
+        let country_visits = user_visits.filter(|v| if let Some(ref country) = params.country {
+            let reference_country = match locations.get(&v.location) {
+                Some(location) => &*location.country,
+                None => return false,
+            };
 
             if country == reference_country {
                 true
@@ -128,9 +324,10 @@ fn users_visits(
 
         let to_distance_visits =
             country_visits.filter(|v| if let Some(to_distance) = params.to_distance {
-                // FIXME: get rid of unwrap
-                let locations = &storage.locations.read().unwrap();
-                let reference_distance = locations.get(&v.location).unwrap().distance;
+                let reference_distance = match locations.get(&v.location) {
+                    Some(location) => location.distance,
+                    None => return false,
+                };
 
                 if to_distance > reference_distance {
                     true
@@ -147,18 +344,18 @@ fn users_visits(
         user_visits.collect()
     };
 
-    result_visits.sort_by(|v1, v2| v1.visited_at.cmp(&v2.visited_at));
+    // Visits whose location was deleted with `DependentsPolicy::Orphan` have
+    // no place to show and are skipped.
     let result_visits = result_visits
         .iter()
-        .map(|v| {
-            let locations = &storage.locations;
-            let location = &locations.read().unwrap()[&v.location];
-            let place = location.place.clone();
-            VisitInfo {
-                mark: v.mark,
-                place: place,
-                visited_at: v.visited_at,
-            }
+        .filter_map(|v| {
+            locations.get(&v.location).map(|location| {
+                VisitInfo {
+                    mark: v.mark,
+                    place: location.place.clone(),
+                    visited_at: v.visited_at,
+                }
+            })
         })
         .collect();
 
@@ -171,59 +368,139 @@ fn users_visits(
 #[post("/users/<id>?<query_id>", data = "<user>")]
 fn users_update(
     id: u32,
-    user: Json<UserUpdate>,
+    user: Result<Validated<UserUpdate>, ApiError>,
     query_id: QueryId,
-    storage: State<Storage>,
+    storage: State<Arc<Storage>>,
     options: State<Options>,
-) -> Option<Json<NewOrUpdateResponse>> {
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
     let _query_id = query_id;
-    let user_update = user.0;
+    let user = user?;
+    let mutation = Mutation::UserUpdate(id, user.0);
+
+    let outcome = wal.commit(&storage, &options, mutation)?;
+    match ApiError::from_outcome(outcome, "user", id) {
+        None => Ok(Json(NewOrUpdateResponse)),
+        Some(error) => Err(error),
+    }
+}
+
+#[post("/users/new", data = "<user>")]
+fn users_new(
+    user: Result<Validated<User>, ApiError>,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Created<Json<User>>, ApiError> {
+    let mut user = user?.0;
+    let outcome = wal.commit_with(&storage, &options, |tables| {
+        if user.id == 0 {
+            user.id = util::next_free_id(&tables.users)?;
+        }
+        Ok(Mutation::UserNew(user.clone()))
+    })?;
+
+    let uri = format!("/users/{}", user.id);
+    match ApiError::from_outcome(outcome, "user", user.id) {
+        None => Ok(Created(uri, Some(Json(user)))),
+        Some(error) if outcome == Outcome::AlreadyExists => Err(error.with_existing(uri)),
+        Some(error) => Err(error),
+    }
+}
 
-    let users = &mut *storage.users.write().unwrap();
-    let user_entry = users.entry(id);
-    match user_entry {
+#[delete("/users/<id>")]
+fn users_delete_no_params(
+    id: u32,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
+    users_delete(id, None, storage, options, wal)
+}
+
+#[delete("/users/<id>?<params>")]
+fn users_delete(
+    id: u32,
+    params: Option<DeleteParams>,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
+    let policy = params.and_then(|p| p.dependents).unwrap_or_default();
+    let mutation = Mutation::UserDelete(id, policy);
+
+    let outcome = wal.commit(&storage, &options, mutation)?;
+    match ApiError::from_outcome(outcome, "user", id) {
+        None => Ok(Json(NewOrUpdateResponse)),
+        Some(error) => Err(error),
+    }
+}
+
+pub fn update_user(
+    tables: &mut Tables,
+    options: &Options,
+    id: u32,
+    user_update: UserUpdate,
+) -> Outcome {
+    // The visitor record and the location sums depend on gender and birth
+    // date, so they're only redone if one of those actually changes.
+    let changes_visitor = match tables.users.get(&id) {
+        Some(user) => {
+            user_update.gender.changes(&user.gender) ||
+                user_update.birth_date.changes(&user.birth_date)
+        }
+        None => return Outcome::NotFound,
+    };
+    let visit_ids = if changes_visitor {
+        tables.user_visits.get(&id).cloned().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    marks::forget(tables, &visit_ids);
+
+    match tables.users.entry(id) {
         Entry::Occupied(mut e) => {
-            if user_update.email != "" {
-                e.get_mut().email = user_update.email;
+            let user = e.get_mut();
+            if let Some(email) = user_update.email.into_option() {
+                user.email = email;
             }
-            if user_update.birth_date != 0 {
-                e.get_mut().birth_date = user_update.birth_date;
-
-                let ages = &mut *storage.ages.write().unwrap();
-                ages.insert(
-                    id,
-                    calculate_age_from_timestamp(user_update.birth_date, options.now),
-                );
+            if let Some(birth_date) = user_update.birth_date.into_option() {
+                user.birth_date = birth_date;
             }
-            if user_update.first_name != "" {
-                e.get_mut().first_name = user_update.first_name;
+            if let Some(first_name) = user_update.first_name.into_option() {
+                user.first_name = first_name;
             }
-            if user_update.last_name != "" {
-                e.get_mut().last_name = user_update.last_name;
+            if let Some(last_name) = user_update.last_name.into_option() {
+                user.last_name = last_name;
             }
-            if user_update.gender != Gender::Unknown {
-                e.get_mut().gender = user_update.gender;
+            if let Some(gender) = user_update.gender.into_option() {
+                user.gender = gender;
             }
         }
-        Entry::Vacant(_) => return None,
+        Entry::Vacant(_) => return Outcome::NotFound,
+    }
+    if changes_visitor {
+        let visitor = Visitor::new(&tables.users[&id], options.now());
+        tables.visitors.insert(id, visitor);
     }
-    Some(Json(NewOrUpdateResponse))
+    marks::record(tables, &visit_ids);
+    Outcome::Applied
 }
 
-#[post("/users/new", data = "<user>")]
-fn users_new(
-    user: Json<User>,
-    storage: State<Storage>,
-    options: State<Options>,
-) -> Option<Json<NewOrUpdateResponse>> {
-    let user = user.0;
+pub fn insert_user(tables: &mut Tables, options: &Options, user: User) -> Outcome {
     let id = user.id;
+    if tables.users.contains_key(&id) {
+        return Outcome::AlreadyExists;
+    }
+    // Visits orphaned by an earlier delete still point at the id, and would
+    // otherwise become this user's.
+    if tables.user_visits.get(&id).map_or(false, |ids| !ids.is_empty()) {
+        return Outcome::HasOrphans;
+    }
 
-    let users = &mut *storage.users.write().unwrap();
-    let ages = &mut *storage.ages.write().unwrap();
-    let user_entry = users.entry(id);
-    match user_entry {
-        Entry::Occupied(_) => return None,
+    let visitor = Visitor::new(&user, options.now());
+    match tables.users.entry(id) {
+        Entry::Occupied(_) => return Outcome::AlreadyExists,
         Entry::Vacant(e) => {
             e.insert(User {
                 id: id,
@@ -233,13 +510,45 @@ fn users_new(
                 last_name: user.last_name,
                 gender: user.gender,
             });
-            ages.insert(
-                id,
-                calculate_age_from_timestamp(user.birth_date, options.now),
-            );
+            tables.visitors.insert(id, visitor);
         }
     }
-    Some(Json(NewOrUpdateResponse))
+    Outcome::Applied
+}
+
+/// Deletes a user, dealing with its visits according to `policy`.
+///
+/// With `DependentsPolicy::Cascade`, a user that is already gone still has
+/// the visits orphaned by its earlier delete removed, which frees the id.
+pub fn delete_user(tables: &mut Tables, id: u32, policy: DependentsPolicy) -> Outcome {
+    if !tables.users.contains_key(&id) {
+        let orphans = tables.user_visits.get(&id).map_or(false, |ids| !ids.is_empty());
+        if !orphans || policy != DependentsPolicy::Cascade {
+            return Outcome::NotFound;
+        }
+        let visit_ids = tables.user_visits.remove(&id).unwrap_or_default();
+        visits::remove_visits(tables, &visit_ids);
+        return Outcome::Applied;
+    }
+    let has_visits = tables
+        .user_visits
+        .get(&id)
+        .map_or(false, |ids| !ids.is_empty());
+    if has_visits && policy == DependentsPolicy::Reject {
+        return Outcome::HasDependents;
+    }
+
+    let visit_ids = tables.user_visits.get(&id).cloned().unwrap_or_default();
+    if policy == DependentsPolicy::Cascade {
+        visits::remove_visits(tables, &visit_ids);
+        tables.user_visits.remove(&id);
+    }
+    // Orphaned visits move to the location sums for unknown users.
+    marks::forget(tables, &visit_ids);
+    tables.users.remove(&id);
+    tables.visitors.remove(&id);
+    marks::record(tables, &visit_ids);
+    Outcome::Applied
 }
 
 pub fn calculate_age_from_timestamp(birth_date_timestamp: i32, now_timestamp: i32) -> i32 {
@@ -1,5 +1,29 @@
-use serde::{Serialize, Serializer};
+use rocket::http::RawStr;
+use rocket::request::FromFormValue;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::ser::SerializeMap;
+use idmap::IdMap;
+use serde_json;
+use wal::Outcome;
+
+use std::cmp::{self, Ordering};
+use std::path::{Component, Path, PathBuf};
+
+/// `relative` inside `root`, for paths that HTTP clients give. `None` if it's
+/// empty, absolute or climbs out with `..`.
+pub fn path_under(root: &Path, relative: &str) -> Option<PathBuf> {
+    let path = Path::new(relative);
+    if relative.is_empty() {
+        return None;
+    }
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(root.join(path))
+}
 
 #[derive(FromForm)]
 pub struct QueryId {
@@ -8,6 +32,41 @@ pub struct QueryId {
 
 pub struct NewOrUpdateResponse;
 
+/// What to do with visits of a user or location being deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DependentsPolicy {
+    /// Refuse to delete while any visit references the entity.
+    Reject,
+    /// Delete the referencing visits too.
+    Cascade,
+    /// Keep the visits; readers skip them where the entity is needed.
+    Orphan,
+}
+
+impl Default for DependentsPolicy {
+    fn default() -> Self {
+        DependentsPolicy::Reject
+    }
+}
+
+impl<'v> FromFormValue<'v> for DependentsPolicy {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<DependentsPolicy, &'v RawStr> {
+        match form_value.as_str() {
+            "reject" => Ok(DependentsPolicy::Reject),
+            "cascade" => Ok(DependentsPolicy::Cascade),
+            "orphan" => Ok(DependentsPolicy::Orphan),
+            _ => Err(form_value),
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct DeleteParams {
+    pub dependents: Option<DependentsPolicy>,
+}
+
 impl Serialize for NewOrUpdateResponse {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -17,3 +76,203 @@ impl Serialize for NewOrUpdateResponse {
         map.end()
     }
 }
+
+/// A field of a partial update: left out, explicitly `null`, or set.
+///
+/// Use with `#[serde(default, skip_serializing_if = "Patch::is_absent")]` so
+/// a missing key stays `Absent`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Patch<T> {
+    Absent,
+    Null,
+    Value(T),
+}
+
+impl<T> Default for Patch<T> {
+    fn default() -> Self {
+        Patch::Absent
+    }
+}
+
+impl<T> Patch<T> {
+    pub fn is_absent(&self) -> bool {
+        match *self {
+            Patch::Absent => true,
+            _ => false,
+        }
+    }
+
+    /// The value to write, if any. Validation turns `Null` into a 400 before
+    /// an update gets this far.
+    pub fn into_option(self) -> Option<T> {
+        match self {
+            Patch::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Whether writing the patch would change `current`.
+    pub fn changes(&self, current: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        match *self {
+            Patch::Value(ref value) => value != current,
+            _ => false,
+        }
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Patch<T>
+where
+    T: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::deserialize(deserializer).map(|value| match value {
+            Some(value) => Patch::Value(value),
+            None => Patch::Null,
+        })
+    }
+}
+
+impl<T> Serialize for Patch<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match *self {
+            Patch::Value(ref value) => serializer.serialize_some(value),
+            _ => serializer.serialize_none(),
+        }
+    }
+}
+
+/// The id after the largest one ever used, for creations that leave the id
+/// to the server. Fails with `Outcome::IdsExhausted` once that's `u32::MAX`.
+pub fn next_free_id<T: Clone>(entities: &IdMap<T>) -> Result<u32, Outcome> {
+    match entities.max_id() {
+        Some(id) => id.checked_add(1).ok_or(Outcome::IdsExhausted),
+        None => Ok(1),
+    }
+}
+
+pub const DEFAULT_PAGE_LIMIT: usize = 100;
+pub const MAX_PAGE_LIMIT: usize = 10000;
+
+/// Paging and sorting parameters shared by the collection listings.
+///
+/// Either `offset` or `after` may be given, not both. `after` is a cursor:
+/// the sort key and id of the last entity of the previous page, as returned
+/// in `next`, encoded as a JSON array `[key, id]`. The page starts right
+/// after that place in the order, whether or not the entity is still there.
+pub struct PageRequest<'a> {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub after: Option<&'a str>,
+    pub sort: Option<&'a str>,
+    pub order: Option<&'a str>,
+}
+
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub next: Option<String>,
+}
+
+/// The value of the field a listing is sorted by, as kept in cursors. It has
+/// to order entities the same way the listing's `compare` does.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(untagged)]
+pub enum SortKey {
+    Int(i64),
+    Str(String),
+}
+
+/// Sorts `items` by the requested field (ties broken by id) and cuts out the
+/// requested page. `None` means the request was malformed.
+pub fn paginate<T, F, K, G>(
+    mut items: Vec<T>,
+    request: PageRequest,
+    sort_fields: &[&str],
+    compare: F,
+    key_of: K,
+    id_of: G,
+) -> Option<Page<T>>
+where
+    F: Fn(&str, &T, &T) -> Ordering,
+    K: Fn(&str, &T) -> SortKey,
+    G: Fn(&T) -> u32,
+{
+    let field = request.sort.unwrap_or("id");
+    if !sort_fields.contains(&field) {
+        return None;
+    }
+    let descending = match request.order {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return None,
+    };
+    let limit = request.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit == 0 || limit > MAX_PAGE_LIMIT {
+        return None;
+    }
+    if request.offset.is_some() && request.after.is_some() {
+        return None;
+    }
+    let after: Option<(SortKey, u32)> = match request.after {
+        Some(cursor) => match serde_json::from_str(cursor) {
+            Ok(cursor) => Some(cursor),
+            Err(_) => return None,
+        },
+        None => None,
+    };
+
+    items.sort_by(|a, b| {
+        let ordering = compare(field, a, b).then_with(|| id_of(a).cmp(&id_of(b)));
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+
+    let total = items.len();
+    let start = match after {
+        // Everything past the cursor is at the end of the sorted items.
+        Some(cursor) => {
+            let past = if descending {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+            let search = items.binary_search_by(|item| {
+                if (key_of(field, item), id_of(item)).cmp(&cursor) == past {
+                    Ordering::Greater
+                } else {
+                    Ordering::Less
+                }
+            });
+            search.unwrap_or_else(|position| position)
+        }
+        None => request.offset.unwrap_or(0),
+    };
+    let end = cmp::min(start.saturating_add(limit), total);
+    let next = if start < end && end < total {
+        let last = &items[end - 1];
+        serde_json::to_string(&(key_of(field, last), id_of(last))).ok()
+    } else {
+        None
+    };
+
+    Some(Page {
+        items: items.into_iter().skip(start).take(limit).collect(),
+        total: total,
+        next: next,
+    })
+}
@@ -0,0 +1,228 @@
+use Options;
+use error::ApiError;
+
+use rocket::{Outcome, Request, State};
+use rocket::data::{self, Data, FromData};
+use rocket::http::Status;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
+
+use std::io::Read;
+
+const LIMIT: u64 = 1 << 20;
+
+/// 1930-01-01. Birth dates run from here to the clock's now.
+pub const MIN_BIRTH_DATE: i64 = -1262304000;
+/// 2000-01-01. Visits run from here to the clock's now.
+pub const MIN_VISITED_AT: i64 = 946684800;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub field: String,
+    pub message: String,
+}
+
+/// Collects violations for one JSON object. In a partial check (updates)
+/// missing fields are fine; an explicit `null` never is.
+pub struct Checker<'a> {
+    object: &'a Map<String, Value>,
+    partial: bool,
+    /// The latest timestamp a field may hold.
+    now: i64,
+    violations: Vec<Violation>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(object: &'a Map<String, Value>, partial: bool, now: i32) -> Checker<'a> {
+        Checker {
+            object: object,
+            partial: partial,
+            now: now as i64,
+            violations: Vec::new(),
+        }
+    }
+
+    pub fn into_violations(self) -> Vec<Violation> {
+        self.violations
+    }
+
+    fn violation(&mut self, field: &str, message: String) {
+        self.violations.push(Violation {
+            field: field.to_owned(),
+            message: message,
+        });
+    }
+
+    fn field(&mut self, name: &str) -> Option<&'a Value> {
+        let object = self.object;
+        match object.get(name) {
+            Some(&Value::Null) => {
+                self.violation(name, "must not be null".to_owned());
+                None
+            }
+            Some(value) => Some(value),
+            None => {
+                if !self.partial {
+                    self.violation(name, "is required".to_owned());
+                }
+                None
+            }
+        }
+    }
+
+    pub fn integer(&mut self, name: &str, min: i64, max: i64) {
+        let value = match self.field(name) {
+            Some(value) => value,
+            None => return,
+        };
+        match value.as_i64() {
+            Some(n) if n >= min && n <= max => {}
+            Some(_) => self.violation(name, format!("must be between {} and {}", min, max)),
+            None => self.violation(name, "must be an integer".to_owned()),
+        }
+    }
+
+    /// A timestamp from `min` up to now.
+    pub fn timestamp(&mut self, name: &str, min: i64) {
+        let now = self.now;
+        self.integer(name, min, now)
+    }
+
+    pub fn id(&mut self, name: &str) {
+        self.integer(name, 1, u32::max_value() as i64)
+    }
+
+    /// Like `id`, but the field may be left out even in a full check.
+    pub fn optional_id(&mut self, name: &str) {
+        if self.object.contains_key(name) {
+            self.id(name);
+        }
+    }
+
+    pub fn string(&mut self, name: &str, max_chars: usize) {
+        let value = match self.field(name) {
+            Some(value) => value,
+            None => return,
+        };
+        match value.as_str() {
+            Some(s) if s.is_empty() => self.violation(name, "must not be empty".to_owned()),
+            Some(s) if s.chars().count() > max_chars => {
+                self.violation(name, format!("must be at most {} characters", max_chars))
+            }
+            Some(_) => {}
+            None => self.violation(name, "must be a string".to_owned()),
+        }
+    }
+
+    pub fn email(&mut self, name: &str, max_chars: usize) {
+        let before = self.violations.len();
+        self.string(name, max_chars);
+        if self.violations.len() != before {
+            return;
+        }
+        let object = self.object;
+        let valid = match object.get(name).and_then(|v| v.as_str()) {
+            Some(s) => is_email(s),
+            None => return,
+        };
+        if !valid {
+            self.violation(name, "must be an email address".to_owned());
+        }
+    }
+
+    pub fn one_of(&mut self, name: &str, allowed: &[&str]) {
+        let value = match self.field(name) {
+            Some(value) => value,
+            None => return,
+        };
+        if !value.as_str().map_or(false, |s| allowed.contains(&s)) {
+            self.violation(name, format!("must be one of {:?}", allowed));
+        }
+    }
+}
+
+fn is_email(s: &str) -> bool {
+    if s.chars().any(|c| c.is_whitespace()) {
+        return false;
+    }
+    let mut parts = s.split('@');
+    let (local, domain) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => (local, domain),
+        _ => return false,
+    };
+    !local.is_empty() && domain.split('.').count() >= 2
+        && domain.split('.').all(|label| !label.is_empty())
+}
+
+/// A request body that can be checked field by field before it's
+/// deserialized.
+pub trait Validate: DeserializeOwned {
+    /// Whether fields may be left out, as in updates.
+    fn is_partial() -> bool {
+        false
+    }
+
+    fn validate(checker: &mut Checker);
+}
+
+/// What in `object` breaks `T`'s rules, with timestamps up to `now`.
+pub fn violations<T: Validate>(object: &Map<String, Value>, now: i32) -> Vec<Violation> {
+    let mut checker = Checker::new(object, T::is_partial(), now);
+    T::validate(&mut checker);
+    checker.into_violations()
+}
+
+/// Checks `value` against `T`'s rules, with timestamps up to `now`, and
+/// deserializes it.
+pub fn validate_value<T: Validate>(value: Value, now: i32) -> Result<T, ApiError> {
+    let violations = match value.as_object() {
+        Some(object) => violations::<T>(object, now),
+        None => {
+            return Err(ApiError::new(
+                Status::BadRequest,
+                "malformed_json",
+                "expected a JSON object".to_owned(),
+            ))
+        }
+    };
+    if !violations.is_empty() {
+        return Err(ApiError::violations(violations));
+    }
+    serde_json::from_value(value)
+        .map_err(|e| ApiError::new(Status::BadRequest, "malformed_json", e.to_string()))
+}
+
+/// Like `Json<T>`, but the body has passed `T::validate` first.
+#[derive(Debug)]
+pub struct Validated<T>(pub T);
+
+impl<T: Validate> FromData for Validated<T> {
+    type Error = ApiError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, ApiError> {
+        if !request.content_type().map_or(false, |ct| ct.is_json()) {
+            return Outcome::Forward(data);
+        }
+
+        let now = match request.guard::<State<Options>>() {
+            Outcome::Success(options) => options.now(),
+            _ => {
+                let message = "no clock to check timestamps against".to_owned();
+                let error = ApiError::new(Status::InternalServerError, "no_clock", message);
+                return Outcome::Failure((Status::InternalServerError, error));
+            }
+        };
+        let limit = request.limits().get("json").unwrap_or(LIMIT);
+        let value: Value = match serde_json::from_reader(data.open().take(limit)) {
+            Ok(value) => value,
+            Err(e) => {
+                let error = ApiError::new(Status::BadRequest, "malformed_json", e.to_string());
+                return Outcome::Failure((Status::BadRequest, error));
+            }
+        };
+        match validate_value(value, now) {
+            Ok(entity) => Outcome::Success(Validated(entity)),
+            Err(error) => Outcome::Failure((Status::BadRequest, error)),
+        }
+    }
+}
@@ -1,14 +1,25 @@
+use Options;
 use QueryId;
 use Storage;
-use util::NewOrUpdateResponse;
+use Tables;
+use error::ApiError;
+use idmap::{HeapSize, IdMap};
+use marks;
+use util::{self, NewOrUpdateResponse, PageRequest, Patch, SortKey};
+use validation::{self, Checker, Validate, Validated};
+use wal::{Mutation, Outcome, Wal};
 
 use rocket::State;
+use rocket::response::status::Created;
 use rocket_contrib::Json;
 
-use std::collections::hash_map::Entry;
+use std::cmp::Ordering;
+use std::sync::Arc;
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Visit {
+    /// 0 in a create request that leaves the id to the server.
+    #[serde(default)]
     pub id: u32,
     pub location: u32,
     pub user: u32,
@@ -16,164 +27,400 @@ pub struct Visit {
     pub mark: u8,
 }
 
+impl HeapSize for Visit {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct VisitUpdate {
-    #[serde(default)] location: u32,
-    #[serde(default)] user: u32,
-    #[serde(default)] visited_at: i32,
-    #[serde(default)] mark: u8,
+pub struct VisitUpdate {
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    location: Patch<u32>,
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    user: Patch<u32>,
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    visited_at: Patch<i32>,
+    #[serde(default, skip_serializing_if = "Patch::is_absent")]
+    mark: Patch<u8>,
+}
+
+fn check_visit_fields(checker: &mut Checker) {
+    checker.id("location");
+    checker.id("user");
+    checker.timestamp("visited_at", validation::MIN_VISITED_AT);
+    checker.integer("mark", 0, 5);
+}
+
+impl Validate for Visit {
+    fn validate(checker: &mut Checker) {
+        checker.optional_id("id");
+        check_visit_fields(checker);
+    }
+}
+
+impl Validate for VisitUpdate {
+    fn is_partial() -> bool {
+        true
+    }
+
+    fn validate(checker: &mut Checker) {
+        check_visit_fields(checker);
+    }
+}
+
+#[derive(FromForm, Default)]
+struct VisitsListParams {
+    limit: Option<usize>,
+    offset: Option<usize>,
+    after: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    user: Option<u32>,
+    location: Option<u32>,
+    mark: Option<u8>,
+    #[form(field = "fromDate")] from_date: Option<i32>,
+    #[form(field = "toDate")] to_date: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct VisitsList {
+    visits: Vec<Visit>,
+    total: usize,
+    next: Option<String>,
+}
+
+const VISIT_SORT_FIELDS: &[&str] = &["id", "location", "user", "visited_at", "mark"];
+
+#[get("/visits")]
+fn visits_list_no_params(storage: State<Arc<Storage>>) -> Result<Json<VisitsList>, ApiError> {
+    visits_list(None, storage)
+}
+
+#[get("/visits?<params>")]
+fn visits_list(
+    params: Option<VisitsListParams>,
+    storage: State<Arc<Storage>>,
+) -> Result<Json<VisitsList>, ApiError> {
+    let params = params.unwrap_or_default();
+
+    let tables = storage.read();
+    let all_visits = &tables.visits;
+    let filtered_visits: Vec<&Visit> = all_visits
+        .values()
+        .filter(|v| params.user.map_or(true, |u| u == v.user))
+        .filter(|v| params.location.map_or(true, |l| l == v.location))
+        .filter(|v| params.mark.map_or(true, |m| m == v.mark))
+        .filter(|v| params.from_date.map_or(true, |d| d < v.visited_at))
+        .filter(|v| params.to_date.map_or(true, |d| d > v.visited_at))
+        .collect();
+
+    let page_request = PageRequest {
+        limit: params.limit,
+        offset: params.offset,
+        after: params.after.as_ref().map(|s| s.as_str()),
+        sort: params.sort.as_ref().map(|s| s.as_str()),
+        order: params.order.as_ref().map(|s| s.as_str()),
+    };
+    let page = match util::paginate(
+        filtered_visits,
+        page_request,
+        VISIT_SORT_FIELDS,
+        |field, a, b| compare_visits(field, a, b),
+        |field, v| visit_sort_key(field, v),
+        |v| v.id,
+    ) {
+        Some(page) => page,
+        None => return Err(ApiError::invalid_query("invalid sort, order, limit or cursor")),
+    };
+
+    Ok(Json(VisitsList {
+        visits: page.items.into_iter().cloned().collect(),
+        total: page.total,
+        next: page.next,
+    }))
+}
+
+fn compare_visits(field: &str, a: &Visit, b: &Visit) -> Ordering {
+    match field {
+        "location" => a.location.cmp(&b.location),
+        "user" => a.user.cmp(&b.user),
+        "visited_at" => a.visited_at.cmp(&b.visited_at),
+        "mark" => a.mark.cmp(&b.mark),
+        _ => a.id.cmp(&b.id),
+    }
+}
+
+fn visit_sort_key(field: &str, visit: &Visit) -> SortKey {
+    match field {
+        "location" => SortKey::Int(visit.location as i64),
+        "user" => SortKey::Int(visit.user as i64),
+        "visited_at" => SortKey::Int(visit.visited_at as i64),
+        "mark" => SortKey::Int(visit.mark as i64),
+        _ => SortKey::Int(visit.id as i64),
+    }
 }
 
 #[get("/visits/<id>")]
-fn visits(id: u32, storage: State<Storage>) -> Option<Json<Visit>> {
+fn visits(id: u32, storage: State<Arc<Storage>>) -> Result<Json<Visit>, ApiError> {
     storage
-        .visits
         .read()
-        .unwrap()
+        .visits
         .get(&id)
         .map(|entity| Json(entity.clone()))
+        .ok_or_else(|| ApiError::not_found("visit", id))
 }
 
 #[post("/visits/<id>?<query_id>", data = "<visit>")]
 fn visits_update(
     id: u32,
-    visit: Json<VisitUpdate>,
+    visit: Result<Validated<VisitUpdate>, ApiError>,
     query_id: QueryId,
-    storage: State<Storage>,
-) -> Option<Json<NewOrUpdateResponse>> {
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
     let _query_id = query_id;
-    let visit_update = visit.0;
-
-    let visits = &mut storage.visits.write().unwrap();
-    let visit_entry = visits.entry(id);
-    match visit_entry {
-        Entry::Occupied(mut e) => {
-            if visit_update.location != 0 {
-                let location_visits_ids = &mut storage.location_visits.write().unwrap();
-                let new_visit_location = visit_update.location;
-                let old_visit_location = e.get().location;
-
-                let old_location_visits_ids = location_visits_ids[&old_visit_location]
-                    .iter()
-                    .map(|visit_id| *visit_id)
-                    .filter(|visit_id| *visit_id != id)
-                    .collect();
-
-                {
-                    let new_location_visits_ids_entry =
-                        location_visits_ids.entry(new_visit_location);
-                    match new_location_visits_ids_entry {
-                        Entry::Occupied(mut e) => {
-                            e.get_mut().push(id);
-                        }
-                        Entry::Vacant(e) => {
-                            e.insert(vec![id]);
-                        }
-                    }
-                }
+    let visit = visit?.0;
+    // Checked before logging so a refused update leaves no record behind.
+    let outcome = wal.commit_with(&storage, &options, |tables| {
+        if !tables.visits.contains_key(&id) {
+            return Err(Outcome::NotFound);
+        }
+        let user = visit.user.clone().into_option();
+        let location = visit.location.clone().into_option();
+        match find_missing_reference(tables, user, location) {
+            Some(outcome) => Err(outcome),
+            None => Ok(Mutation::VisitUpdate(id, visit)),
+        }
+    })?;
+    match ApiError::from_outcome(outcome, "visit", id) {
+        None => Ok(Json(NewOrUpdateResponse)),
+        Some(error) => Err(error),
+    }
+}
 
-                let new_location_visits_ids = location_visits_ids[&new_visit_location].clone();
+#[post("/visits/new", data = "<visit>")]
+fn visits_new(
+    visit: Result<Validated<Visit>, ApiError>,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Created<Json<Visit>>, ApiError> {
+    let mut visit = visit?.0;
+    let outcome = wal.commit_with(&storage, &options, |tables| {
+        if visit.id == 0 {
+            visit.id = util::next_free_id(&tables.visits)?;
+        }
+        match find_missing_reference(tables, Some(visit.user), Some(visit.location)) {
+            Some(outcome) => Err(outcome),
+            None => Ok(Mutation::VisitNew(visit.clone())),
+        }
+    })?;
 
-                location_visits_ids.insert(old_visit_location, old_location_visits_ids);
-                location_visits_ids.insert(new_visit_location, new_location_visits_ids);
-                e.get_mut().location = visit_update.location;
-            };
-            if visit_update.mark != 0 {
-                e.get_mut().mark = visit_update.mark;
-            }
-            if visit_update.user != 0 {
-                let user_visits_ids = &mut storage.user_visits.write().unwrap();
-                let new_visit_user = visit_update.user;
-                let old_visit_user = e.get().user;
-
-                let old_user_visits_ids = user_visits_ids[&old_visit_user]
-                    .iter()
-                    .map(|visit_id| *visit_id)
-                    .filter(|visit_id| *visit_id != id)
-                    .collect();
-
-                {
-                    let new_user_visits_ids_entry = user_visits_ids.entry(new_visit_user);
-                    match new_user_visits_ids_entry {
-                        Entry::Occupied(mut e) => {
-                            e.get_mut().push(id);
-                        }
-                        Entry::Vacant(e) => {
-                            e.insert(vec![id]);
-                        }
-                    }
-                }
+    let uri = format!("/visits/{}", visit.id);
+    match ApiError::from_outcome(outcome, "visit", visit.id) {
+        None => Ok(Created(uri, Some(Json(visit)))),
+        Some(error) if outcome == Outcome::AlreadyExists => Err(error.with_existing(uri)),
+        Some(error) => Err(error),
+    }
+}
+
+#[delete("/visits/<id>")]
+fn visits_delete(
+    id: u32,
+    storage: State<Arc<Storage>>,
+    options: State<Options>,
+    wal: State<Arc<Wal>>,
+) -> Result<Json<NewOrUpdateResponse>, ApiError> {
+    let mutation = Mutation::VisitDelete(id);
 
-                let new_user_visits_ids = user_visits_ids[&new_visit_user].clone();
+    let outcome = wal.commit(&storage, &options, mutation)?;
+    match ApiError::from_outcome(outcome, "visit", id) {
+        None => Ok(Json(NewOrUpdateResponse)),
+        Some(error) => Err(error),
+    }
+}
 
-                user_visits_ids.insert(old_visit_user, old_user_visits_ids);
-                user_visits_ids.insert(new_visit_user, new_user_visits_ids);
+pub fn update_visit(tables: &mut Tables, id: u32, visit_update: VisitUpdate) -> Outcome {
+    if !tables.visits.contains_key(&id) {
+        return Outcome::NotFound;
+    }
+    let new_user = visit_update.user.into_option();
+    let new_location = visit_update.location.into_option();
+    if let Some(outcome) = find_missing_reference(tables, new_user, new_location) {
+        return outcome;
+    }
 
-                e.get_mut().user = visit_update.user;
+    marks::forget(tables, &[id]);
+    // Set to the visit's old user if it has to move in `user_visits`.
+    let mut retimed_from = None;
+    // Set to the visit's old location if it has to move in `location_visits`.
+    let mut moved_from = None;
+    match tables.visits.get_mut(&id) {
+        Some(visit) => {
+            if let Some(location) = new_location {
+                if location != visit.location {
+                    moved_from = Some(visit.location);
+                    visit.location = location;
+                }
             }
-            if visit_update.visited_at != 0 {
-                e.get_mut().visited_at = visit_update.visited_at;
+            if let Some(mark) = visit_update.mark.into_option() {
+                visit.mark = mark;
+            }
+            let old_user = visit.user;
+            let old_visited_at = visit.visited_at;
+            if let Some(user) = new_user {
+                visit.user = user;
+            }
+            if let Some(visited_at) = visit_update.visited_at.into_option() {
+                visit.visited_at = visited_at;
+            }
+            if visit.user != old_user || visit.visited_at != old_visited_at {
+                retimed_from = Some(old_user);
             }
         }
-        Entry::Vacant(_) => return None,
+        None => return Outcome::NotFound,
+    }
+    if let Some(old_location) = moved_from {
+        if let Some(ids) = tables.location_visits.get_mut(&old_location) {
+            ids.retain(|visit_id| *visit_id != id);
+        }
+        let location = tables.visits[&id].location;
+        tables.location_visits.entry(location).or_insert_with(Vec::new).push(id);
+    }
+    if let Some(old_user) = retimed_from {
+        remove_from_timeline(tables, old_user, id);
+        add_to_timeline(tables, id);
     }
-    Some(Json(NewOrUpdateResponse))
+    marks::record(tables, &[id]);
+    Outcome::Applied
 }
 
-#[post("/visits/new", data = "<visit>")]
-fn visits_new(visit: Json<Visit>, storage: State<Storage>) -> Option<Json<NewOrUpdateResponse>> {
-    let visit = visit.0;
+pub fn insert_visit(tables: &mut Tables, visit: Visit) -> Outcome {
     let id = visit.id;
 
-    let visits = &mut storage.visits.write().unwrap();
-    let visit_entry = visits.entry(id);
-    match visit_entry {
-        Entry::Occupied(_) => return None,
-        Entry::Vacant(e) => {
-            let location_visits_ids = &mut storage.location_visits.write().unwrap();
-            let new_visit_location = visit.location;
-
-            {
-                let new_location_visits_ids_entry = location_visits_ids.entry(new_visit_location);
-                match new_location_visits_ids_entry {
-                    Entry::Occupied(mut e) => {
-                        e.get_mut().push(id);
-                    }
-                    Entry::Vacant(e) => {
-                        e.insert(vec![id]);
-                    }
-                }
-            }
-            let new_location_visits_ids = location_visits_ids[&new_visit_location].clone();
-
-            location_visits_ids.insert(new_visit_location, new_location_visits_ids);
-
-            let user_visits_ids = &mut storage.user_visits.write().unwrap();
-            let new_visit_user = visit.user;
-
-            {
-                let new_user_visits_ids_entry = user_visits_ids.entry(new_visit_user);
-                match new_user_visits_ids_entry {
-                    Entry::Occupied(mut e) => {
-                        e.get_mut().push(id);
-                    }
-                    Entry::Vacant(e) => {
-                        e.insert(vec![id]);
-                    }
-                }
-            }
-            let new_user_visits_ids = user_visits_ids[&new_visit_user].clone();
+    if tables.visits.contains_key(&id) {
+        return Outcome::AlreadyExists;
+    }
+    if let Some(outcome) =
+        find_missing_reference(tables, Some(visit.user), Some(visit.location))
+    {
+        return outcome;
+    }
+
+    tables
+        .location_visits
+        .entry(visit.location)
+        .or_insert_with(Vec::new)
+        .push(id);
+    tables.visits.insert(id, visit);
+    add_to_timeline(tables, id);
+    marks::record(tables, &[id]);
+    Outcome::Applied
+}
+
+/// Orders a user's visits by `visited_at`, then id. Ids of visits that don't
+/// exist sort first.
+pub fn timeline_key(visits: &IdMap<Visit>, id: u32) -> (i32, u32) {
+    (visits.get(&id).map_or(i32::min_value(), |v| v.visited_at), id)
+}
 
-            user_visits_ids.insert(new_visit_user, new_user_visits_ids);
+// The visit has to be in `visits` already.
+fn add_to_timeline(tables: &mut Tables, id: u32) {
+    let visits = &tables.visits;
+    let key = timeline_key(visits, id);
+    let ids = tables.user_visits.entry(visits[&id].user).or_insert_with(Vec::new);
+    let position = ids.binary_search_by(|other| timeline_key(visits, *other).cmp(&key))
+        .unwrap_or_else(|position| position);
+    ids.insert(position, id);
+}
+
+/// Sorts every list in `user_visits`, for tables built without going through
+/// `insert_visit`.
+pub fn sort_timelines(tables: &mut Tables) {
+    let users: Vec<u32> = tables.user_visits.keys().collect();
+    for user in users {
+        let visits = &tables.visits;
+        if let Some(ids) = tables.user_visits.get_mut(&user) {
+            ids.sort_by_key(|id| timeline_key(visits, *id));
+        }
+    }
+}
+
+fn remove_from_timeline(tables: &mut Tables, user: u32, id: u32) {
+    if let Some(ids) = tables.user_visits.get_mut(&user) {
+        ids.retain(|visit_id| *visit_id != id);
+    }
+}
+
+// The number of leading ids whose visit time satisfies `before`.
+fn count_before<F>(visits: &IdMap<Visit>, ids: &[u32], before: F) -> usize
+where
+    F: Fn(i32) -> bool,
+{
+    let search = ids.binary_search_by(|id| if before(timeline_key(visits, *id).0) {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    });
+    search.unwrap_or_else(|position| position)
+}
 
-            e.insert(Visit {
-                id: id,
-                location: visit.location,
-                mark: visit.mark,
-                user: visit.user,
-                visited_at: visit.visited_at,
-            });
+/// The part of a user's list in `user_visits` with `from < visited_at < to`.
+pub fn timeline_range<'a>(
+    visits: &IdMap<Visit>,
+    ids: &'a [u32],
+    from: Option<i32>,
+    to: Option<i32>,
+) -> &'a [u32] {
+    let start = from.map_or(0, |from| count_before(visits, ids, |t| t <= from));
+    let end = to.map_or(ids.len(), |to| count_before(visits, ids, |t| t < to));
+    if end <= start {
+        &[]
+    } else {
+        &ids[start..end]
+    }
+}
+
+// Handlers check this before logging; `insert_visit` and `update_visit`
+// check again for records logged before they did.
+fn find_missing_reference(
+    tables: &Tables,
+    user: Option<u32>,
+    location: Option<u32>,
+) -> Option<Outcome> {
+    if let Some(user) = user {
+        if !tables.users.contains_key(&user) {
+            return Some(Outcome::MissingReference("user", user));
+        }
+    }
+    if let Some(location) = location {
+        if !tables.locations.contains_key(&location) {
+            return Some(Outcome::MissingReference("location", location));
+        }
+    }
+    None
+}
+
+pub fn delete_visit(tables: &mut Tables, id: u32) -> Outcome {
+    if !tables.visits.contains_key(&id) {
+        return Outcome::NotFound;
+    }
+    remove_visits(tables, &[id]);
+    Outcome::Applied
+}
+
+/// Removes visits from `visits`, both indexes and the location sums.
+pub fn remove_visits(tables: &mut Tables, ids: &[u32]) {
+    marks::forget(tables, ids);
+    let removed: Vec<Visit> = ids.iter().filter_map(|id| tables.visits.remove(id)).collect();
+    for visit in &removed {
+        if let Some(visit_ids) = tables.location_visits.get_mut(&visit.location) {
+            visit_ids.retain(|visit_id| *visit_id != visit.id);
+        }
+        if let Some(visit_ids) = tables.user_visits.get_mut(&visit.user) {
+            visit_ids.retain(|visit_id| *visit_id != visit.id);
         }
     }
-    Some(Json(NewOrUpdateResponse))
 }
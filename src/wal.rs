@@ -0,0 +1,440 @@
+use Options;
+use Storage;
+use Tables;
+use locations::{self, Location, LocationUpdate};
+use status;
+use users::{self, User, UserUpdate};
+use util::DependentsPolicy;
+use visits::{self, Visit, VisitUpdate};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde_json;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+// Every record is `[payload length: u32][crc32 of payload: u32][payload]`,
+// both integers little-endian, payload is a JSON-encoded `Mutation`.
+const RECORD_HEADER_LEN: usize = 8;
+// Anything larger than this is treated as a corrupted length field.
+const MAX_RECORD_LEN: u32 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Mutation {
+    UserNew(User),
+    UserUpdate(u32, UserUpdate),
+    LocationNew(Location),
+    LocationUpdate(u32, LocationUpdate),
+    VisitNew(Visit),
+    VisitUpdate(u32, VisitUpdate),
+    UserDelete(u32, DependentsPolicy),
+    LocationDelete(u32, DependentsPolicy),
+    VisitDelete(u32),
+}
+
+/// What applying a `Mutation` did to `Storage`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Applied,
+    NotFound,
+    AlreadyExists,
+    /// Deletion refused because visits still reference the entity.
+    HasDependents,
+    /// Creation refused because visits orphaned when an entity with the same
+    /// id was deleted still reference it.
+    HasOrphans,
+    /// Every id up to `u32::MAX` was used, so the server can't pick one.
+    IdsExhausted,
+    /// A visit would point at a user or location that doesn't exist; carries
+    /// the field name and the id.
+    MissingReference(&'static str, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// fsync after every record.
+    Always,
+    /// fsync after every N records.
+    Every(u32),
+    /// Leave flushing to the OS.
+    Never,
+}
+
+impl SyncPolicy {
+    pub fn parse(value: &str) -> Option<SyncPolicy> {
+        match value {
+            "always" => Some(SyncPolicy::Always),
+            "never" => Some(SyncPolicy::Never),
+            _ => match value.parse::<u32>() {
+                Ok(0) | Err(_) => None,
+                Ok(n) => Some(SyncPolicy::Every(n)),
+            },
+        }
+    }
+}
+
+struct WalWriter {
+    file: File,
+    policy: SyncPolicy,
+    unsynced: u32,
+    /// Set when a failed append couldn't be rolled back, so nothing gets
+    /// written after the torn bytes.
+    poisoned: bool,
+    /// Copies of the records appended since `Wal::capture`.
+    captured: Option<Vec<Vec<u8>>>,
+}
+
+impl WalWriter {
+    // Writes one record. If that fails the log is cut back to where it was,
+    // so a torn record never ends up in front of later ones.
+    fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::new(
+                ErrorKind::Other,
+                "the WAL is unusable after a failed write",
+            ));
+        }
+        let start = self.file.seek(SeekFrom::Current(0))?;
+        let result = self.write_record(payload);
+        if result.is_err() {
+            let rolled_back = self.file
+                .set_len(start)
+                .and_then(|_| self.file.seek(SeekFrom::Start(start)));
+            if let Err(e) = rolled_back {
+                println!("Couldn't cut a failed WAL record off at {}: {}", start, e);
+                self.poisoned = true;
+            }
+        }
+        result
+    }
+
+    // Appends `payload` and keeps a copy of it if records are being captured.
+    fn log(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        self.append(&payload)?;
+        if let Some(ref mut captured) = self.captured {
+            captured.push(payload);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    fn write_record(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.write_u32::<LittleEndian>(crc32(payload))?;
+        record.extend_from_slice(payload);
+        self.file.write_all(&record)?;
+
+        match self.policy {
+            SyncPolicy::Always => self.file.sync_data()?,
+            SyncPolicy::Every(n) => {
+                self.unsynced += 1;
+                if self.unsynced >= n {
+                    self.file.sync_data()?;
+                    self.unsynced = 0;
+                }
+            }
+            SyncPolicy::Never => {}
+        }
+        Ok(())
+    }
+}
+
+/// Append-only log of accepted mutations.
+///
+/// Mutations are written to the log before they are applied to `Storage`, and
+/// the writer lock is held while applying, so the order of records in the log
+/// is exactly the order in which they hit memory. A mutation that turns out to
+/// be a no-op (e.g. a duplicate id) is logged too; it is a no-op on replay as
+/// well.
+pub struct Wal {
+    writer: Mutex<WalWriter>,
+}
+
+impl Wal {
+    pub fn open(path: &Path, policy: SyncPolicy) -> io::Result<Wal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        Ok(Wal {
+            writer: Mutex::new(WalWriter {
+                file: file,
+                policy: policy,
+                unsynced: 0,
+                poisoned: false,
+                captured: None,
+            }),
+        })
+    }
+
+    /// Applies every intact record to `storage` and positions the log for
+    /// appending. A torn or corrupted last record (e.g. from a crash
+    /// mid-write) is truncated away. A corrupted record with more after it,
+    /// or one that is intact but can't be decoded, fails the replay and
+    /// leaves the log as it is.
+    pub fn replay(&self, storage: &Storage, options: &Options) -> io::Result<usize> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.file.seek(SeekFrom::Start(0))?;
+
+        let (count, valid_len) = apply_records(&writer.file, storage, options)?;
+
+        let file_len = writer.file.metadata()?.len();
+        if valid_len < file_len {
+            check_tail(&writer.file, valid_len, file_len)?;
+            println!(
+                "Truncating {} bytes of torn WAL tail at offset {}",
+                file_len - valid_len,
+                valid_len
+            );
+            writer.file.set_len(valid_len)?;
+            writer.file.sync_data()?;
+        }
+        writer.file.seek(SeekFrom::Start(valid_len))?;
+        Ok(count)
+    }
+
+    /// Logs `mutation` and applies it.
+    pub fn commit(
+        &self,
+        storage: &Storage,
+        options: &Options,
+        mutation: Mutation,
+    ) -> io::Result<Outcome> {
+        let payload = serde_json::to_vec(&mutation)?;
+        let mut writer = self.writer.lock().unwrap();
+        writer.log(payload)?;
+        let scope = status::scope_to_check(storage, options, &mutation);
+        let outcome = apply(storage, options, mutation);
+        drop(writer);
+        status::check_mutation(storage, scope);
+        Ok(outcome)
+    }
+
+    /// Like `commit`, but builds the mutation while holding the log lock, so
+    /// it can depend on the current state, e.g. to allocate the next free id.
+    /// If `build` fails, nothing is logged and its outcome is returned.
+    pub fn commit_with<F>(
+        &self,
+        storage: &Storage,
+        options: &Options,
+        build: F,
+    ) -> io::Result<Outcome>
+    where
+        F: FnOnce(&Tables) -> Result<Mutation, Outcome>,
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let mutation = match build(&*storage.read()) {
+            Ok(mutation) => mutation,
+            Err(outcome) => return Ok(outcome),
+        };
+        let payload = serde_json::to_vec(&mutation)?;
+        writer.log(payload)?;
+        let scope = status::scope_to_check(storage, options, &mutation);
+        let outcome = apply(storage, options, mutation);
+        drop(writer);
+        status::check_mutation(storage, scope);
+        Ok(outcome)
+    }
+
+    /// Runs `f` with all mutations blocked and empties the log if it
+    /// succeeds. Used to persist a snapshot that already contains every
+    /// logged record.
+    pub fn checkpoint<F, T>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T>,
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let result = f()?;
+        writer.truncate()?;
+        Ok(result)
+    }
+
+    /// Whether the log holds no records.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        let writer = self.writer.lock().unwrap();
+        Ok(writer.file.metadata()?.len() == 0)
+    }
+
+    /// Keeps a copy of every record appended from now on, until
+    /// `checkpoint_captured` or `stop_capture`, so mutations made to data
+    /// that's about to be replaced can be made to its replacement too.
+    pub fn capture(&self) {
+        self.writer.lock().unwrap().captured = Some(Vec::new());
+    }
+
+    pub fn stop_capture(&self) {
+        self.writer.lock().unwrap().captured = None;
+    }
+
+    /// Like `checkpoint`, but hands `f` the mutations captured since
+    /// `capture`, in log order, and stops capturing.
+    pub fn checkpoint_captured<F, T>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(Vec<Mutation>) -> io::Result<T>,
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let captured = writer.captured.take().unwrap_or_default();
+        let mut mutations = Vec::with_capacity(captured.len());
+        for payload in captured {
+            mutations.push(serde_json::from_slice(&payload)?);
+        }
+        let result = f(mutations)?;
+        writer.truncate()?;
+        Ok(result)
+    }
+}
+
+/// Applies every intact record in the log at `path` to `storage` without
+/// changing the file, so the data can be read next to a running server that
+/// may be appending to it. A missing log has no records.
+pub fn replay_read_only(path: &Path, storage: &Storage, options: &Options) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let (count, valid_len) = apply_records(&file, storage, options)?;
+    let file_len = file.metadata()?.len();
+    if valid_len < file_len {
+        check_tail(&file, valid_len, file_len)?;
+    }
+    Ok(count)
+}
+
+// Applies the intact records from the current position of `file`. Returns
+// how many there were and how many bytes they take. A record that passes the
+// checksum was written whole, so if it doesn't decode the log can't be
+// trusted past it, and cutting it off would lose the records after it.
+fn apply_records(file: &File, storage: &Storage, options: &Options) -> io::Result<(usize, u64)> {
+    let mut valid_len = 0u64;
+    let mut count = 0;
+    let mut reader = BufReader::new(file);
+    while let Some(payload) = read_record(&mut reader)? {
+        let mutation = match serde_json::from_slice(&payload) {
+            Ok(mutation) => mutation,
+            Err(e) => {
+                let message = format!("undecodable WAL record at offset {}: {}", valid_len, e);
+                return Err(io::Error::new(ErrorKind::InvalidData, message));
+            }
+        };
+        apply(storage, options, mutation);
+        valid_len += (RECORD_HEADER_LEN + payload.len()) as u64;
+        count += 1;
+    }
+    Ok((count, valid_len))
+}
+
+// Fails unless all that's left of `file` from `offset`, where the intact
+// records end, is one record that doesn't pass the checks, as a crash
+// mid-append leaves. Anything after it would be records that truncating the
+// tail loses.
+fn check_tail(mut file: &File, offset: u64, file_len: u64) -> io::Result<()> {
+    let remaining = file_len - offset;
+    if remaining <= RECORD_HEADER_LEN as u64 {
+        return Ok(());
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let len = file.read_u32::<LittleEndian>()?;
+    let extent = RECORD_HEADER_LEN as u64 + len as u64;
+    if extent >= remaining {
+        return Ok(());
+    }
+    let message = format!(
+        "corrupted WAL record at offset {} has {} more bytes after it",
+        offset,
+        remaining - extent
+    );
+    Err(io::Error::new(ErrorKind::InvalidData, message))
+}
+
+/// Applies `mutation` as one transaction: readers see all of it or none.
+pub fn apply(storage: &Storage, options: &Options, mutation: Mutation) -> Outcome {
+    storage.update(|tables| apply_to(tables, options, mutation))
+}
+
+pub fn apply_to(tables: &mut Tables, options: &Options, mutation: Mutation) -> Outcome {
+    match mutation {
+        Mutation::UserNew(user) => users::insert_user(tables, options, user),
+        Mutation::UserUpdate(id, user_update) => {
+            users::update_user(tables, options, id, user_update)
+        }
+        Mutation::LocationNew(location) => locations::insert_location(tables, location),
+        Mutation::LocationUpdate(id, location_update) => {
+            locations::update_location(tables, id, location_update)
+        }
+        Mutation::VisitNew(visit) => visits::insert_visit(tables, visit),
+        Mutation::VisitUpdate(id, visit_update) => visits::update_visit(tables, id, visit_update),
+        Mutation::UserDelete(id, policy) => users::delete_user(tables, id, policy),
+        Mutation::LocationDelete(id, policy) => locations::delete_location(tables, id, policy),
+        Mutation::VisitDelete(id) => visits::delete_visit(tables, id),
+    }
+}
+
+fn read_record<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: Read,
+{
+    let len = match reader.read_u32::<LittleEndian>() {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let checksum = match reader.read_u32::<LittleEndian>() {
+        Ok(checksum) => checksum,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_RECORD_LEN {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; len as usize];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if crc32(&payload) != checksum {
+        return Ok(None);
+    }
+    Ok(Some(payload))
+}
+
+lazy_static! {
+    static ref CRC32_TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+// CRC-32 (IEEE 802.3), also used to checksum snapshots.
+pub fn crc32(data: &[u8]) -> u32 {
+    let table = &*CRC32_TABLE;
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}